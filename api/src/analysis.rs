@@ -0,0 +1,328 @@
+// Local audio analysis for recordings AcousticBrainz never analysed.
+// Produces the same feature keys as convert_acousticbrainz_features
+// (tempo, loudness, complexity) plus spectral_centroid and intensity. Intensity is
+// a signal-level estimate, kept apart from "energy" (AcousticBrainz mood_aggressive)
+// because the two don't measure the same thing.
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+type AnalysisResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const SUPPORTED_EXTENSIONS: [&str; 5] = ["flac", "mp3", "ogg", "wav", "oga"];
+
+const FRAME_SIZE: usize = 2048;
+const HOP_SIZE: usize = 512;
+const MAX_SECONDS: usize = 300; // Enough for a stable estimate, bounded CPU per upload
+const MIN_SECONDS: usize = 5;
+const SILENCE_DB: f64 = -60.0;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub bpm: f64,
+    pub loudness: f64,           // 0-1, like AcousticBrainz average_loudness
+    pub spectral_centroid: f64,  // Hz
+    pub dynamic_complexity: f64, // Mean deviation of frame loudness, in dB
+    pub intensity: f64,          // 0-1 estimate from loudness, brightness and rhythm
+}
+
+impl Analysis {
+    // Map onto our feature schema
+    pub fn features(&self) -> HashMap<String, f64> {
+        let mut features = HashMap::new();
+        features.insert("tempo".to_string(), self.bpm / 200.0); // Normalize to 0-1
+        features.insert("loudness".to_string(), self.loudness);
+        features.insert("complexity".to_string(), self.dynamic_complexity);
+        features.insert("intensity".to_string(), self.intensity);
+        // Relative to 11kHz, so the value doesn't depend on the file's sample rate
+        features.insert(
+            "spectral_centroid".to_string(),
+            (self.spectral_centroid / 11_025.0).min(1.0),
+        );
+        features
+    }
+}
+
+// A file in the library named after the recording, e.g. "<mbid>.flac"
+pub fn find_in_library(dir: &Path, mbid: &str) -> Option<PathBuf> {
+    SUPPORTED_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", mbid, ext)))
+        .find(|path| path.is_file())
+}
+
+pub fn analyse_file(path: &Path) -> AnalysisResult<Analysis> {
+    let file = std::fs::File::open(path)?;
+    let extension = path.extension().and_then(|ext| ext.to_str());
+    analyse(Box::new(file), extension)
+}
+
+// Decodes straight from the buffer, e.g. an upload's Bytes, without copying it
+pub fn analyse_bytes<B>(bytes: B, extension: Option<&str>) -> AnalysisResult<Analysis>
+where
+    B: AsRef<[u8]> + Send + Sync + 'static,
+{
+    analyse(Box::new(Cursor::new(bytes)), extension)
+}
+
+fn analyse(source: Box<dyn MediaSource>, extension: Option<&str>) -> AnalysisResult<Analysis> {
+    let (samples, sample_rate) = decode_mono(source, extension)?;
+    if samples.len() < MIN_SECONDS * sample_rate as usize {
+        return Err("Audio is too short to analyse".into());
+    }
+    Ok(analyse_samples(&samples, sample_rate))
+}
+
+// Decode the first audio track, mixed down to mono
+fn decode_mono(
+    source: Box<dyn MediaSource>,
+    extension: Option<&str>,
+) -> AnalysisResult<(Vec<f32>, u32)> {
+    let stream = MediaSourceStream::new(source, Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or("No audio track found")?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or("Unknown sample rate")?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let max_samples = MAX_SECONDS * sample_rate as usize;
+    let mut samples = Vec::new();
+    while samples.len() < max_samples {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // Skip corrupt packets rather than failing the whole file
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend(
+            buffer
+                .samples()
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+    }
+
+    Ok((samples, sample_rate))
+}
+
+fn analyse_samples(samples: &[f32], sample_rate: u32) -> Analysis {
+    let window: Vec<f32> = (0..FRAME_SIZE)
+        .map(|i| {
+            0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (FRAME_SIZE - 1) as f32).cos()
+        })
+        .collect();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(FRAME_SIZE);
+    let bin_hz = sample_rate as f64 / FRAME_SIZE as f64;
+
+    let mut frame_db = Vec::new(); // Loudness of non-silent frames
+    let mut onset_envelope = Vec::new(); // Spectral flux per frame
+    let mut centroid_sum = 0.0;
+    let mut centroid_weight = 0.0;
+    let mut previous = vec![0.0f32; FRAME_SIZE / 2];
+    let mut buffer = vec![Complex::new(0.0f32, 0.0); FRAME_SIZE];
+    let mut square_sum = 0.0f64;
+
+    for frame in samples.windows(FRAME_SIZE).step_by(HOP_SIZE) {
+        let energy: f64 = frame.iter().map(|s| (*s as f64).powi(2)).sum::<f64>();
+        square_sum += frame[..HOP_SIZE]
+            .iter()
+            .map(|s| (*s as f64).powi(2))
+            .sum::<f64>();
+        let rms = (energy / FRAME_SIZE as f64).sqrt();
+        let db = 20.0 * rms.max(1e-10).log10();
+
+        for (slot, (sample, w)) in buffer.iter_mut().zip(frame.iter().zip(&window)) {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        fft.process(&mut buffer);
+
+        let mut flux = 0.0;
+        let mut weighted = 0.0;
+        let mut total = 0.0;
+        for (bin, (value, last)) in buffer[..FRAME_SIZE / 2]
+            .iter()
+            .zip(previous.iter_mut())
+            .enumerate()
+        {
+            let magnitude = value.norm();
+            flux += (magnitude - *last).max(0.0) as f64;
+            weighted += bin as f64 * bin_hz * magnitude as f64;
+            total += magnitude as f64;
+            *last = magnitude;
+        }
+        onset_envelope.push(flux);
+
+        // Silence and fade-outs say nothing about the track's dynamics or timbre
+        if db > SILENCE_DB {
+            frame_db.push(db);
+            if total > 0.0 {
+                centroid_sum += weighted;
+                centroid_weight += total;
+            }
+        }
+    }
+
+    let overall_rms = (square_sum / samples.len().max(1) as f64).sqrt();
+    let loudness =
+        ((20.0 * overall_rms.max(1e-10).log10() - SILENCE_DB) / -SILENCE_DB).clamp(0.0, 1.0);
+
+    let mean_db = frame_db.iter().sum::<f64>() / frame_db.len().max(1) as f64;
+    let dynamic_complexity =
+        frame_db.iter().map(|db| (db - mean_db).abs()).sum::<f64>() / frame_db.len().max(1) as f64;
+
+    let spectral_centroid = if centroid_weight > 0.0 {
+        centroid_sum / centroid_weight
+    } else {
+        0.0
+    };
+
+    let frame_rate = sample_rate as f64 / HOP_SIZE as f64;
+    let (bpm, rhythm_strength) = estimate_tempo(&onset_envelope, frame_rate);
+
+    let brightness = (spectral_centroid / 3000.0).min(1.0);
+    let intensity = (0.6 * loudness + 0.25 * brightness + 0.15 * rhythm_strength).clamp(0.0, 1.0);
+
+    Analysis {
+        bpm,
+        loudness,
+        spectral_centroid,
+        dynamic_complexity,
+        intensity,
+    }
+}
+
+// Autocorrelation of the onset envelope over the 60-200 BPM range, weighted towards
+// ~120 BPM to avoid half/double tempo picks. Also returns how periodic the envelope
+// is (0-1), used as a rough measure of rhythmic drive.
+fn estimate_tempo(envelope: &[f64], frame_rate: f64) -> (f64, f64) {
+    // Remove the local mean so sustained loud passages don't read as onsets
+    let radius = (frame_rate / 2.0) as usize;
+    let detrended: Vec<f64> = (0..envelope.len())
+        .map(|i| {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(envelope.len());
+            let mean = envelope[start..end].iter().sum::<f64>() / (end - start) as f64;
+            (envelope[i] - mean).max(0.0)
+        })
+        .collect();
+
+    let autocorrelation = |lag: usize| -> f64 {
+        detrended
+            .iter()
+            .zip(&detrended[lag..])
+            .map(|(a, b)| a * b)
+            .sum::<f64>()
+    };
+    let zero_lag = autocorrelation(0);
+    if zero_lag <= 0.0 {
+        return (0.0, 0.0);
+    }
+
+    let min_lag = (60.0 * frame_rate / MAX_BPM).floor().max(1.0) as usize;
+    let max_lag = ((60.0 * frame_rate / MIN_BPM).ceil() as usize).min(detrended.len() - 1);
+    let scores: Vec<(usize, f64, f64)> = (min_lag..=max_lag)
+        .map(|lag| {
+            let value = autocorrelation(lag);
+            let bpm = 60.0 * frame_rate / lag as f64;
+            let prior = (-0.5 * ((bpm / 120.0).log2() / 0.8).powi(2)).exp();
+            (lag, value, value * prior)
+        })
+        .collect();
+    let Some(best) = scores
+        .iter()
+        .position(|s| scores.iter().all(|other| other.2 <= s.2))
+    else {
+        return (0.0, 0.0);
+    };
+    let (lag, value, _) = scores[best];
+
+    // Parabolic interpolation between neighbouring lags for a finer estimate
+    let mut refined = lag as f64;
+    if best > 0 && best + 1 < scores.len() {
+        let (a, b, c) = (scores[best - 1].1, value, scores[best + 1].1);
+        let denominator = a - 2.0 * b + c;
+        if denominator.abs() > f64::EPSILON {
+            refined += (0.5 * (a - c) / denominator).clamp(-0.5, 0.5);
+        }
+    }
+
+    (
+        60.0 * frame_rate / refined,
+        (value / zero_lag).clamp(0.0, 1.0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Short decaying 1kHz blips at a fixed tempo, over silence
+    fn click_track(bpm: f64, seconds: usize, sample_rate: u32) -> Vec<f32> {
+        let mut samples = vec![0.0f32; seconds * sample_rate as usize];
+        let interval = (60.0 / bpm * sample_rate as f64) as usize;
+        let click_len = sample_rate as usize / 100;
+        for start in (0..samples.len()).step_by(interval) {
+            for i in 0..click_len.min(samples.len() - start) {
+                let t = i as f32 / sample_rate as f32;
+                let decay = 1.0 - i as f32 / click_len as f32;
+                samples[start + i] = 0.8 * decay * (2.0 * std::f32::consts::PI * 1000.0 * t).sin();
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn estimates_click_track_tempo() {
+        for bpm in [90.0, 120.0, 150.0] {
+            let analysis = analyse_samples(&click_track(bpm, 20, 22_050), 22_050);
+            assert!(
+                (analysis.bpm - bpm).abs() < 2.0,
+                "expected {} BPM, got {}",
+                bpm,
+                analysis.bpm
+            );
+        }
+    }
+}
@@ -0,0 +1,126 @@
+// Import the AcousticBrainz JSON data dumps (https://acousticbrainz.org/download)
+// into the local feature store read by the API (FEATURE_STORE_PATH).
+//
+// Extract the low-level and/or high-level archives (or any subset of them) and run:
+//   cargo run --release --bin import_acousticbrainz -- --store features.redb <dir or file>...
+// Dump files are named "<mbid>-<submission>.json"; only the first submission of
+// each recording is imported. Stop the API first: the store can only be open once.
+use api::feature_store::FeatureStore;
+use std::env;
+use std::path::{Path, PathBuf};
+
+const BATCH_SIZE: usize = 5_000;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Import failed: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut store_path = env::var("FEATURE_STORE_PATH").ok().map(PathBuf::from);
+    let mut inputs = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--store" => store_path = Some(args.next().ok_or("--store needs a path")?.into()),
+            "-h" | "--help" => {
+                println!("Usage: import_acousticbrainz [--store <path>] <dump dir or file>...");
+                return Ok(());
+            }
+            _ => inputs.push(PathBuf::from(arg)),
+        }
+    }
+    let store_path = store_path.ok_or("No store path: pass --store or set FEATURE_STORE_PATH")?;
+    if inputs.is_empty() {
+        return Err("No dump directories or files given".into());
+    }
+
+    let store = FeatureStore::open(&store_path)?;
+    println!("Importing into {}", store_path.display());
+
+    // The dumps hold millions of files, so they are imported as the walk finds them
+    let mut batch = Vec::new();
+    let (mut imported, mut skipped) = (0usize, 0usize);
+    for input in &inputs {
+        walk_json_files(input, &mut |file| {
+            let Some(mbid) = first_submission_mbid(file) else {
+                skipped += 1;
+                return Ok(());
+            };
+            let document = match std::fs::read_to_string(file)
+                .map_err(|e| e.to_string())
+                .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            {
+                Ok(document) => document,
+                Err(e) => {
+                    eprintln!("Skipping {}: {}", file.display(), e);
+                    skipped += 1;
+                    return Ok(());
+                }
+            };
+            batch.push((mbid, document));
+            if batch.len() >= BATCH_SIZE {
+                imported += batch.len();
+                store.put_many(std::mem::take(&mut batch))?;
+                println!("  {} documents imported", imported);
+            }
+            Ok(())
+        })?;
+    }
+    imported += batch.len();
+    store.put_many(batch)?;
+
+    println!(
+        "Done: {} documents imported, {} files skipped, {} recordings in store",
+        imported,
+        skipped,
+        store.len()?
+    );
+    Ok(())
+}
+
+type ImportResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+// Call `visit` for every JSON file under `path`, depth first
+fn walk_json_files(path: &Path, visit: &mut impl FnMut(&Path) -> ImportResult) -> ImportResult {
+    if path.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            walk_json_files(&entry?.path(), visit)?;
+        }
+    } else if path.extension().is_some_and(|ext| ext == "json") {
+        visit(path)?;
+    }
+    Ok(())
+}
+
+// "<mbid>-0.json" -> mbid; later submissions of the same recording are skipped
+fn first_submission_mbid(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    let (mbid, submission) = stem.rsplit_once('-')?;
+    (submission == "0" && mbid.len() == 36).then(|| mbid.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_first_submissions_only() {
+        let mbid = "b10bbbfc-cf9e-42e0-be17-e2c3e1d2600d";
+        assert_eq!(
+            first_submission_mbid(Path::new(&format!("dump/b1/{}-0.json", mbid))),
+            Some(mbid.to_string())
+        );
+        assert_eq!(
+            first_submission_mbid(Path::new(&format!("{}-0.json", mbid.to_uppercase()))),
+            Some(mbid.to_string())
+        );
+        assert_eq!(
+            first_submission_mbid(Path::new(&format!("{}-1.json", mbid))),
+            None
+        );
+        assert_eq!(first_submission_mbid(Path::new("readme-0.json")), None);
+    }
+}
@@ -0,0 +1,200 @@
+// Local AcousticBrainz feature store: trimmed low-/high-level documents keyed by
+// recording MBID, in a redb database. Written by the import_acousticbrainz binary
+// and read by the API before it falls back to the AcousticBrainz web service.
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+type StoreResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// MBID -> trimmed JSON document
+const DOCUMENTS: TableDefinition<&str, &str> = TableDefinition::new("acousticbrainz");
+
+// The parts of a document the API actually uses; everything else in the dump
+// (frame statistics, spectral histograms, metadata) is dropped on import
+const KEPT_FIELDS: [&[&str]; 11] = [
+    &["lowlevel", "average_loudness"],
+    &["lowlevel", "dynamic_complexity"],
+    &["rhythm", "bpm"],
+    &["tonal", "key_key"],
+    &["tonal", "key_scale"],
+    &["tonal", "key_strength"],
+    &["highlevel", "danceability", "all"],
+    &["highlevel", "mood_acoustic", "all"],
+    &["highlevel", "mood_aggressive", "all"],
+    &["highlevel", "mood_happy", "all"],
+    &["highlevel", "mood_sad", "all"],
+];
+
+pub struct FeatureStore {
+    db: Database,
+}
+
+impl FeatureStore {
+    // Open (or create) a store. redb locks the file, so the importer and the API
+    // can't have the same store open at the same time.
+    pub fn open(path: &Path) -> StoreResult<Self> {
+        let db = Database::create(path)?;
+        // Create the table up front so reads on an empty store don't fail
+        let txn = db.begin_write()?;
+        txn.open_table(DOCUMENTS)?;
+        txn.commit()?;
+        Ok(FeatureStore { db })
+    }
+
+    pub fn len(&self) -> StoreResult<u64> {
+        let txn = self.db.begin_read()?;
+        Ok(txn.open_table(DOCUMENTS)?.len()?)
+    }
+
+    pub fn is_empty(&self) -> StoreResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // Documents for whichever of `mbids` are in the store
+    pub fn get_many(&self, mbids: &[String]) -> StoreResult<Vec<(String, Value)>> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(DOCUMENTS)?;
+        let mut documents = Vec::new();
+        for mbid in mbids {
+            if let Some(document) = table.get(mbid.as_str())? {
+                documents.push((mbid.clone(), serde_json::from_str(document.value())?));
+            }
+        }
+        Ok(documents)
+    }
+
+    // Trim and store documents in one transaction. A recording's low- and high-level
+    // documents come from separate dumps, so they are merged with what's stored.
+    pub fn put_many(&self, documents: Vec<(String, Value)>) -> StoreResult<()> {
+        let txn = self.db.begin_write()?;
+        {
+            let mut table = txn.open_table(DOCUMENTS)?;
+            for (mbid, document) in documents {
+                let mut trimmed = trim_document(&document);
+                let existing = table
+                    .get(mbid.as_str())?
+                    .map(|existing| existing.value().to_string());
+                if let Some(existing) = existing {
+                    merge(&mut trimmed, serde_json::from_str(&existing)?);
+                }
+                if trimmed.as_object().is_some_and(|fields| !fields.is_empty()) {
+                    table.insert(mbid.as_str(), trimmed.to_string().as_str())?;
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+}
+
+fn trim_document(document: &Value) -> Value {
+    let mut trimmed = Value::Object(Map::new());
+    for path in KEPT_FIELDS {
+        let Some(value) = path.iter().try_fold(document, |value, key| value.get(key)) else {
+            continue;
+        };
+        let mut target = &mut trimmed;
+        for key in &path[..path.len() - 1] {
+            target = target
+                .as_object_mut()
+                .unwrap()
+                .entry(key.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+        }
+        target
+            .as_object_mut()
+            .unwrap()
+            .insert(path[path.len() - 1].to_string(), value.clone());
+    }
+    trimmed
+}
+
+// Fill fields missing from `into` with those from `from` (fields in `into` win)
+fn merge(into: &mut Value, from: Value) {
+    let (Some(into), Value::Object(from)) = (into.as_object_mut(), from) else {
+        return;
+    };
+    for (key, value) in from {
+        match into.get_mut(&key) {
+            Some(existing) => merge(existing, value),
+            None => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn trim_keeps_only_used_fields() {
+        let document = json!({
+            "lowlevel": { "average_loudness": 0.8, "spectral_centroid": { "mean": 1200.0 } },
+            "rhythm": { "bpm": 120.0, "beats_position": [0.5, 1.0] },
+            "metadata": { "tags": { "artist": ["A"] } },
+        });
+        assert_eq!(
+            trim_document(&document),
+            json!({ "lowlevel": { "average_loudness": 0.8 }, "rhythm": { "bpm": 120.0 } })
+        );
+    }
+
+    #[test]
+    fn merge_fills_missing_fields_only() {
+        let mut into = json!({ "rhythm": { "bpm": 120.0 } });
+        merge(
+            &mut into,
+            json!({
+                "rhythm": { "bpm": 90.0 },
+                "highlevel": { "mood_happy": { "all": { "happy": 0.3 } } },
+            }),
+        );
+        assert_eq!(
+            into,
+            json!({
+                "rhythm": { "bpm": 120.0 },
+                "highlevel": { "mood_happy": { "all": { "happy": 0.3 } } },
+            })
+        );
+    }
+
+    #[test]
+    fn low_and_high_level_imports_combine() {
+        let path = std::env::temp_dir().join(format!("feature-store-{}.redb", std::process::id()));
+        let store = FeatureStore::open(&path).unwrap();
+        let mbid = "b10bbbfc-cf9e-42e0-be17-e2c3e1d2600d".to_string();
+        store
+            .put_many(vec![(mbid.clone(), json!({ "rhythm": { "bpm": 120.0 } }))])
+            .unwrap();
+        store
+            .put_many(vec![
+                (
+                    mbid.clone(),
+                    json!({ "highlevel": { "danceability": { "all": { "danceable": 0.7 } } } }),
+                ),
+                // Nothing the API uses, so not stored
+                ("other".to_string(), json!({ "metadata": {} })),
+            ])
+            .unwrap();
+
+        let documents = store
+            .get_many(&[mbid.clone(), "other".to_string()])
+            .unwrap();
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            documents,
+            vec![(
+                mbid,
+                json!({
+                    "rhythm": { "bpm": 120.0 },
+                    "highlevel": { "danceability": { "all": { "danceable": 0.7 } } },
+                })
+            )]
+        );
+    }
+}
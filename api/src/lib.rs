@@ -0,0 +1,2 @@
+// Code shared by the API server and the import_acousticbrainz binary
+pub mod feature_store;
@@ -1,25 +1,37 @@
+mod analysis;
+
+use api::feature_store::FeatureStore;
 use axum::{
-    extract::{Path, State},
-    http::{Method, StatusCode},
+    body::Bytes,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        DefaultBodyLimit, Path, Query, Request, State,
+    },
+    http::{header::LINK, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
     response::{
-        sse::{Event, Sse},
-        IntoResponse,
+        sse::{Event, KeepAlive, Sse},
+        AppendHeaders, IntoResponse, Response,
     },
-    routing::{get, post},
+    routing::{delete, get, post},
     Json, Router,
 };
+use futures::future::{BoxFuture, FutureExt, Shared};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{rngs::StdRng, thread_rng, SeedableRng};
 use reqwest::header::AUTHORIZATION;
 use scraper::{Html, Selector};
-use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 use tokio::sync::Mutex as TokioMutex;
+use tokio_util::sync::CancellationToken;
 use tower_http::cors::{Any, CorsLayer};
-use urlencoding;
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
+use utoipa_scalar::{Scalar, Servable};
 
 macro_rules! hashmap {
     ($($key:expr => $value:expr),* $(,)?) => {{
@@ -30,21 +42,120 @@ macro_rules! hashmap {
 }
 
 // Structs
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 struct Preferences {
     energy: f64,
     obscurity: f64,
     mood: f64,
+    #[serde(default)]
+    artist_diversity: bool, // Cap how many results any one credited artist gets
+    #[serde(default)]
+    harmonic: f64, // 0-1: weight of key compatibility with the seeds (DJ mixing)
+    #[serde(default)]
+    harmonic_sequence: bool, // Order results so consecutive tracks mix in key
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
 struct Track {
     id: String,
     name: String,
-    artist: String,
-    features: HashMap<String, f64>,
-    popularity: u32,
+    artist: String, // Display credit, e.g. "Artist A feat. Artist B"
+    #[serde(default)]
+    artists: Vec<ArtistCredit>,
+    // Ordered maps, so feature vectors and serialized tracks don't vary between runs
+    features: BTreeMap<String, f64>,
+    popularity: u32, // Percentile of ListenBrainz listens (0-100)
+    listen_count: Option<u64>,
+    user_count: Option<u64>,
     album_art: Option<String>,
+    #[serde(default)]
+    flags: RecordingFlags,
+    #[serde(flatten)]
+    metadata: TrackMetadata,
+    #[serde(default)]
+    tag_vector: BTreeMap<String, f64>, // Weighted genres/tags from MusicBrainz and Last.fm
+    harmonic_key: Option<HarmonicKey>, // Detected key, when AcousticBrainz has one
+    #[serde(skip, default = "default_seed_weight")]
+    weight: f64, // Share of its seed (artist/album seeds are spread over several tracks)
+}
+
+// Release/artist metadata from MusicBrainz, for filtering and display
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+struct TrackMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    first_release_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release: Option<ReleaseInfo>, // Earliest official studio release
+    #[serde(skip_serializing_if = "Option::is_none")]
+    release_group: Option<ReleaseGroupInfo>,
+    #[serde(default)]
+    isrcs: Vec<String>,
+    #[serde(default)]
+    tags: Vec<TagCount>, // Folksonomy tags, most voted first
+    #[serde(default)]
+    genres: Vec<TagCount>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+struct ReleaseInfo {
+    id: String,
+    title: String,
+    date: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+struct ReleaseGroupInfo {
+    id: String,
+    title: String,
+    primary_type: Option<String>,
+    #[serde(default)]
+    secondary_types: Vec<String>,
+}
+
+// A key on the Camelot wheel: 1-12 around the circle of fifths, A = minor, B = major
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+struct HarmonicKey {
+    tonic: String,   // e.g. "C#"
+    scale: String,   // "major" or "minor"
+    camelot: String, // e.g. "12A"
+}
+
+// One entry of a MusicBrainz artist credit
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+struct ArtistCredit {
+    name: String, // As credited on the recording, which may differ from the artist's name
+    mbid: Option<String>,
+    #[serde(default)]
+    joinphrase: String, // Text after this artist, e.g. " feat. " or " & "
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
+struct TagCount {
+    name: String,
+    count: i64,
+}
+
+fn default_seed_weight() -> f64 {
+    1.0
+}
+
+// What MusicBrainz tells us about a recording beyond title and artist
+#[derive(Serialize, Deserialize, Clone, Debug, Default, ToSchema)]
+struct RecordingFlags {
+    cover: bool,       // Performance of a work originally recorded by another artist
+    karaoke: bool,     // Karaoke/backing-track version
+    tribute: bool,     // Performed by a tribute or cover act
+    live: bool,        // Live performance
+    compilation: bool, // Only released on compilations
+}
+
+impl RecordingFlags {
+    // Not the song the user (or Last.fm) actually meant
+    fn is_imitation(&self) -> bool {
+        self.cover || self.karaoke || self.tribute
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -53,12 +164,131 @@ struct TrackId {
     spotify: Option<String>, // Spotify ID (for migration)
     name: String,            // Track name for fallback searches
     artist: String,          // Artist name for fallback searches
+    #[serde(default)]
+    artists: Vec<ArtistCredit>,
+    #[serde(default)]
+    canonical_keys: Vec<String>, // Work/ISRC/title keys shared by versions of the same song
+    #[serde(default)]
+    flags: RecordingFlags,
+    #[serde(default = "default_seed_weight")]
+    weight: f64,
+}
+
+// Lightweight type-ahead result
+#[derive(Serialize, Clone, Debug, ToSchema)]
+struct Suggestion {
+    id: String,
+    name: String,
+    artist: String,
+    label: String, // "Track Name - Artist", the format the track input expects
+}
+
+impl Suggestion {
+    fn new(id: String, name: String, artist: String) -> Self {
+        let label = format!("{} - {}", name, artist);
+        Self {
+            id,
+            name,
+            artist,
+            label,
+        }
+    }
+}
+
+// /mb/search query string: pagination, filters and enrichment
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SearchParams {
+    limit: Option<usize>,
+    offset: Option<usize>,  // Upstream row to start at (first page only)
+    cursor: Option<String>, // `next_cursor` from the previous page
+    artist: Option<String>,
+    year_from: Option<u32>,
+    year_to: Option<u32>,
+    release_type: Option<String>, // album, single, ep, compilation, live, soundtrack...
+    #[serde(default)]
+    exclude_live: bool,
+    #[serde(default)]
+    exclude_karaoke: bool,
+    sort: Option<String>, // popularity (default), relevance or date
+    // Comma-separated enrichment: duration, release_date, release, cover_art, features (or all)
+    include: Option<String>,
+}
+
+// /mb/search result: a track plus whatever metadata was requested via `include`
+#[derive(Serialize, ToSchema)]
+struct SearchResult {
+    #[serde(flatten)]
+    track: Track,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    features_available: Option<bool>, // AcousticBrainz has analysed this recording
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct SuggestParams {
+    q: String,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SuggestResponse {
+    suggestions: Vec<Suggestion>,
+    source: &'static str, // cache, musicbrainz or none
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+// /mb/search response
+#[derive(Serialize, ToSchema)]
+struct SearchResponse {
+    tracks: Vec<SearchResult>,
+    pagination: Pagination,
+    metadata: SearchMetadata,
+}
+
+#[derive(Serialize, ToSchema)]
+struct Pagination {
+    offset: usize,
+    limit: usize,
+    upstream_total: usize, // MusicBrainz rows, before grouping and filtering
+    next_cursor: Option<String>, // None on the last page
+}
+
+#[derive(Serialize, ToSchema)]
+struct SearchMetadata {
+    source: &'static str,
+    audio_features_available: bool,
+    popularity_data: &'static str,
+    note: &'static str,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, ToSchema)]
 struct RecommendRequest {
+    #[serde(default)]
     tracks: Vec<String>,
+    #[serde(default)]
+    artists: Vec<String>, // Artist MBIDs or names ("Radiohead")
+    #[serde(default)]
+    albums: Vec<String>, // Release/release-group MBIDs or names ("OK Computer by Radiohead")
     preferences: Preferences,
+    limit: Option<usize>, // Page size, DEFAULT_RECOMMENDATION_LIMIT when absent
+    #[serde(default)]
+    offset: usize,
+    // Fixes tie-breaks between equal scores (and the legacy shuffle). Random when
+    // absent; either way it's echoed in the response, so a result can be reproduced.
+    seed: Option<u64>,
+}
+
+impl RecommendRequest {
+    fn page_size(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_RECOMMENDATION_LIMIT)
+            .clamp(1, MAX_RECOMMENDATION_LIMIT)
+    }
+
+    fn seed_or_random(&self) -> u64 {
+        self.seed
+            .unwrap_or_else(|| rand::Rng::gen(&mut thread_rng()))
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -115,6 +345,7 @@ struct GeniusResult {
 #[derive(Deserialize)]
 struct MusicBrainzSearchResponse {
     recordings: Vec<MusicBrainzRecording>,
+    count: Option<usize>, // Total matches across all pages
 }
 
 // Cover Art Archive structs
@@ -143,52 +374,179 @@ struct MusicBrainzRecording {
     title: String,
     #[serde(rename = "artist-credit")]
     artist_credit: Option<Vec<MusicBrainzArtistCredit>>,
-    #[allow(dead_code)]
     releases: Option<Vec<MusicBrainzRelease>>,
+    length: Option<u32>, // Duration in milliseconds
+    disambiguation: Option<String>,
+    #[serde(rename = "first-release-date")]
+    first_release_date: Option<String>,
+    #[serde(default)]
+    isrcs: Vec<String>,
+    #[serde(default)]
+    relations: Vec<MusicBrainzRelation>, // Only present on lookups with inc=work-rels
+    #[serde(default)]
+    tags: Vec<MusicBrainzTag>,
+    #[serde(default)]
+    genres: Vec<MusicBrainzTag>,
+}
+
+#[derive(Deserialize, Clone)]
+struct MusicBrainzTag {
+    name: String,
+    #[serde(default)]
+    count: i64,
 }
 
 #[derive(Deserialize, Clone)]
 struct MusicBrainzArtistCredit {
+    name: Option<String>,
+    #[serde(default)]
+    joinphrase: String,
     artist: MusicBrainzArtist,
 }
 
 #[derive(Deserialize, Clone)]
 struct MusicBrainzArtist {
-    #[allow(dead_code)]
     id: String,
     name: String,
+    #[serde(rename = "type")]
+    artist_type: Option<String>,
+    disambiguation: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
-#[allow(dead_code)]
 struct MusicBrainzRelease {
     id: String,
     title: String,
+    status: Option<String>,
+    date: Option<String>,
+    #[serde(rename = "release-group")]
+    release_group: Option<MusicBrainzReleaseGroup>,
+}
+
+#[derive(Deserialize, Clone)]
+struct MusicBrainzReleaseGroup {
+    id: Option<String>,
+    title: Option<String>,
+    #[serde(rename = "primary-type")]
+    primary_type: Option<String>,
+    #[serde(rename = "secondary-types", default)]
+    secondary_types: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzArtistSearchResponse {
+    artists: Vec<MusicBrainzArtistMatch>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzArtistMatch {
+    id: String,
+    name: String,
+    score: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzReleaseGroupSearchResponse {
+    #[serde(rename = "release-groups")]
+    release_groups: Vec<MusicBrainzReleaseGroupMatch>,
 }
 
-// AcousticBrainz structs
 #[derive(Deserialize)]
+struct MusicBrainzReleaseGroupMatch {
+    id: String,
+    #[serde(rename = "primary-type")]
+    primary_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzReleaseGroupLookup {
+    #[serde(default)]
+    releases: Vec<MusicBrainzRelease>,
+}
+
+// Release lookup with inc=recordings
+#[derive(Deserialize)]
+struct MusicBrainzReleaseLookup {
+    title: String,
+    #[serde(default)]
+    media: Vec<MusicBrainzMedium>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzMedium {
+    #[serde(default)]
+    tracks: Vec<MusicBrainzMediumTrack>,
+}
+
+#[derive(Deserialize)]
+struct MusicBrainzMediumTrack {
+    recording: MusicBrainzRecording,
+}
+
+#[derive(Deserialize, Clone)]
+struct MusicBrainzRelation {
+    #[serde(default)]
+    attributes: Vec<String>, // e.g. "cover", "live", "karaoke" on performance relations
+    work: Option<MusicBrainzWork>,
+}
+
+#[derive(Deserialize, Clone)]
+struct MusicBrainzWork {
+    id: String,
+}
+
+// AcousticBrainz structs
+// One submission from either endpoint: /low-level documents carry lowlevel, rhythm
+// and tonal; /high-level documents carry highlevel. Both are merged per recording.
+#[derive(Deserialize, Default)]
 struct AcousticBrainzResponse {
     highlevel: Option<AcousticBrainzHighLevel>,
     lowlevel: Option<AcousticBrainzLowLevel>,
+    rhythm: Option<AcousticBrainzRhythm>,
+    tonal: Option<AcousticBrainzTonal>,
+}
+
+impl AcousticBrainzResponse {
+    // Both the /low-level data (loudness, rhythm, key) and the /high-level classifiers
+    fn has_both_levels(&self) -> bool {
+        self.highlevel.is_some() && (self.lowlevel.is_some() || self.rhythm.is_some())
+    }
+
+    fn merge(&mut self, other: AcousticBrainzResponse) {
+        self.highlevel = self.highlevel.take().or(other.highlevel);
+        self.lowlevel = self.lowlevel.take().or(other.lowlevel);
+        self.rhythm = self.rhythm.take().or(other.rhythm);
+        self.tonal = self.tonal.take().or(other.tonal);
+    }
+}
+
+#[derive(Deserialize)]
+struct AcousticBrainzRhythm {
+    bpm: Option<f64>,
 }
 
+#[derive(Deserialize)]
+struct AcousticBrainzTonal {
+    key_key: Option<String>,   // Tonic, e.g. "F#"
+    key_scale: Option<String>, // "major" or "minor"
+    key_strength: Option<f64>,
+}
+
+// Classifiers can be missing from older submissions, so each one is optional
 #[derive(Deserialize)]
 struct AcousticBrainzHighLevel {
-    danceability: AcousticBrainzFeature,
-    #[allow(dead_code)]
-    mood_acoustic: AcousticBrainzFeature,
-    mood_aggressive: AcousticBrainzFeature,
-    mood_happy: AcousticBrainzFeature,
+    danceability: Option<AcousticBrainzFeature>,
+    mood_acoustic: Option<AcousticBrainzFeature>,
+    mood_aggressive: Option<AcousticBrainzFeature>,
+    mood_happy: Option<AcousticBrainzFeature>,
     #[allow(dead_code)]
-    mood_sad: AcousticBrainzFeature,
+    mood_sad: Option<AcousticBrainzFeature>,
 }
 
 #[derive(Deserialize)]
 struct AcousticBrainzLowLevel {
-    average_loudness: f64,
-    bpm: f64,
-    dynamic_complexity: f64,
+    average_loudness: Option<f64>,
+    dynamic_complexity: Option<f64>,
 }
 
 #[derive(Deserialize)]
@@ -199,7 +557,6 @@ struct AcousticBrainzFeature {
 #[derive(Deserialize)]
 struct AcousticBrainzProbability {
     danceable: Option<f64>,
-    #[allow(dead_code)]
     acoustic: Option<f64>,
     aggressive: Option<f64>,
     happy: Option<f64>,
@@ -218,11 +575,17 @@ struct ListenBrainzPopularityResponse {
     payload: Vec<ListenBrainzRecordingPopularity>,
 }
 
+#[derive(Deserialize)]
+struct ListenBrainzTopRecording {
+    recording_mbid: Option<String>,
+    recording_name: String,
+    artist_name: String,
+}
+
 #[derive(Deserialize)]
 struct ListenBrainzRecordingPopularity {
     recording_mbid: String,
     total_listen_count: Option<u64>,
-    #[allow(dead_code)]
     total_user_count: Option<u64>,
 }
 
@@ -250,6 +613,48 @@ struct LastFmArtist {
     name: String,
 }
 
+#[derive(Deserialize)]
+struct LastFmTopTracks {
+    toptracks: LastFmTopTrackList,
+}
+
+#[derive(Deserialize)]
+struct LastFmTopTrackList {
+    track: Vec<LastFmTopTrack>,
+}
+
+#[derive(Deserialize)]
+struct LastFmTopTrack {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct LastFmTopTags {
+    toptags: LastFmTagList,
+}
+
+#[derive(Deserialize)]
+struct LastFmTagList {
+    #[serde(default)]
+    tag: Vec<LastFmTag>,
+}
+
+#[derive(Deserialize)]
+struct LastFmTag {
+    name: String,
+    #[serde(default)]
+    count: i64, // 0-100, relative to the item's top tag
+}
+
+// Artist lookup with inc=tags+genres
+#[derive(Deserialize)]
+struct MusicBrainzArtistTags {
+    #[serde(default)]
+    tags: Vec<MusicBrainzTag>,
+    #[serde(default)]
+    genres: Vec<MusicBrainzTag>,
+}
+
 // Token Response from Spotify
 #[derive(Deserialize)]
 struct TokenResponse {
@@ -261,111 +666,697 @@ struct TokenResponse {
 struct AppState {
     rate_limiter: Arc<RateLimiter>,
     spotify_token_manager: Option<Arc<TokenManager>>,
+    // Recording lookups (work rels, ISRCs, releases) shared by resolution and album art
+    recording_cache: Arc<TokioMutex<BoundedCache<MusicBrainzRecording>>>,
+    // Artists on the earliest recording of each work, keyed by work MBID
+    original_artist_cache: Arc<TokioMutex<BoundedCache<Vec<String>>>>,
+    suggest_cache: Arc<SuggestCache>,
+    search_cursors: Arc<TokioMutex<BoundedCache<SearchCursor>>>,
+    // Tag lists per source and entity, e.g. "lastfm-artist:<name>"
+    tag_cache: Arc<TokioMutex<BoundedCache<Vec<TagCount>>>>,
+    // Converted AcousticBrainz features per recording MBID (empty = no data)
+    feature_cache: Arc<TokioMutex<BoundedCache<CachedFeatures>>>,
+    // Imported AcousticBrainz dump, checked before the web service (FEATURE_STORE_PATH)
+    feature_store: Option<Arc<FeatureStore>>,
+    popularity: Arc<PopularityIndex>,
+    runs: Arc<RunRegistry>, // Streaming recommendation runs, for resuming
+    result_pools: Arc<ResultPools>, // Ranked results, for paging past the first page
+    jobs: Arc<JobStore>,    // Background recommendation jobs, persisted to JOBS_DIR
+    upstreams: Arc<UpstreamMonitor>,
 }
 
+// Gaps longer than this mean the limiter was idle, not that requests are slow
+const RATE_LIMITER_IDLE_GAP: Duration = Duration::from_secs(5);
+
 // Rate limiter for MusicBrainz API (1 request per second)
 struct RateLimiter {
     last_request: TokioMutex<Instant>,
+    seconds_per_request: std::sync::Mutex<f64>, // Moving average while busy, for ETAs
+    waiting: AtomicUsize,                       // Callers queued in wait(), for /status
+}
+
+// Counts a caller as queued until it's through (or gives up)
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl RateLimiter {
     fn new() -> Self {
         Self {
             last_request: TokioMutex::new(Instant::now() - Duration::from_secs(1)),
+            seconds_per_request: std::sync::Mutex::new(1.0),
+            waiting: AtomicUsize::new(0),
         }
     }
 
     async fn wait(&self) {
+        self.waiting.fetch_add(1, Ordering::Relaxed);
+        let _slot = QueueSlot(&self.waiting);
         let mut last = self.last_request.lock().await;
         let elapsed = last.elapsed();
         if elapsed < Duration::from_secs(1) {
             let wait_time = Duration::from_secs(1) - elapsed;
             tokio::time::sleep(wait_time).await;
         }
+        let interval = last.elapsed();
+        if interval < RATE_LIMITER_IDLE_GAP {
+            let mut average = self.seconds_per_request.lock().unwrap();
+            *average = 0.8 * *average + 0.2 * interval.as_secs_f64();
+        }
+        *last = Instant::now();
+    }
+
+    fn seconds_per_request(&self) -> f64 {
+        *self.seconds_per_request.lock().unwrap()
+    }
+
+    // True while another caller holds or is queued for the limiter
+    fn is_busy(&self) -> bool {
+        self.last_request.try_lock().is_err()
+    }
+
+    // Take the next request slot only if it's free right now, without queueing
+    fn try_acquire(&self) -> bool {
+        let Ok(mut last) = self.last_request.try_lock() else {
+            return false;
+        };
+        if last.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
         *last = Instant::now();
+        true
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
     }
 }
 
-// Token Manager
-struct TokenManager {
-    token: TokioMutex<Option<(String, Instant)>>,
-    client_id: String,
-    client_secret: String,
+// Upstream health: the outcome of every request to each service we depend on,
+// so /status can tell a dead upstream from a quiet one. An upstream counts as
+// reachable when its last success is more recent than its last error.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum Upstream {
+    MusicBrainz,
+    LastFm,
+    ListenBrainz,
+    AcousticBrainz,
+    CoverArtArchive,
+    Genius,
+    Spotify,
 }
 
-impl TokenManager {
-    fn new(client_id: String, client_secret: String) -> Self {
-        Self {
-            token: TokioMutex::new(None),
-            client_id,
-            client_secret,
+impl Upstream {
+    // Whether our requests carry credentials, so a 401 or 403 means they were refused
+    fn authenticated(self) -> bool {
+        !matches!(
+            self,
+            Upstream::MusicBrainz
+                | Upstream::AcousticBrainz
+                | Upstream::ListenBrainz
+                | Upstream::CoverArtArchive
+        )
+    }
+}
+
+const UPSTREAMS: [Upstream; 7] = [
+    Upstream::MusicBrainz,
+    Upstream::LastFm,
+    Upstream::ListenBrainz,
+    Upstream::AcousticBrainz,
+    Upstream::CoverArtArchive,
+    Upstream::Genius,
+    Upstream::Spotify,
+];
+
+#[derive(Clone, Default, Serialize, ToSchema)]
+struct UpstreamHealth {
+    last_success: Option<u64>, // Unix seconds
+    last_error: Option<UpstreamError>,
+}
+
+#[derive(Clone, Serialize, ToSchema)]
+struct UpstreamError {
+    message: String,
+    at: u64, // Unix seconds
+}
+
+impl UpstreamHealth {
+    fn reachable(&self) -> Option<bool> {
+        match (self.last_success, &self.last_error) {
+            (None, None) => None,
+            (Some(success), Some(error)) => Some(success >= error.at),
+            (success, _) => Some(success.is_some()),
         }
     }
 
-    async fn get_token(&self) -> String {
-        let mut guard = self.token.lock().await;
-        if let Some((ref tok, ref time)) = *guard {
-            if time.elapsed() < Duration::from_secs(3600 - 60) {
-                return tok.clone();
+    // When the upstream last answered or failed, in Unix seconds
+    fn last_outcome(&self) -> Option<u64> {
+        self.last_success
+            .max(self.last_error.as_ref().map(|error| error.at))
+    }
+}
+
+// Whether a response means the upstream isn't serving us. Not-found and other
+// client errors are answers; rate limiting and server errors are not, and nor
+// are auth failures when the request carried credentials.
+fn is_upstream_failure(status: reqwest::StatusCode, authenticated: bool) -> bool {
+    status.is_server_error()
+        || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || (authenticated && matches!(status.as_u16(), 401 | 403))
+}
+
+#[derive(Default)]
+struct UpstreamMonitor {
+    health: std::sync::Mutex<BTreeMap<Upstream, UpstreamHealth>>,
+    last_probe: std::sync::Mutex<Option<Instant>>, // Of the last /status?probe=true
+}
+
+impl UpstreamMonitor {
+    fn succeeded(&self, upstream: Upstream) {
+        let mut health = self.health.lock().unwrap();
+        health.entry(upstream).or_default().last_success = Some(unix_now());
+    }
+
+    fn failed(&self, upstream: Upstream, message: String) {
+        let mut health = self.health.lock().unwrap();
+        health.entry(upstream).or_default().last_error = Some(UpstreamError {
+            message,
+            at: unix_now(),
+        });
+    }
+
+    fn record(
+        &self,
+        upstream: Upstream,
+        result: &reqwest::Result<reqwest::Response>,
+        authenticated: bool,
+    ) {
+        match result {
+            Ok(response) if is_upstream_failure(response.status(), authenticated) => {
+                self.failed(upstream, format!("HTTP {}", response.status()))
             }
+            Ok(_) => self.succeeded(upstream),
+            Err(e) => self.failed(upstream, e.to_string()),
         }
-        let client = reqwest::Client::new();
-        let params = [("grant_type", "client_credentials")];
-        let res = client
-            .post("https://accounts.spotify.com/api/token")
-            .basic_auth(&self.client_id, Some(&self.client_secret))
-            .form(&params)
-            .send()
-            .await
+    }
+
+    // Send a request and record the outcome
+    async fn send(
+        &self,
+        upstream: Upstream,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        let result = request.send().await;
+        self.record(upstream, &result, upstream.authenticated());
+        result
+    }
+
+    // Whether a probe may run now; at most one per PROBE_INTERVAL, whoever asks
+    fn start_probe(&self) -> bool {
+        let mut last_probe = self.last_probe.lock().unwrap();
+        if last_probe.is_some_and(|at| at.elapsed() < PROBE_INTERVAL) {
+            return false;
+        }
+        *last_probe = Some(Instant::now());
+        true
+    }
+
+    fn snapshot(&self, upstream: Upstream) -> UpstreamHealth {
+        self.health
+            .lock()
             .unwrap()
-            .json::<TokenResponse>()
-            .await
-            .unwrap();
-        let new_token = res.access_token;
-        *guard = Some((new_token.clone(), Instant::now()));
-        new_token
+            .get(&upstream)
+            .cloned()
+            .unwrap_or_default()
     }
 }
 
-// Levenshtein distance
-fn levenshtein(a: &str, b: &str) -> usize {
-    let a_chars: Vec<char> = a.chars().collect();
-    let b_chars: Vec<char> = b.chars().collect();
-    let mut matrix = vec![vec![0; b_chars.len() + 1]; a_chars.len() + 1];
-    for i in 0..=a_chars.len() {
-        matrix[i][0] = i;
+// Type-ahead cache for /mb/suggest: recent query results, recordings users
+// have actually picked, and in-flight MusicBrainz lookups to coalesce onto
+const SUGGEST_CACHE_TTL: Duration = Duration::from_secs(600);
+const SUGGEST_CACHE_CAPACITY: usize = 2000;
+const SUGGEST_POPULAR_CAPACITY: usize = 5000;
+
+type SharedSuggestions = Shared<BoxFuture<'static, Option<Vec<Suggestion>>>>;
+
+struct SuggestCache {
+    recent: TokioMutex<HashMap<String, (Instant, Vec<Suggestion>)>>,
+    popular: TokioMutex<HashMap<String, (Suggestion, u32)>>,
+    in_flight: TokioMutex<HashMap<String, SharedSuggestions>>,
+}
+
+impl SuggestCache {
+    fn new() -> Self {
+        Self {
+            recent: TokioMutex::new(HashMap::new()),
+            popular: TokioMutex::new(HashMap::new()),
+            in_flight: TokioMutex::new(HashMap::new()),
+        }
     }
-    for j in 0..=b_chars.len() {
-        matrix[0][j] = j;
+
+    async fn get(&self, query: &str) -> Option<Vec<Suggestion>> {
+        let recent = self.recent.lock().await;
+        recent
+            .get(query)
+            .filter(|(at, _)| at.elapsed() < SUGGEST_CACHE_TTL)
+            .map(|(_, suggestions)| suggestions.clone())
     }
-    for i in 1..=a_chars.len() {
-        for j in 1..=b_chars.len() {
-            let cost = if a_chars[i - 1] == b_chars[j - 1] {
-                0
-            } else {
-                1
-            };
-            matrix[i][j] = *[
-                matrix[i - 1][j] + 1,
-                matrix[i][j - 1] + 1,
-                matrix[i - 1][j - 1] + cost,
-            ]
-            .iter()
-            .min()
-            .unwrap();
+
+    // Best answer without touching MusicBrainz: results cached for the longest
+    // shorter prefix of the query, topped up with popular recordings
+    async fn local_matches(&self, query: &str, limit: usize) -> Vec<Suggestion> {
+        let mut matches: Vec<Suggestion> = Vec::new();
+        {
+            let recent = self.recent.lock().await;
+            let prefix_hit = query
+                .char_indices()
+                .map(|(i, _)| &query[..i])
+                .rev()
+                .find_map(|prefix| recent.get(prefix));
+            if let Some((_, suggestions)) = prefix_hit {
+                matches.extend(
+                    suggestions
+                        .iter()
+                        .filter(|s| suggestion_matches(s, query))
+                        .cloned(),
+                );
+            }
         }
+
+        let popular = self.popular.lock().await;
+        let mut ranked: Vec<&(Suggestion, u32)> = popular
+            .values()
+            .filter(|(s, _)| suggestion_matches(s, query))
+            .collect();
+        ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.label.cmp(&b.0.label)));
+        for (suggestion, _) in ranked {
+            if !matches.iter().any(|m| m.id == suggestion.id) {
+                matches.push(suggestion.clone());
+            }
+        }
+
+        matches.truncate(limit);
+        matches
     }
-    matrix[a_chars.len()][b_chars.len()]
-}
 
-// Simple VADER-like sentiment analysis
-fn vader_analyse(lyrics: &str) -> f64 {
-    let words: Vec<&str> = lyrics.split_whitespace().collect();
-    if words.is_empty() {
-        return 0.0;
+    async fn store(&self, query: &str, suggestions: Vec<Suggestion>) {
+        let mut recent = self.recent.lock().await;
+        recent.insert(query.to_string(), (Instant::now(), suggestions));
+        if recent.len() > SUGGEST_CACHE_CAPACITY {
+            if let Some(oldest) = recent
+                .iter()
+                .min_by_key(|(_, (at, _))| *at)
+                .map(|(key, _)| key.clone())
+            {
+                recent.remove(&oldest);
+            }
+        }
     }
-    let lexicon: HashMap<&str, f64> = [
-        ("love", 3.0),
+
+    async fn remember_seeds(&self, seeds: &[TrackId]) {
+        for seed in seeds {
+            if let Some(mbid) = &seed.mbid {
+                let suggestion =
+                    Suggestion::new(mbid.clone(), seed.name.clone(), seed.artist.clone());
+                self.remember(suggestion).await;
+            }
+        }
+    }
+
+    // Count a recording that was searched for or used as a seed
+    async fn remember(&self, suggestion: Suggestion) {
+        let mut popular = self.popular.lock().await;
+        popular
+            .entry(suggestion.id.clone())
+            .or_insert((suggestion, 0))
+            .1 += 1;
+        if popular.len() > SUGGEST_POPULAR_CAPACITY {
+            if let Some(least) = popular
+                .iter()
+                .min_by_key(|(_, (_, hits))| *hits)
+                .map(|(id, _)| id.clone())
+            {
+                popular.remove(&least);
+            }
+        }
+    }
+}
+
+// A map that forgets: entries expire after `ttl`, and past `capacity` the oldest
+// tenth is dropped in one go. For lookups that are cheap to redo but would
+// otherwise grow with every recording the server has ever seen.
+struct BoundedCache<V> {
+    entries: HashMap<String, (Instant, V)>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl<V: Clone> BoundedCache<V> {
+    fn new(ttl: Duration, capacity: usize) -> Self {
+        BoundedCache {
+            entries: HashMap::new(),
+            ttl,
+            capacity,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<V> {
+        self.entries
+            .get(key)
+            .filter(|(at, _)| at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: V) {
+        self.entries.insert(key, (Instant::now(), value));
+        if self.entries.len() > self.capacity {
+            self.sweep();
+        }
+        if self.entries.len() > self.capacity {
+            let mut oldest: Vec<(Instant, String)> = self
+                .entries
+                .iter()
+                .map(|(key, (at, _))| (*at, key.clone()))
+                .collect();
+            oldest.sort_unstable();
+            for (_, key) in oldest.into_iter().take((self.capacity / 10).max(1)) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    // Drop expired entries
+    fn sweep(&mut self) {
+        let ttl = self.ttl;
+        self.entries.retain(|_, (at, _)| at.elapsed() < ttl);
+    }
+}
+
+// Popularity: ListenBrainz listen counts mapped onto percentiles of a global
+// reference distribution, so a score means the same thing in every request.
+// The reference is a persisted sample of log listen counts seen in lookups,
+// seeded with a rough prior and refreshed periodically (or on demand).
+const POPULARITY_SAMPLE_CAPACITY: usize = 20_000;
+const POPULARITY_MIN_SAMPLE: usize = 500; // Below this the prior is kept
+const POPULARITY_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const LISTENBRAINZ_BATCH_SIZE: usize = 100;
+const LISTEN_COUNTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const LISTEN_COUNTS_CAPACITY: usize = 100_000;
+
+// (percentile, listens) anchors for the prior, roughly ListenBrainz's long tail
+const POPULARITY_PRIOR: [(f64, f64); 7] = [
+    (0.0, 0.0),
+    (25.0, 5.0),
+    (50.0, 50.0),
+    (75.0, 500.0),
+    (90.0, 5_000.0),
+    (99.0, 200_000.0),
+    (100.0, 10_000_000.0),
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct ListenCounts {
+    listens: u64,
+    users: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PopularityScale {
+    breakpoints: Vec<f64>, // ln(1 + listens) at percentiles 0..=100
+    sample: Vec<f64>,      // Observed ln(1 + listens), reservoir sampled
+    seen: u64,             // Observations offered to the reservoir
+    refreshed_at: u64,     // Unix seconds, 0 = never (prior)
+}
+
+impl PopularityScale {
+    fn prior() -> Self {
+        let breakpoints = (0..=100)
+            .map(|p| {
+                let p = p as f64;
+                let i = POPULARITY_PRIOR
+                    .windows(2)
+                    .position(|w| p <= w[1].0)
+                    .unwrap_or(POPULARITY_PRIOR.len() - 2);
+                let ((p0, l0), (p1, l1)) = (POPULARITY_PRIOR[i], POPULARITY_PRIOR[i + 1]);
+                let (l0, l1) = (l0.ln_1p(), l1.ln_1p());
+                l0 + (l1 - l0) * (p - p0) / (p1 - p0)
+            })
+            .collect();
+        PopularityScale {
+            breakpoints,
+            sample: Vec::new(),
+            seen: 0,
+            refreshed_at: 0,
+        }
+    }
+
+    // Percentile (0-100) of a listen count
+    fn score(&self, listens: u64) -> u32 {
+        let value = (listens as f64).ln_1p();
+        let upper = self.breakpoints.partition_point(|b| *b < value);
+        if upper == 0 {
+            return 0;
+        }
+        if upper >= self.breakpoints.len() {
+            return 100;
+        }
+        let (low, high) = (self.breakpoints[upper - 1], self.breakpoints[upper]);
+        let fraction = if high > low {
+            (value - low) / (high - low)
+        } else {
+            0.0
+        };
+        ((upper - 1) as f64 + fraction).round().min(100.0) as u32
+    }
+
+    fn observe(&mut self, listens: u64) {
+        self.seen += 1;
+        let value = (listens as f64).ln_1p();
+        if self.sample.len() < POPULARITY_SAMPLE_CAPACITY {
+            self.sample.push(value);
+        } else {
+            let slot = rand::Rng::gen_range(&mut thread_rng(), 0..self.seen) as usize;
+            if slot < self.sample.len() {
+                self.sample[slot] = value;
+            }
+        }
+    }
+
+    // Recompute percentiles from the sample. `top_listens` (the sitewide most
+    // listened recording) pins the top end, which a sample rarely reaches.
+    fn refresh(&mut self, top_listens: Option<u64>, now: u64) -> bool {
+        if self.sample.len() < POPULARITY_MIN_SAMPLE {
+            return false;
+        }
+        let mut sorted = self.sample.clone();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let last = sorted.len() - 1;
+        self.breakpoints = (0..=100)
+            .map(|p| sorted[(p as f64 / 100.0 * last as f64).round() as usize])
+            .collect();
+        if let Some(top) = top_listens {
+            let top = (top as f64).ln_1p();
+            if top > self.breakpoints[100] {
+                self.breakpoints[100] = top;
+            }
+        }
+        self.refreshed_at = now;
+        true
+    }
+}
+
+struct PopularityIndex {
+    scale: TokioMutex<PopularityScale>,
+    counts: TokioMutex<BoundedCache<ListenCounts>>, // Per recording MBID
+    path: std::path::PathBuf,
+    upstreams: Arc<UpstreamMonitor>,
+}
+
+impl PopularityIndex {
+    // Load the persisted scale, or start from the prior
+    fn load(path: std::path::PathBuf, upstreams: Arc<UpstreamMonitor>) -> Self {
+        let scale = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|text| serde_json::from_str::<PopularityScale>(&text).ok())
+            .filter(|scale| scale.breakpoints.len() == 101)
+            .unwrap_or_else(PopularityScale::prior);
+        PopularityIndex {
+            scale: TokioMutex::new(scale),
+            counts: TokioMutex::new(BoundedCache::new(LISTEN_COUNTS_TTL, LISTEN_COUNTS_CAPACITY)),
+            path,
+            upstreams,
+        }
+    }
+
+    async fn score(&self, listens: u64) -> u32 {
+        self.scale.lock().await.score(listens)
+    }
+
+    // Listen counts for many recordings, from the cache or in batched lookups.
+    // Recordings ListenBrainz doesn't know get zero counts.
+    async fn counts(&self, mbids: &[String]) -> HashMap<String, ListenCounts> {
+        let mut result = HashMap::new();
+        let mut missing = Vec::new();
+        {
+            let cache = self.counts.lock().await;
+            for mbid in mbids {
+                match cache.get(mbid) {
+                    Some(counts) => {
+                        result.insert(mbid.clone(), counts);
+                    }
+                    None if !missing.contains(mbid) => missing.push(mbid.clone()),
+                    None => {}
+                }
+            }
+        }
+
+        for chunk in missing.chunks(LISTENBRAINZ_BATCH_SIZE) {
+            let fetched = match fetch_listen_counts(chunk, &self.upstreams).await {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    // Not cached, so the next request retries
+                    eprintln!("Failed to fetch popularity: {}", e);
+                    continue;
+                }
+            };
+            let mut scale = self.scale.lock().await;
+            let mut cache = self.counts.lock().await;
+            for mbid in chunk {
+                // Only recordings ListenBrainz knows go into the sample; the rest
+                // would pile up at zero and drag every percentile down
+                let counts = match fetched.get(mbid) {
+                    Some(counts) => {
+                        scale.observe(counts.listens);
+                        *counts
+                    }
+                    None => ListenCounts::default(),
+                };
+                cache.insert(mbid.clone(), counts);
+                result.insert(mbid.clone(), counts);
+            }
+        }
+        result
+    }
+
+    // Recompute the scale and persist it (sample included, so it survives restarts)
+    async fn refresh(&self) -> Result<serde_json::Value, Box<dyn std::error::Error + Send + Sync>> {
+        let top_listens = match fetch_sitewide_top_listens(&self.upstreams).await {
+            Ok(top) => top,
+            Err(e) => {
+                eprintln!("Failed to fetch sitewide top recording: {}", e);
+                None
+            }
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        let (serialized, summary) = {
+            let mut scale = self.scale.lock().await;
+            let refreshed = scale.refresh(top_listens, now);
+            let summary = serde_json::json!({
+                "refreshed": refreshed,
+                "sample_size": scale.sample.len(),
+                "min_sample_size": POPULARITY_MIN_SAMPLE,
+                "refreshed_at": scale.refreshed_at,
+                "median_listens": scale.breakpoints[50].exp_m1().round(),
+                "p90_listens": scale.breakpoints[90].exp_m1().round(),
+            });
+            (serde_json::to_string(&*scale)?, summary)
+        };
+        tokio::fs::write(&self.path, serialized).await?;
+        Ok(summary)
+    }
+}
+
+// Token Manager
+struct TokenManager {
+    token: TokioMutex<Option<(String, Instant)>>,
+    client_id: String,
+    client_secret: String,
+    upstreams: Arc<UpstreamMonitor>,
+}
+
+impl TokenManager {
+    fn new(client_id: String, client_secret: String, upstreams: Arc<UpstreamMonitor>) -> Self {
+        Self {
+            token: TokioMutex::new(None),
+            client_id,
+            client_secret,
+            upstreams,
+        }
+    }
+
+    async fn get_token(&self) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut guard = self.token.lock().await;
+        if let Some((ref tok, ref time)) = *guard {
+            if time.elapsed() < Duration::from_secs(3600 - 60) {
+                return Ok(tok.clone());
+            }
+        }
+        let client = reqwest::Client::new();
+        let params = [("grant_type", "client_credentials")];
+        let request = client
+            .post("https://accounts.spotify.com/api/token")
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&params);
+        let res = self
+            .upstreams
+            .send(Upstream::Spotify, request)
+            .await?
+            .error_for_status()?
+            .json::<TokenResponse>()
+            .await?;
+        let new_token = res.access_token;
+        *guard = Some((new_token.clone(), Instant::now()));
+        Ok(new_token)
+    }
+}
+
+// Levenshtein distance
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a_chars: Vec<char> = a.chars().collect();
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut matrix = vec![vec![0; b_chars.len() + 1]; a_chars.len() + 1];
+    for (i, row) in matrix.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    for (j, cell) in matrix[0].iter_mut().enumerate() {
+        *cell = j;
+    }
+    for i in 1..=a_chars.len() {
+        for j in 1..=b_chars.len() {
+            let cost = if a_chars[i - 1] == b_chars[j - 1] {
+                0
+            } else {
+                1
+            };
+            matrix[i][j] = *[
+                matrix[i - 1][j] + 1,
+                matrix[i][j - 1] + 1,
+                matrix[i - 1][j - 1] + cost,
+            ]
+            .iter()
+            .min()
+            .unwrap();
+        }
+    }
+    matrix[a_chars.len()][b_chars.len()]
+}
+
+// Simple VADER-like sentiment analysis
+fn vader_analyse(lyrics: &str) -> f64 {
+    let words: Vec<&str> = lyrics.split_whitespace().collect();
+    if words.is_empty() {
+        return 0.0;
+    }
+    let lexicon: HashMap<&str, f64> = [
+        ("love", 3.0),
         ("hate", -3.0),
         ("happy", 2.5),
         ("sad", -2.5),
@@ -387,7 +1378,7 @@ fn vader_analyse(lyrics: &str) -> f64 {
 }
 
 // Cosine similarity
-fn cosine_similarity(a: &Vec<f64>, b: &Vec<f64>, weights: &HashMap<String, f64>) -> f64 {
+fn cosine_similarity(a: &[f64], b: &[f64], weights: &HashMap<String, f64>) -> f64 {
     let dot: f64 = a
         .iter()
         .zip(b.iter())
@@ -401,1317 +1392,6767 @@ fn cosine_similarity(a: &Vec<f64>, b: &Vec<f64>, weights: &HashMap<String, f64>)
     dot / (mag_a * mag_b)
 }
 
-// Search MusicBrainz for recordings with better ranking
-async fn search_musicbrainz(
-    query: &str,
-    rate_limiter: &RateLimiter,
-) -> Result<Vec<MusicBrainzRecording>, Box<dyn std::error::Error + Send + Sync>> {
-    rate_limiter.wait().await;
+// Words that distinguish versions of the same song rather than different songs.
+// They're matched as whole words, so "Oliver" isn't live and "Credit" isn't an
+// edit. Remixes are different songs, so "mix" only counts as "mono/stereo mix".
+const VERSION_MARKERS: [&str; 11] = [
+    "remaster",
+    "remastered",
+    "live",
+    "version",
+    "edit",
+    "mono",
+    "stereo",
+    "deluxe",
+    "anniversary",
+    "bonus",
+    "demo",
+];
+
+fn has_version_marker(segment: &str) -> bool {
+    let normalized = normalize_text(segment);
+    let words: Vec<&str> = normalized.split_whitespace().collect();
+    words.iter().enumerate().any(|(i, word)| {
+        VERSION_MARKERS.contains(word)
+            // "Take 2", "Alternate Take", but not "(Take Me Home)"
+            || (*word == "take"
+                && (words.get(i + 1).is_some_and(|next| next.parse::<u32>().is_ok())
+                    || (i > 0 && matches!(words[i - 1], "alternate" | "alt"))))
+    })
+}
 
-    let client = reqwest::Client::new();
+fn has_word(text: &str, word: &str) -> bool {
+    normalize_text(text).split_whitespace().any(|w| w == word)
+}
 
-    // Parse query to extract track and artist
-    let enhanced_query = if query.contains(" by ") {
-        // If "by" is present, use as-is
-        let parts: Vec<&str> = query.split(" by ").collect();
-        if parts.len() == 2 {
-            format!("recording:\"{}\" AND artist:\"{}\"", parts[0], parts[1])
-        } else {
-            query.to_string()
-        }
-    } else {
-        // Try to intelligently split track and artist
-        // Common patterns: "Track Name Artist Name" or "Track Name - Artist Name"
-        if query.contains(" - ") {
-            let parts: Vec<&str> = query.split(" - ").collect();
-            if parts.len() == 2 {
-                format!("recording:\"{}\" AND artist:\"{}\"", parts[0].trim(), parts[1].trim())
-            } else {
-                query.to_string()
-            }
-        } else {
-            // For queries like "All the Small Things Blink-182", try to identify where the artist starts
-            // This is tricky, but we can make some educated guesses
-            let words: Vec<&str> = query.split_whitespace().collect();
-            if words.len() >= 3 {
-                // Try different split points to find the best match
-                format!("\"{}\"", query) // Search the whole phrase
-            } else {
-                query.to_string()
+fn normalize_text(text: &str) -> String {
+    text.to_lowercase()
+        .replace('&', " and ")
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Normalize a recording title so "Song (2011 Remaster)", "Song - Live at Wembley"
+// and "Song" all map to "song"
+fn normalize_title(title: &str) -> String {
+    let mut stripped = String::new();
+    let mut bracket: Option<String> = None;
+    for c in title.chars() {
+        match (c, bracket.as_mut()) {
+            ('(' | '[', None) => bracket = Some(String::new()),
+            (')' | ']', Some(segment)) => {
+                // Keep bracketed text that is part of the title, e.g. "(Don't Fear) The Reaper"
+                if !has_version_marker(segment) {
+                    stripped.push_str(segment);
+                }
+                bracket = None;
             }
+            (_, Some(segment)) => segment.push(c),
+            (_, None) => stripped.push(c),
         }
-    };
-
-    eprintln!("MusicBrainz search query: {}", enhanced_query);
-
-    let url = format!(
-        "https://musicbrainz.org/ws/2/recording?query={}&fmt=json&limit=50",
-        urlencoding::encode(&enhanced_query)
-    );
-
-    let response = client
-        .get(&url)
-        .header(
-            "User-Agent",
-            "NextTrack/0.1.0 (https://github.com/leebenson/nexttrack)",
-        )
-        .send()
-        .await?;
+    }
+    if let Some(segment) = bracket {
+        stripped.push_str(&segment);
+    }
 
-    if !response.status().is_success() {
-        return Err(format!("MusicBrainz API error: {}", response.status()).into());
+    if let Some((head, tail)) = stripped.split_once(" - ") {
+        if has_version_marker(tail) {
+            stripped = head.to_string();
+        }
     }
 
-    let search_result = response.json::<MusicBrainzSearchResponse>().await?;
-    Ok(search_result.recordings)
+    normalize_text(&stripped)
 }
 
-// Fetch popularity data from ListenBrainz (no auth required)
-async fn fetch_listenbrainz_popularity(
-    mbids: Vec<String>,
-) -> Result<HashMap<String, u32>, Box<dyn std::error::Error + Send + Sync>> {
-    if mbids.is_empty() {
-        return Ok(HashMap::new());
-    }
+fn normalize_artist(name: &str) -> String {
+    let normalized = normalize_text(name);
+    normalized
+        .strip_prefix("the ")
+        .map(str::to_string)
+        .unwrap_or(normalized)
+}
 
-    let client = reqwest::Client::new();
-    let url = "https://api.listenbrainz.org/1/popularity/recording";
+fn artist_credits(rec: &MusicBrainzRecording) -> Vec<ArtistCredit> {
+    rec.artist_credit
+        .iter()
+        .flatten()
+        .map(|credit| ArtistCredit {
+            name: credit
+                .name
+                .clone()
+                .unwrap_or_else(|| credit.artist.name.clone()),
+            mbid: Some(credit.artist.id.clone()),
+            joinphrase: credit.joinphrase.clone(),
+        })
+        .collect()
+}
 
-    let request = ListenBrainzRecordingRequest {
-        recording_mbids: mbids,
-    };
+// The credit as MusicBrainz renders it: each credited name followed by its join phrase
+fn display_credit(credits: &[ArtistCredit]) -> String {
+    let credit: String = credits
+        .iter()
+        .map(|c| format!("{}{}", c.name, c.joinphrase))
+        .collect();
+    if credit.trim().is_empty() {
+        "Unknown Artist".to_string()
+    } else {
+        credit
+    }
+}
 
-    let response = client
-        .post(url)
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await?;
+fn recording_artist(rec: &MusicBrainzRecording) -> String {
+    display_credit(&artist_credits(rec))
+}
 
-    if !response.status().is_success() {
-        return Err(format!("ListenBrainz API error: {}", response.status()).into());
+// Normalized names of every credited artist (credited and canonical spellings)
+fn credited_artist_names(rec: &MusicBrainzRecording) -> Vec<String> {
+    let mut names = Vec::new();
+    for credit in rec.artist_credit.iter().flatten() {
+        for name in [credit.name.as_deref(), Some(credit.artist.name.as_str())]
+            .into_iter()
+            .flatten()
+        {
+            let name = normalize_artist(name);
+            if !name.is_empty() && !names.contains(&name) {
+                names.push(name);
+            }
+        }
     }
+    names
+}
 
-    let popularity_data = response.json::<ListenBrainzPopularityResponse>().await?;
+const CREDIT_SEPARATORS: &[&str] = &[
+    " featuring ",
+    " feat. ",
+    " feat ",
+    " ft. ",
+    " ft ",
+    " with ",
+    " vs. ",
+    " vs ",
+    " / ",
+];
+
+// Names in a free-text credit such as "Artist A feat. Artist B", whole credit first.
+// Only collaboration markers split: "&", "and" and commas are as likely to be part
+// of a band name ("Earth, Wind & Fire") as to join two artists.
+fn split_credit(credit: &str) -> Vec<String> {
+    let mut marked = format!(" {} ", credit.to_lowercase());
+    for separator in CREDIT_SEPARATORS {
+        marked = marked.replace(separator, "|");
+    }
+    let mut names = vec![normalize_artist(credit)];
+    for part in marked.split('|') {
+        let name = normalize_artist(part);
+        if !name.is_empty() && !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
 
-    // Convert to a hashmap and calculate popularity score (0-100)
-    let mut popularity_map = HashMap::new();
+// Identity used for diversity: MBID when known, otherwise the normalized name
+fn artist_identities(track: &Track) -> Vec<String> {
+    if track.artists.is_empty() {
+        return vec![normalize_artist(&track.artist)];
+    }
+    track
+        .artists
+        .iter()
+        .map(|credit| {
+            credit
+                .mbid
+                .clone()
+                .unwrap_or_else(|| normalize_artist(&credit.name))
+        })
+        .collect()
+}
 
-    // Find max listen count for normalization
-    let max_count = popularity_data
-        .payload
+// Keys shared by every version of the same song by the same artist.
+// Two recordings belong to the same group if they share any key.
+// Title and work keys are emitted per credited artist, so "A feat. B" and a
+// release of the same song under B's name alone are recognised as one song.
+fn canonical_keys(rec: &MusicBrainzRecording) -> Vec<String> {
+    let mut artists = credited_artist_names(rec);
+    if artists.is_empty() {
+        artists.push(String::new());
+    }
+    let title = normalize_title(&rec.title);
+    let mut keys: Vec<String> = artists
         .iter()
-        .filter_map(|p| p.total_listen_count)
-        .max()
-        .unwrap_or(1);
-
-    for recording in popularity_data.payload {
-        if let Some(listen_count) = recording.total_listen_count {
-            // Normalize to 0-100 scale, with logarithmic scaling for better distribution
-            let popularity = if listen_count > 0 {
-                let log_count = (listen_count as f64).ln();
-                let log_max = (max_count as f64).ln();
-                ((log_count / log_max) * 100.0).min(100.0) as u32
-            } else {
-                0
-            };
-            popularity_map.insert(recording.recording_mbid, popularity);
-        } else {
-            popularity_map.insert(recording.recording_mbid, 0);
+        .map(|artist| format!("title:{}|{}", title, artist))
+        .collect();
+    for isrc in &rec.isrcs {
+        keys.push(format!("isrc:{}", isrc.to_uppercase()));
+    }
+    for work in rec.relations.iter().filter_map(|rel| rel.work.as_ref()) {
+        // Scope works by artist so covers by other artists stay distinct
+        for artist in &artists {
+            keys.push(format!("work:{}|{}", work.id, artist));
         }
     }
-
-    Ok(popularity_map)
+    keys
 }
 
-// Fetch AcousticBrainz features for a recording
-async fn fetch_acousticbrainz_features(
-    mbid: &str,
-) -> Result<AcousticBrainzResponse, Box<dyn std::error::Error + Send + Sync>> {
-    let client = reqwest::Client::new();
-    // AcousticBrainz doesn't require rate limiting
-    let url = format!("https://acousticbrainz.org/api/v1/{}/low-level", mbid);
+fn is_live_recording(rec: &MusicBrainzRecording) -> bool {
+    let disambiguation_live = rec
+        .disambiguation
+        .as_deref()
+        .map(|d| has_word(d, "live"))
+        .unwrap_or(false);
+    let releases_live = rec.releases.as_ref().is_some_and(|releases| {
+        !releases.is_empty()
+            && releases.iter().all(|release| {
+                release
+                    .release_group
+                    .as_ref()
+                    .is_some_and(|rg| rg.secondary_types.iter().any(|t| t == "Live"))
+            })
+    });
+    disambiguation_live || releases_live
+}
 
-    let response = client.get(&url).send().await?;
+fn is_compilation_only(rec: &MusicBrainzRecording) -> bool {
+    rec.releases.as_ref().is_some_and(|releases| {
+        !releases.is_empty()
+            && releases.iter().all(|release| {
+                release
+                    .release_group
+                    .as_ref()
+                    .is_some_and(|rg| rg.secondary_types.iter().any(|t| t == "Compilation"))
+            })
+    })
+}
 
-    if response.status() == 404 {
-        return Err("No AcousticBrainz data for this recording".into());
-    }
+// Derive cover/karaoke/tribute/live flags from MusicBrainz data: performance
+// relationship attributes (lookups only), release-group secondary types,
+// disambiguation comments and the credited artist's type and description
+fn recording_flags(rec: &MusicBrainzRecording) -> RecordingFlags {
+    let mentions = |text: Option<&str>, words: &[&str]| {
+        text.map(|t| {
+            let t = t.to_lowercase();
+            words.iter().any(|w| t.contains(w))
+        })
+        .unwrap_or(false)
+    };
+    let has_attribute = |name: &str| {
+        rec.relations
+            .iter()
+            .any(|rel| rel.attributes.iter().any(|a| a.eq_ignore_ascii_case(name)))
+    };
+    let artists: Vec<&MusicBrainzArtist> = rec
+        .artist_credit
+        .iter()
+        .flatten()
+        .map(|credit| &credit.artist)
+        .collect();
+    let release_groups = rec
+        .releases
+        .iter()
+        .flatten()
+        .filter_map(|release| release.release_group.as_ref());
+
+    let cover = has_attribute("cover");
+    let karaoke = has_attribute("karaoke")
+        || mentions(rec.disambiguation.as_deref(), &["karaoke", "backing track"])
+        || release_groups.clone().any(|rg| {
+            rg.secondary_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case("karaoke"))
+        })
+        || artists
+            .iter()
+            .any(|artist| mentions(artist.disambiguation.as_deref(), &["karaoke"]));
+    // Cover factories are usually typed "Other" rather than as a person or group
+    let tribute = artists.iter().any(|artist| {
+        mentions(
+            artist.disambiguation.as_deref(),
+            &["tribute", "cover band", "covers band", "soundalike"],
+        ) || (cover && artist.artist_type.as_deref() == Some("Other"))
+    });
 
-    if !response.status().is_success() {
-        return Err(format!("AcousticBrainz API error: {}", response.status()).into());
+    RecordingFlags {
+        cover,
+        karaoke,
+        tribute,
+        live: has_attribute("live") || is_live_recording(rec),
+        compilation: is_compilation_only(rec),
     }
+}
+
+fn is_studio_release(release: &MusicBrainzRelease) -> bool {
+    release.release_group.as_ref().is_some_and(|rg| {
+        rg.secondary_types.is_empty()
+            && matches!(rg.primary_type.as_deref(), Some("Album" | "Single" | "EP"))
+    })
+}
 
-    let features = response.json::<AcousticBrainzResponse>().await?;
-    Ok(features)
+// The release a recording is best known from: earliest official studio release
+fn primary_release(rec: &MusicBrainzRecording) -> Option<&MusicBrainzRelease> {
+    rec.releases.as_deref()?.iter().min_by_key(|release| {
+        (
+            release.status.as_deref() != Some("Official"),
+            !is_studio_release(release),
+            release
+                .date
+                .clone()
+                .filter(|date| !date.is_empty())
+                .unwrap_or_else(|| "9999".to_string()),
+        )
+    })
 }
 
-// Convert AcousticBrainz features to our internal format
-fn convert_acousticbrainz_features(ab_features: &AcousticBrainzResponse) -> HashMap<String, f64> {
-    let mut features = HashMap::new();
+fn release_year(date: Option<&str>) -> Option<u32> {
+    date.and_then(|d| d.get(..4)).and_then(|y| y.parse().ok())
+}
 
-    if let Some(lowlevel) = &ab_features.lowlevel {
-        features.insert("tempo".to_string(), lowlevel.bpm / 200.0); // Normalize to 0-1
-        features.insert(
-            "loudness".to_string(),
-            (lowlevel.average_loudness + 60.0) / 60.0,
-        ); // Normalize
-        features.insert("complexity".to_string(), lowlevel.dynamic_complexity);
+fn tag_counts(tags: &[MusicBrainzTag]) -> Vec<TagCount> {
+    let mut counts: Vec<TagCount> = tags
+        .iter()
+        .filter(|tag| tag.count > 0)
+        .map(|tag| TagCount {
+            name: tag.name.to_lowercase(),
+            count: tag.count,
+        })
+        .collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    counts
+}
+
+// Everything we keep from a search result or recording lookup
+fn track_metadata(rec: &MusicBrainzRecording) -> TrackMetadata {
+    let release = primary_release(rec);
+    TrackMetadata {
+        duration_ms: rec.length,
+        first_release_date: rec
+            .first_release_date
+            .clone()
+            .filter(|date| !date.is_empty()),
+        release: release.map(|release| ReleaseInfo {
+            id: release.id.clone(),
+            title: release.title.clone(),
+            date: release.date.clone().filter(|date| !date.is_empty()),
+        }),
+        release_group: release
+            .and_then(|release| release.release_group.as_ref())
+            .and_then(|rg| {
+                Some(ReleaseGroupInfo {
+                    id: rg.id.clone()?,
+                    title: rg.title.clone().unwrap_or_default(),
+                    primary_type: rg.primary_type.clone(),
+                    secondary_types: rg.secondary_types.clone(),
+                })
+            }),
+        isrcs: rec.isrcs.clone(),
+        tags: tag_counts(&rec.tags),
+        genres: tag_counts(&rec.genres),
     }
+}
 
-    if let Some(highlevel) = &ab_features.highlevel {
-        if let Some(danceable) = highlevel.danceability.all.danceable {
-            features.insert("danceability".to_string(), danceable);
-        }
-        if let Some(happy) = highlevel.mood_happy.all.happy {
-            features.insert("valence".to_string(), happy); // Similar to Spotify's valence
+// Lower is better: studio before live, original album/single before compilations,
+// earliest release first, clean titles before "(Remastered)" variants
+fn canonical_rank(rec: &MusicBrainzRecording) -> (bool, bool, bool, String, bool) {
+    let releases = rec.releases.as_deref().unwrap_or_default();
+    let has_studio_release = releases.iter().any(is_studio_release);
+    let has_official_release = releases
+        .iter()
+        .any(|release| release.status.as_deref() == Some("Official"));
+    let earliest = rec
+        .first_release_date
+        .clone()
+        .filter(|date| !date.is_empty())
+        .or_else(|| {
+            releases
+                .iter()
+                .filter_map(|release| release.date.clone())
+                .filter(|date| !date.is_empty())
+                .min()
+        })
+        .unwrap_or_else(|| "9999".to_string());
+    let marked_title = normalize_text(&rec.title) != normalize_title(&rec.title);
+    (
+        is_live_recording(rec),
+        !has_studio_release,
+        !has_official_release,
+        earliest,
+        marked_title,
+    )
+}
+
+// Group recordings that share a work, ISRC or normalized title+artist (union-find)
+fn group_recordings(recordings: &[MusicBrainzRecording]) -> Vec<Vec<usize>> {
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        if parent[i] != i {
+            parent[i] = find(parent, parent[i]);
         }
-        if let Some(aggressive) = highlevel.mood_aggressive.all.aggressive {
-            features.insert("energy".to_string(), aggressive); // Similar to energy
+        parent[i]
+    }
+
+    let mut parent: Vec<usize> = (0..recordings.len()).collect();
+    let mut owner: HashMap<String, usize> = HashMap::new();
+    for (i, rec) in recordings.iter().enumerate() {
+        for key in canonical_keys(rec) {
+            match owner.get(&key) {
+                Some(&j) => {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    if a != b {
+                        parent[a.max(b)] = a.min(b);
+                    }
+                }
+                None => {
+                    owner.insert(key, i);
+                }
+            }
         }
     }
 
-    features
+    // Groups are ordered by their first member so search ranking is preserved
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of_root: HashMap<usize, usize> = HashMap::new();
+    for i in 0..recordings.len() {
+        let root = find(&mut parent, i);
+        let index = *group_of_root.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[index].push(i);
+    }
+    groups
 }
 
-// Resolve tracks using MusicBrainz
-async fn resolve_tracks_musicbrainz(
-    queries: Vec<String>,
-    rate_limiter: &RateLimiter,
-) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
-    let mut ids = Vec::new();
-    let mut seen = HashSet::new();
+fn choose_canonical<'a>(
+    recordings: impl IntoIterator<Item = &'a MusicBrainzRecording>,
+) -> Option<&'a MusicBrainzRecording> {
+    recordings.into_iter().min_by_key(|rec| canonical_rank(rec))
+}
 
-    for query in queries {
-        eprintln!("Searching for: {}", query);
-        // Search MusicBrainz
-        let recordings = search_musicbrainz(&query, rate_limiter).await?;
+// Tracks which songs (not just MBIDs) have already been seen
+struct CanonicalIndex {
+    keys: HashSet<String>,
+}
 
-        if !recordings.is_empty() {
-            // Try to find the best match
-            let query_lower = query.to_lowercase();
-            let words: Vec<&str> = query.split_whitespace().collect();
-            
-            // Score each recording based on how well it matches the query
-            let mut scored_recordings: Vec<(&MusicBrainzRecording, i32)> = recordings
-                .iter()
-                .map(|rec| {
-                    let title_lower = rec.title.to_lowercase();
-                    let artist_name = rec
-                        .artist_credit
-                        .as_ref()
-                        .and_then(|credits| credits.first())
-                        .map(|credit| credit.artist.name.to_lowercase())
-                        .unwrap_or_default();
-                    
-                    let mut score = 0;
-                    
-                    // HUGE bonus for exact artist match
-                    for word in &words {
-                        let word_lower = word.to_lowercase();
-                        // Check if this might be the artist name
-                        if artist_name == word_lower {
-                            score += 100; // Exact artist match
-                        } else if artist_name.contains(&word_lower) {
-                            score += 10;
-                        }
-                        
-                        if title_lower.contains(&word_lower) {
-                            score += 5;
-                        }
-                    }
-                    
-                    // Check if the artist name in the result matches what we're searching for
-                    // Split the query by "by" to extract expected artist
-                    if query_lower.contains(" by ") {
-                        if let Some(expected_artist) = query_lower.split(" by ").nth(1) {
-                            let expected_artist = expected_artist.trim();
-                            if artist_name == expected_artist {
-                                score += 200; // Exact artist match
-                            } else if artist_name.contains(expected_artist) || expected_artist.contains(&artist_name) {
-                                score += 50; // Partial match
-                            }
-                        }
-                    }
-                    
-                    // HEAVY penalties for covers
-                    if title_lower.contains("cover") || title_lower.contains("tribute") || 
-                       title_lower.contains("karaoke") || title_lower.contains(" vs ") ||
-                       title_lower.contains("remix") || title_lower.contains("acoustic") ||
-                       artist_name.contains("tribute") || artist_name.contains("karaoke") ||
-                       artist_name.contains("twinkle") || artist_name.contains("rock star") ||
-                       title_lower.contains("(") && title_lower.contains(")") {
-                        score -= 100;
-                    }
-                    
-                    // Bonus for clean titles (no parentheses)
-                    if !title_lower.contains("(") && !title_lower.contains("[") {
-                        score += 20;
-                    }
-                    
-                    (rec, score)
-                })
-                .collect();
-            
-            // Sort by score descending
-            scored_recordings.sort_by(|a, b| b.1.cmp(&a.1));
-            
-            // Debug: show top 5 results
-            eprintln!("Top search results for '{}' =>", query);
-            for (i, (rec, score)) in scored_recordings.iter().take(5).enumerate() {
-                let artist_name = rec.artist_credit.as_ref()
-                    .and_then(|credits| credits.first())
-                    .map(|credit| credit.artist.name.as_str())
-                    .unwrap_or("Unknown");
-                eprintln!("  {}. {} by {} (score: {})", i+1, rec.title, artist_name, score);
-            }
-            
-            let best_recording = scored_recordings.first()
-                .map(|(rec, _)| *rec)
-                .unwrap_or(&recordings[0]);
+impl CanonicalIndex {
+    fn new() -> Self {
+        Self {
+            keys: HashSet::new(),
+        }
+    }
 
-            let artist_name = best_recording
-                .artist_credit
-                .as_ref()
-                .and_then(|credits| credits.first())
-                .map(|credit| credit.artist.name.clone())
-                .unwrap_or_else(|| "Unknown Artist".to_string());
+    fn contains(&self, keys: &[String]) -> bool {
+        keys.iter().any(|key| self.keys.contains(key))
+    }
 
-            eprintln!(
-                "Selected recording: {} by {} (from {} results)",
-                best_recording.title, artist_name, recordings.len()
-            );
+    // Returns false if any of the keys was already seen
+    fn insert(&mut self, keys: &[String]) -> bool {
+        let is_new = !self.contains(keys);
+        self.keys.extend(keys.iter().cloned());
+        is_new
+    }
+}
 
-            let track_id = TrackId {
-                mbid: Some(best_recording.id.clone()),
-                spotify: None,
-                name: best_recording.title.clone(),
-                artist: artist_name,
-            };
+// Search MusicBrainz for recordings with better ranking
+async fn search_musicbrainz(
+    query: &str,
+    app_state: &AppState,
+) -> Result<Vec<MusicBrainzRecording>, Box<dyn std::error::Error + Send + Sync>> {
+    let enhanced_query = build_recording_query(query);
+    eprintln!("MusicBrainz search query: {}", enhanced_query);
 
-            let id_string = best_recording.id.clone();
-            if seen.insert(id_string) {
-                ids.push(track_id);
+    let search_result = search_musicbrainz_page(&enhanced_query, 50, 0, app_state).await?;
+    Ok(search_result.recordings)
+}
+
+// Turn a free-text "Track by Artist" / "Track - Artist" query into Lucene syntax
+fn build_recording_query(query: &str) -> String {
+    // Parse query to extract track and artist
+    if query.contains(" by ") {
+        // If "by" is present, use as-is
+        let parts: Vec<&str> = query.split(" by ").collect();
+        if parts.len() == 2 {
+            format!("recording:\"{}\" AND artist:\"{}\"", parts[0], parts[1])
+        } else {
+            query.to_string()
+        }
+    } else {
+        // Try to intelligently split track and artist
+        // Common patterns: "Track Name Artist Name" or "Track Name - Artist Name"
+        if query.contains(" - ") {
+            let parts: Vec<&str> = query.split(" - ").collect();
+            if parts.len() == 2 {
+                format!("recording:\"{}\" AND artist:\"{}\"", parts[0].trim(), parts[1].trim())
+            } else {
+                query.to_string()
             }
         } else {
-            eprintln!("No recordings found for: {}", query);
+            // For queries like "All the Small Things Blink-182", try to identify where the artist starts
+            // This is tricky, but we can make some educated guesses
+            let words: Vec<&str> = query.split_whitespace().collect();
+            if words.len() >= 3 {
+                // Try different split points to find the best match
+                format!("\"{}\"", query) // Search the whole phrase
+            } else {
+                query.to_string()
+            }
         }
     }
+}
 
-    Ok(ids)
+// One page of a MusicBrainz recording search (Lucene query, limit <= 100)
+async fn search_musicbrainz_page(
+    lucene_query: &str,
+    limit: usize,
+    offset: usize,
+    app_state: &AppState,
+) -> Result<MusicBrainzSearchResponse, Box<dyn std::error::Error + Send + Sync>> {
+    let path = format!(
+        "recording?query={}&limit={}&offset={}",
+        urlencoding::encode(lucene_query),
+        limit,
+        offset
+    );
+    musicbrainz_get(&path, app_state).await
 }
 
-// Resolve tracks (legacy Spotify version - kept for migration)
-async fn resolve_tracks(
-    queries: Vec<String>,
-    token: &str,
-) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+const RECORDING_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const RECORDING_CACHE_CAPACITY: usize = 20_000;
+
+// Look up a recording with its work relationships, ISRCs, releases and tags (cached)
+async fn fetch_recording_details(mbid: &str, app_state: &AppState) -> Option<MusicBrainzRecording> {
+    if let Some(rec) = app_state.recording_cache.lock().await.get(mbid) {
+        return Some(rec);
+    }
+
+    app_state.rate_limiter.wait().await;
     let client = reqwest::Client::new();
-    let mut ids = Vec::new();
-    let mut seen = HashSet::new();
-    for query in queries {
-        // Check if query looks like a Spotify track ID (22 characters, alphanumeric)
-        if query.len() == 22 && query.chars().all(|c| c.is_alphanumeric()) {
-            // Verify it's a valid track ID by fetching track info
-            let track_url = format!("https://api.spotify.com/v1/tracks/{}", query);
-            let track_response = client
-                .get(&track_url)
-                .header(AUTHORIZATION, format!("Bearer {}", token))
-                .send()
-                .await?;
+    let url = format!(
+        "https://musicbrainz.org/ws/2/recording/{}?inc=artist-credits+isrcs+releases+release-groups+work-rels+tags+genres&fmt=json",
+        mbid
+    );
 
-            if track_response.status().is_success() {
-                if seen.insert(query.clone()) {
-                    // For Spotify IDs, we need to fetch track info to get name/artist
-                    let track_info = track_response.json::<SpotifyTrack>().await?;
-                    ids.push(TrackId {
-                        spotify: Some(query),
-                        mbid: None,
-                        name: track_info.name,
-                        artist: track_info
-                            .artists
-                            .first()
-                            .map(|a| a.name.clone())
-                            .unwrap_or_else(|| "Unknown Artist".to_string()),
-                    });
-                }
-                continue;
-            }
-        }
+    let request = client.get(&url).header(
+        "User-Agent",
+        "NextTrack/0.1.0 (https://github.com/leebenson/nexttrack)",
+    );
+    let response = app_state
+        .upstreams
+        .send(Upstream::MusicBrainz, request)
+        .await
+        .ok()?;
 
-        // Otherwise, search for the track
-        let url = format!(
-            "https://api.spotify.com/v1/search?q={}&type=track&limit=10",
-            urlencoding::encode(&query)
+    if !response.status().is_success() {
+        eprintln!(
+            "MusicBrainz lookup error for {}: {}",
+            mbid,
+            response.status()
         );
-        let res = client
-            .get(&url)
-            .header(AUTHORIZATION, format!("Bearer {}", token))
-            .send()
-            .await?
-            .json::<SpotifySearchResponse>()
-            .await?;
-        if res.tracks.items.is_empty() {
-            continue;
-        }
-        let lowercase_query = query.to_lowercase();
-        let best_match = res
-            .tracks
-            .items
-            .into_iter()
-            .min_by_key(|t| levenshtein(&t.name.to_lowercase(), &lowercase_query))
-            .unwrap();
-        if seen.insert(best_match.id.clone()) {
-            ids.push(TrackId {
-                spotify: Some(best_match.id),
-                mbid: None,
-                name: best_match.name.clone(),
-                artist: best_match
-                    .artists
-                    .first()
-                    .map(|a| a.name.clone())
-                    .unwrap_or_else(|| "Unknown Artist".to_string()),
-            });
-        }
+        return None;
     }
-    Ok(ids)
+
+    let rec = response.json::<MusicBrainzRecording>().await.ok()?;
+    app_state
+        .recording_cache
+        .lock()
+        .await
+        .insert(mbid.to_string(), rec.clone());
+    Some(rec)
 }
 
-// Fetch Spotify features
-async fn fetch_spotify_features(
-    track_id: &str,
-    token: &str,
-) -> Result<SpotifyFeatures, Box<dyn std::error::Error + Send + Sync>> {
-    let client = reqwest::Client::new();
-    let url = format!("https://api.spotify.com/v1/audio-features/{}", track_id);
-    let res = client
-        .get(&url)
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await?
-        .json::<SpotifyFeatures>()
-        .await?;
-    Ok(res)
+#[derive(Deserialize)]
+struct MusicBrainzRecordingBrowse {
+    #[serde(default)]
+    recordings: Vec<MusicBrainzRecording>,
 }
 
-// Fetch Genius lyrics
-async fn fetch_genius_lyrics(
-    query: &str,
-) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-    let genius_key = env::var("GENIUS_API_KEY").expect("GENIUS_API_KEY not set");
+// Credited artists on the earliest released recording of a work
+async fn work_original_artists(work_id: &str, app_state: &AppState) -> Option<Vec<String>> {
+    if let Some(artists) = app_state.original_artist_cache.lock().await.get(work_id) {
+        return Some(artists);
+    }
+
+    let browse: MusicBrainzRecordingBrowse = match musicbrainz_get(
+        &format!("recording?work={}&inc=artist-credits&limit=100", work_id),
+        app_state,
+    )
+    .await
+    {
+        Ok(browse) => browse,
+        Err(e) => {
+            eprintln!("MusicBrainz work browse error for {}: {}", work_id, e);
+            return None;
+        }
+    };
+    let artists = browse
+        .recordings
+        .iter()
+        .filter(|rec| {
+            rec.first_release_date
+                .as_deref()
+                .is_some_and(|d| !d.is_empty())
+        })
+        .min_by(|a, b| a.first_release_date.cmp(&b.first_release_date))
+        .map(credited_artist_names)
+        .unwrap_or_default();
+
+    app_state
+        .original_artist_cache
+        .lock()
+        .await
+        .insert(work_id.to_string(), artists.clone());
+    Some(artists)
+}
+
+// Flags from a looked-up recording, plus a cover check against the original artist
+// of each work it performs (for covers MusicBrainz doesn't mark as such)
+async fn lookup_flags(details: &MusicBrainzRecording, app_state: &AppState) -> RecordingFlags {
+    let mut flags = recording_flags(details);
+    if flags.is_imitation() {
+        return flags;
+    }
+
+    let credited = credited_artist_names(details);
+    for work in details.relations.iter().filter_map(|rel| rel.work.as_ref()) {
+        let Some(original) = work_original_artists(&work.id, app_state).await else {
+            continue;
+        };
+        if !original.is_empty() && !original.iter().any(|name| credited.contains(name)) {
+            eprintln!(
+                "{} is a cover of a work first recorded by {}",
+                details.title,
+                original.join(", ")
+            );
+            flags.cover = true;
+            break;
+        }
+    }
+    flags
+}
+
+// Every query word must prefix a word of the title or artist ("bohem rhap que")
+fn suggestion_matches(suggestion: &Suggestion, query: &str) -> bool {
+    let haystack = normalize_text(&format!("{} {}", suggestion.name, suggestion.artist));
+    let words: Vec<&str> = haystack.split_whitespace().collect();
+    normalize_text(query)
+        .split_whitespace()
+        .filter(|q| *q != "by")
+        .all(|q| words.iter().any(|w| w.starts_with(q)))
+}
+
+// Prefix search for type-ahead: a small result set and no ListenBrainz lookup
+async fn fetch_suggestions(
+    query: &str,
+    app_state: &AppState,
+) -> Result<Vec<Suggestion>, Box<dyn std::error::Error + Send + Sync>> {
+    let words: Vec<String> = normalize_text(query)
+        .split_whitespace()
+        .filter(|w| *w != "by")
+        .map(str::to_string)
+        .collect();
+    let Some(last) = words.len().checked_sub(1) else {
+        return Ok(Vec::new());
+    };
+
+    // Each word may be in the title or the artist; the last one is still being typed
+    let lucene_query = words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| {
+            let term = if i == last {
+                format!("{}*", word)
+            } else {
+                word.clone()
+            };
+            format!("(recording:{0} OR artist:{0})", term)
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ");
+
+    app_state.rate_limiter.wait().await;
     let client = reqwest::Client::new();
-    // First search
-    let search_url = format!(
-        "https://api.genius.com/search?q={}",
-        urlencoding::encode(query)
+    let url = format!(
+        "https://musicbrainz.org/ws/2/recording?query={}&fmt=json&limit=15",
+        urlencoding::encode(&lucene_query)
     );
-    let res = client
-        .get(&search_url)
-        .header(AUTHORIZATION, format!("Bearer {}", genius_key))
-        .send()
-        .await?
-        .json::<GeniusSearchResponse>()
+
+    let request = client.get(&url).header(
+        "User-Agent",
+        "NextTrack/0.1.0 (https://github.com/leebenson/nexttrack)",
+    );
+    let response = app_state
+        .upstreams
+        .send(Upstream::MusicBrainz, request)
         .await?;
-    if res.response.hits.is_empty() {
-        return Ok("".to_string());
+
+    if !response.status().is_success() {
+        return Err(format!("MusicBrainz API error: {}", response.status()).into());
     }
-    let path = res.response.hits[0].result.path.clone();
-    let lyrics_url = format!("https://genius.com{}", path);
-    let text = client.get(&lyrics_url).send().await?.text().await?;
-    let document = Html::parse_document(&text);
-    let selector = Selector::parse(r#"div[data-lyrics-container="true"]"#).unwrap();
-    let lyrics = document
-        .select(&selector)
-        .flat_map(|el| el.text())
-        .collect::<String>();
-    Ok(lyrics)
+
+    let recordings = response
+        .json::<MusicBrainzSearchResponse>()
+        .await?
+        .recordings;
+    let suggestions = group_recordings(&recordings)
+        .into_iter()
+        .filter_map(|group| choose_canonical(group.iter().map(|&i| &recordings[i])))
+        .filter(|rec| !recording_flags(rec).is_imitation())
+        .map(|rec| Suggestion::new(rec.id.clone(), rec.title.clone(), recording_artist(rec)))
+        .take(10)
+        .collect();
+    Ok(suggestions)
 }
 
-// Fetch album art from MusicBrainz Cover Art Archive
-async fn fetch_album_art(mbid: &str, rate_limiter: &RateLimiter) -> Option<String> {
-    // First get recording details to find a release
-    rate_limiter.wait().await;
+// Listen and user counts from ListenBrainz (no auth required), one batch per call
+async fn fetch_listen_counts(
+    mbids: &[String],
+    upstreams: &UpstreamMonitor,
+) -> Result<HashMap<String, ListenCounts>, Box<dyn std::error::Error + Send + Sync>> {
+    if mbids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
     let client = reqwest::Client::new();
-    
-    let recording_url = format!(
-        "https://musicbrainz.org/ws/2/recording/{}?inc=releases&fmt=json",
-        mbid
+    let url = "https://api.listenbrainz.org/1/popularity/recording";
+
+    let request = ListenBrainzRecordingRequest {
+        recording_mbids: mbids.to_vec(),
+    };
+
+    let request = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .json(&request);
+    let response = upstreams.send(Upstream::ListenBrainz, request).await?;
+
+    if !response.status().is_success() {
+        return Err(format!("ListenBrainz API error: {}", response.status()).into());
+    }
+
+    // Recordings ListenBrainz doesn't know come back with null counts; leave them out
+    let popularity_data = response.json::<ListenBrainzPopularityResponse>().await?;
+    Ok(popularity_data
+        .payload
+        .into_iter()
+        .filter_map(|recording| {
+            let counts = ListenCounts {
+                listens: recording.total_listen_count?,
+                users: recording.total_user_count.unwrap_or(0),
+            };
+            Some((recording.recording_mbid, counts))
+        })
+        .collect())
+}
+
+// Listen count of the sitewide most listened recording, to anchor the top percentile
+async fn fetch_sitewide_top_listens(
+    upstreams: &UpstreamMonitor,
+) -> Result<Option<u64>, Box<dyn std::error::Error + Send + Sync>> {
+    let url = "https://api.listenbrainz.org/1/stats/sitewide/recordings?range=all_time&count=1";
+    let request = reqwest::Client::new().get(url);
+    let response = upstreams.send(Upstream::ListenBrainz, request).await?;
+    if !response.status().is_success() {
+        return Err(format!("ListenBrainz API error: {}", response.status()).into());
+    }
+    let body = response.json::<serde_json::Value>().await?;
+    Ok(body["payload"]["recordings"][0]["listen_count"].as_u64())
+}
+
+// Bulk-load audio features and listen counts so per-track aggregation hits caches
+async fn prefetch_track_data(mbids: &[String], app_state: &AppState) {
+    tokio::join!(
+        prefetch_acousticbrainz_features(mbids, app_state),
+        app_state.popularity.counts(mbids),
     );
-    
-    let recording_response = client
-        .get(&recording_url)
-        .header("User-Agent", "NextTrack/1.0")
-        .send()
-        .await;
-        
-    if let Ok(resp) = recording_response {
-        if let Ok(json) = resp.json::<serde_json::Value>().await {
-            // Get the first release ID
-            if let Some(releases) = json.get("releases").and_then(|r| r.as_array()) {
-                if let Some(first_release) = releases.first() {
-                    if let Some(release_id) = first_release.get("id").and_then(|id| id.as_str()) {
-                        // Try to fetch cover art for this release
-                        let cover_url = format!(
-                            "https://coverartarchive.org/release/{}",
-                            release_id
-                        );
-                        
-                        if let Ok(cover_resp) = client.get(&cover_url).send().await {
-                            if let Ok(cover_data) = cover_resp.json::<CoverArtArchiveResponse>().await {
-                                // Return the large thumbnail if available, otherwise the full image
-                                if let Some(first_image) = cover_data.images.first() {
-                                    return first_image.thumbnails.large.clone()
-                                        .or_else(|| first_image.thumbnails.small.clone())
-                                        .or_else(|| Some(first_image.image.clone()));
-                                }
-                            }
-                        }
-                    }
+}
+
+fn track_mbids(ids: &[TrackId]) -> Vec<String> {
+    ids.iter().filter_map(|id| id.mbid.clone()).collect()
+}
+
+// Fetch low- and high-level AcousticBrainz data for many recordings at once.
+// Recordings without data are simply absent from the result.
+async fn fetch_acousticbrainz_bulk(
+    mbids: &[String],
+    upstreams: &UpstreamMonitor,
+) -> AcousticBrainzBulk {
+    let client = reqwest::Client::new();
+    let mut bulk = AcousticBrainzBulk::default();
+
+    // AcousticBrainz doesn't require rate limiting; bulk endpoints take 25 recordings
+    for chunk in mbids.chunks(25) {
+        for level in ["low-level", "high-level"] {
+            let url = format!(
+                "https://acousticbrainz.org/api/v1/{}?recording_ids={}",
+                level,
+                chunk.join(";")
+            );
+            // Keep whatever the other level (or chunks) returned, and remember which
+            // recordings are missing a level so they are fetched again later
+            let body = match fetch_acousticbrainz_level(&client, &url, upstreams).await {
+                Ok(body) => body,
+                Err(e) => {
+                    eprintln!("AcousticBrainz {} error: {}", level, e);
+                    bulk.incomplete.extend(chunk.iter().cloned());
+                    continue;
+                }
+            };
+            // { "<mbid>": { "0": <document>, ... }, "mbid_mapping": {...} }
+            for (mbid, submissions) in body {
+                let Some(document) = submissions.get("0") else {
+                    continue;
+                };
+                match serde_json::from_value::<AcousticBrainzResponse>(document.clone()) {
+                    Ok(document) => bulk.documents.entry(mbid).or_default().merge(document),
+                    Err(e) => eprintln!(
+                        "Unreadable AcousticBrainz {} data for {}: {}",
+                        level, mbid, e
+                    ),
                 }
             }
         }
     }
-    
-    None
-}
 
-// Aggregate features using MusicBrainz/AcousticBrainz
-async fn aggregate_features_musicbrainz(
-    track: &TrackId,
-    _rate_limiter: &RateLimiter,
-) -> Result<Track, Box<dyn std::error::Error + Send + Sync>> {
-    let mut features = HashMap::new();
+    bulk
+}
 
-    // Try to get AcousticBrainz features if we have an MBID
-    if let Some(mbid) = &track.mbid {
-        match fetch_acousticbrainz_features(mbid).await {
-            Ok(ab_features) => {
-                features = convert_acousticbrainz_features(&ab_features);
-            }
-            Err(_) => {
-                // No AcousticBrainz data available - use empty features
-                // This is real - many tracks don't have audio analysis
-            }
-        }
+async fn fetch_acousticbrainz_level(
+    client: &reqwest::Client,
+    url: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<HashMap<String, serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+    let response = upstreams
+        .send(Upstream::AcousticBrainz, client.get(url))
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("status {}", response.status()).into());
     }
+    Ok(response.json().await?)
+}
 
-    // Get lyrics sentiment from Genius
-    let query = format!("{} {}", track.name, track.artist);
-    let sentiment = match fetch_genius_lyrics(&query).await {
-        Ok(lyrics) => vader_analyse(&lyrics),
-        Err(_) => 0.5, // Neutral sentiment as fallback
+// Documents from both bulk endpoints, and the recordings a failed request left
+// without one of the levels
+#[derive(Default)]
+struct AcousticBrainzBulk {
+    documents: HashMap<String, AcousticBrainzResponse>,
+    incomplete: HashSet<String>,
+}
+
+// Converted features for a recording (empty = no audio data). Incomplete entries
+// are missing a level after a failed request and are fetched again when next needed.
+#[derive(Clone, Default)]
+struct CachedFeatures {
+    features: HashMap<String, f64>,
+    complete: bool,
+}
+
+// AcousticBrainz stopped collecting data in 2022, so entries only expire to bound memory
+const FEATURE_CACHE_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const FEATURE_CACHE_CAPACITY: usize = 50_000;
+
+// Fill the feature cache for every recording not already in it, in bulk
+async fn prefetch_acousticbrainz_features(mbids: &[String], app_state: &AppState) {
+    let missing: Vec<String> = {
+        let cache = app_state.feature_cache.lock().await;
+        let mut missing: Vec<String> = mbids
+            .iter()
+            .filter(|mbid| !cache.get(mbid).is_some_and(|cached| cached.complete))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        missing
     };
-    features.insert("sentiment".to_string(), sentiment);
+    if missing.is_empty() {
+        return;
+    }
 
-    // Fetch real popularity from ListenBrainz if we have an MBID
-    let popularity = if let Some(mbid) = &track.mbid {
-        match fetch_listenbrainz_popularity(vec![mbid.clone()]).await {
-            Ok(map) => map.get(mbid).copied().unwrap_or(0),
-            Err(_) => 0,
+    // The local dump answers instantly and offline. Recordings it has only one level
+    // for (the low- and high-level dumps are imported separately) still go to the
+    // network for the other one.
+    let mut documents = read_feature_store(&missing, app_state).await;
+    let network: Vec<String> = missing
+        .iter()
+        .filter(|mbid| {
+            !documents
+                .get(*mbid)
+                .is_some_and(AcousticBrainzResponse::has_both_levels)
+        })
+        .cloned()
+        .collect();
+    let mut complete: HashSet<String> = missing
+        .iter()
+        .filter(|mbid| !network.contains(mbid))
+        .cloned()
+        .collect();
+    if !network.is_empty() {
+        let bulk = fetch_acousticbrainz_bulk(&network, &app_state.upstreams).await;
+        complete.extend(
+            network
+                .iter()
+                .filter(|mbid| !bulk.incomplete.contains(*mbid))
+                .cloned(),
+        );
+        for (mbid, document) in bulk.documents {
+            documents.entry(mbid).or_default().merge(document);
         }
-    } else {
-        0
-    };
+    }
 
-    // Fetch album art if we have an MBID
-    let album_art = if let Some(mbid) = &track.mbid {
-        fetch_album_art(mbid, _rate_limiter).await
-    } else {
-        None
-    };
+    let mut analysed = Vec::new();
+    for mbid in missing {
+        let mut features = documents
+            .get(&mbid)
+            .map(convert_acousticbrainz_features)
+            .unwrap_or_default();
+        // Newer recordings AcousticBrainz never saw: analyse a local copy if we have one
+        if features.is_empty() {
+            if let Some(local) = analyse_library_file(&mbid).await {
+                features = local;
+            }
+        }
+        analysed.push((mbid, features));
+    }
 
-    Ok(Track {
-        id: track
-            .mbid
-            .clone()
-            .unwrap_or_else(|| format!("{}-{}", track.name, track.artist)),
-        name: track.name.clone(),
-        artist: track.artist.clone(),
-        features,
-        popularity,
-        album_art,
-    })
+    store_features(analysed, &complete, app_state).await;
 }
 
-// Aggregate features (legacy Spotify version)
-async fn aggregate_features(
-    track_id: &str,
-    token: &str,
-) -> Result<Track, Box<dyn std::error::Error + Send + Sync>> {
-    let spotify_features = fetch_spotify_features(track_id, token).await?;
-    // Fetch track info to get name and artist for Genius query
-    let client = reqwest::Client::new();
-    let track_url = format!("https://api.spotify.com/v1/tracks/{}", track_id);
-    let track_res = client
-        .get(&track_url)
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await?
-        .json::<SpotifyTrack>()
-        .await?;
-    let query = format!("{} {}", track_res.name, track_res.artists[0].name);
-    let lyrics = fetch_genius_lyrics(&query).await?;
-    let sentiment = vader_analyse(&lyrics);
-    let mut features = HashMap::new();
-    features.insert("energy".to_string(), spotify_features.energy);
-    features.insert("valence".to_string(), spotify_features.valence);
-    features.insert("tempo".to_string(), spotify_features.tempo);
-    features.insert("sentiment".to_string(), sentiment);
-    Ok(Track {
-        id: track_id.to_string(),
-        name: track_res.name,
-        artist: track_res.artists[0].name.clone(),
-        features,
-        popularity: track_res.popularity,
-        album_art: None, // Spotify version doesn't support album art yet
-    })
+async fn store_features(
+    analysed: Vec<(String, HashMap<String, f64>)>,
+    complete: &HashSet<String>,
+    app_state: &AppState,
+) {
+    let mut cache = app_state.feature_cache.lock().await;
+    for (mbid, features) in analysed {
+        // An empty map records that there is no audio data for this recording
+        let complete = complete.contains(&mbid);
+        cache.insert(mbid, CachedFeatures { features, complete });
+    }
 }
 
-// Generate candidates using Last.fm
-async fn generate_candidates(
-    seeds: Vec<TrackId>,
-    inputs: &Vec<Track>,
-    prefs: &Preferences,
-    token: &str,
-) -> Result<Vec<Track>, Box<dyn std::error::Error + Send + Sync>> {
-    if seeds.is_empty() {
-        return Err("No seed tracks provided".into());
+// Documents for whichever recordings are in the imported dump
+async fn read_feature_store(
+    mbids: &[String],
+    app_state: &AppState,
+) -> HashMap<String, AcousticBrainzResponse> {
+    let Some(store) = app_state.feature_store.clone() else {
+        return HashMap::new();
+    };
+    let mbids = mbids.to_vec();
+    let documents = match tokio::task::spawn_blocking(move || store.get_many(&mbids)).await {
+        Ok(Ok(documents)) => documents,
+        Ok(Err(e)) => {
+            eprintln!("Feature store read failed: {}", e);
+            return HashMap::new();
+        }
+        Err(e) => {
+            eprintln!("Feature store task failed: {}", e);
+            return HashMap::new();
+        }
+    };
+    documents
+        .into_iter()
+        .filter_map(|(mbid, document)| {
+            // Stored documents keep the dump's structure, just fewer fields
+            match serde_json::from_value::<AcousticBrainzResponse>(document) {
+                Ok(document) => Some((mbid, document)),
+                Err(e) => {
+                    eprintln!("Unreadable stored features for {}: {}", mbid, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+// Analyse "<AUDIO_LIBRARY_DIR>/<mbid>.<ext>" if it exists
+async fn analyse_library_file(mbid: &str) -> Option<HashMap<String, f64>> {
+    let dir = env::var("AUDIO_LIBRARY_DIR").ok()?;
+    let path = analysis::find_in_library(std::path::Path::new(&dir), mbid)?;
+    eprintln!("Analysing local audio: {}", path.display());
+    match tokio::task::spawn_blocking(move || analysis::analyse_file(&path)).await {
+        Ok(Ok(result)) => Some(result.features()),
+        Ok(Err(e)) => {
+            eprintln!("Local analysis failed for {}: {}", mbid, e);
+            None
+        }
+        Err(e) => {
+            eprintln!("Local analysis task failed for {}: {}", mbid, e);
+            None
+        }
     }
+}
+
+// AcousticBrainz features for one recording (empty when it was never analysed)
+async fn fetch_acousticbrainz_features(mbid: &str, app_state: &AppState) -> HashMap<String, f64> {
+    prefetch_acousticbrainz_features(&[mbid.to_string()], app_state).await;
+    app_state
+        .feature_cache
+        .lock()
+        .await
+        .get(mbid)
+        .map(|cached| cached.features)
+        .unwrap_or_default()
+}
 
-    let lastfm_key = env::var("LASTFM_API_KEY").expect("LASTFM_API_KEY not set");
+// Which recordings AcousticBrainz has analysed, without fetching the data itself.
+// Recordings in a chunk that failed are left out; the rest get a count (0 = never).
+async fn fetch_acousticbrainz_counts(
+    mbids: &[String],
+    upstreams: &UpstreamMonitor,
+) -> HashMap<String, u64> {
     let client = reqwest::Client::new();
-    let mut candidate_queries = Vec::new();
-    for input in inputs {
+    let mut counts = HashMap::new();
+
+    // The bulk endpoints accept at most 25 recordings per request
+    for chunk in mbids.chunks(25) {
         let url = format!(
-            "https://ws.audioscrobbler.com/2.0/?method=track.getsimilar&track={}&artist={}&api_key={}&format=json&limit=50",
-            urlencoding::encode(&input.name),
-            urlencoding::encode(&input.artist),
-            lastfm_key
+            "https://acousticbrainz.org/api/v1/count?recording_ids={}",
+            chunk.join(";")
         );
-        let res = client
-            .get(&url)
-            .send()
-            .await?
-            .json::<LastFmSimilar>()
-            .await?;
-        for sim_track in res.similartracks.track {
-            if sim_track.match_score > 0.1 {
-                // threshold to filter low matches
-                let query = format!("{} by {}", sim_track.name, sim_track.artist.name);
-                candidate_queries.push(query);
+        let body = match fetch_acousticbrainz_count_chunk(&client, &url, upstreams).await {
+            Ok(body) => body,
+            Err(e) => {
+                eprintln!("Failed to fetch AcousticBrainz counts: {}", e);
+                continue;
             }
+        };
+        for mbid in chunk {
+            let count = body
+                .get(mbid)
+                .and_then(|value| value.get("count"))
+                .and_then(|c| c.as_u64())
+                .unwrap_or(0);
+            counts.insert(mbid.clone(), count);
         }
     }
 
-    let candidate_ids = resolve_tracks(candidate_queries, token).await?;
+    counts
+}
 
-    let seed_ids: HashSet<String> = seeds.iter().filter_map(|s| s.spotify.clone()).collect();
+async fn fetch_acousticbrainz_count_chunk(
+    client: &reqwest::Client,
+    url: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<HashMap<String, serde_json::Value>, Box<dyn std::error::Error + Send + Sync>> {
+    let response = upstreams
+        .send(Upstream::AcousticBrainz, client.get(url))
+        .await?;
+    if !response.status().is_success() {
+        return Err(format!("AcousticBrainz API error: {}", response.status()).into());
+    }
+    Ok(response.json().await?)
+}
 
-    let mut candidates = Vec::new();
-    for id in candidate_ids {
-        if let Some(spotify_id) = &id.spotify {
-            if seed_ids.contains(spotify_id) {
-                continue;
-            }
+// Convert AcousticBrainz features to our internal format
+fn convert_acousticbrainz_features(ab_features: &AcousticBrainzResponse) -> HashMap<String, f64> {
+    let mut features = HashMap::new();
+
+    if let Some(bpm) = ab_features.rhythm.as_ref().and_then(|rhythm| rhythm.bpm) {
+        features.insert("tempo".to_string(), bpm / 200.0); // Normalize to 0-1
+    }
+
+    if let Some(lowlevel) = &ab_features.lowlevel {
+        if let Some(loudness) = lowlevel.average_loudness {
+            // Essentia reports average loudness already scaled to 0-1
+            features.insert("loudness".to_string(), loudness);
         }
-        if let Some(spotify_id) = &id.spotify {
-            if let Ok(track) = aggregate_features(spotify_id, token).await {
-                let obscurity_score = 1.0 - (track.popularity as f64 / 100.0);
-                if obscurity_score >= prefs.obscurity {
-                    candidates.push(track);
+        if let Some(complexity) = lowlevel.dynamic_complexity {
+            features.insert("complexity".to_string(), complexity);
+        }
+    }
+
+    if let Some(highlevel) = &ab_features.highlevel {
+        let probability =
+            |feature: &Option<AcousticBrainzFeature>,
+             pick: fn(&AcousticBrainzProbability) -> Option<f64>| {
+                feature.as_ref().and_then(|f| pick(&f.all))
+            };
+        if let Some(danceable) = probability(&highlevel.danceability, |p| p.danceable) {
+            features.insert("danceability".to_string(), danceable);
+        }
+        if let Some(happy) = probability(&highlevel.mood_happy, |p| p.happy) {
+            features.insert("valence".to_string(), happy); // Similar to Spotify's valence
+        }
+        if let Some(aggressive) = probability(&highlevel.mood_aggressive, |p| p.aggressive) {
+            features.insert("energy".to_string(), aggressive); // Similar to energy
+        }
+        if let Some(acoustic) = probability(&highlevel.mood_acoustic, |p| p.acoustic) {
+            features.insert("acousticness".to_string(), acoustic);
+        }
+    }
+
+    if let Some(tonal) = &ab_features.tonal {
+        let pitch_class = tonal.key_key.as_deref().and_then(pitch_class);
+        let mode = match tonal.key_scale.as_deref() {
+            Some("major") => Some(1.0),
+            Some("minor") => Some(0.0),
+            _ => None,
+        };
+        if let (Some(pitch_class), Some(mode)) = (pitch_class, mode) {
+            features.insert("key".to_string(), pitch_class as f64);
+            features.insert("mode".to_string(), mode);
+            if let Some(strength) = tonal.key_strength {
+                features.insert("key_strength".to_string(), strength);
+            }
+        }
+    }
+
+    features
+}
+
+// Key features are categorical, so they're scored by HarmonicScorer rather than
+// as part of the audio feature vector
+const HARMONIC_FEATURES: [&str; 3] = ["key", "mode", "key_strength"];
+
+const PITCH_CLASSES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+// 0 = C ... 11 = B, accepting sharps and flats
+fn pitch_class(tonic: &str) -> Option<u8> {
+    let mut chars = tonic.trim().chars();
+    let natural = match chars.next()?.to_ascii_uppercase() {
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let accidental: i8 = match chars.as_str() {
+        "" => 0,
+        "#" | "♯" => 1,
+        "b" | "♭" => -1,
+        _ => return None,
+    };
+    Some((natural + accidental).rem_euclid(12) as u8)
+}
+
+// Camelot number (1-12) and whether the key is major
+fn camelot(pitch_class: u8, major: bool) -> (u8, bool) {
+    // Minor keys share a number with their relative major, three semitones up
+    let relative_major = if major {
+        pitch_class
+    } else {
+        (pitch_class + 3) % 12
+    };
+    // Each step clockwise is a fifth; C major is 8B
+    (((relative_major as u32 * 7) % 12 + 7) as u8 % 12 + 1, major)
+}
+
+fn harmonic_key(features: &BTreeMap<String, f64>) -> Option<HarmonicKey> {
+    let pitch_class = *features.get("key")? as u8;
+    let major = *features.get("mode")? >= 0.5;
+    let (number, _) = camelot(pitch_class, major);
+    Some(HarmonicKey {
+        tonic: PITCH_CLASSES.get(pitch_class as usize)?.to_string(),
+        scale: if major { "major" } else { "minor" }.to_string(),
+        camelot: format!("{}{}", number, if major { 'B' } else { 'A' }),
+    })
+}
+
+// How well two keys mix: same key, adjacent numbers on the wheel or the relative
+// major/minor mix cleanly; an energy boost (+2) or diagonal move is passable
+fn key_compatibility(a: (u8, bool), b: (u8, bool)) -> f64 {
+    let diff = (a.0 as i8 - b.0 as i8).rem_euclid(12);
+    let steps = diff.min(12 - diff);
+    match (steps, a.1 == b.1) {
+        (0, true) => 1.0,
+        (0, false) | (1, true) => 0.9,
+        (1, false) | (2, true) => 0.6,
+        (2, false) => 0.4,
+        _ => 0.1,
+    }
+}
+
+fn camelot_of(track: &Track) -> Option<(u8, bool)> {
+    let pitch_class = *track.features.get("key")? as u8;
+    let major = *track.features.get("mode")? >= 0.5;
+    Some(camelot(pitch_class, major))
+}
+
+// How many of the best scored search results are looked up before giving up on
+// finding one that isn't a cover
+const IMITATION_CHECK_CANDIDATES: usize = 3;
+
+// Bracketed and " - " suffixed parts of a title, e.g. "(Karaoke Version)"
+fn title_annotations(title: &str) -> Vec<&str> {
+    let mut annotations: Vec<&str> = title
+        .split(['(', '['])
+        .skip(1)
+        .filter_map(|part| part.split([')', ']']).next())
+        .collect();
+    if let Some((_, tail)) = title.split_once(" - ") {
+        annotations.push(tail);
+    }
+    annotations
+}
+
+// Title and artist name markers for imitations MusicBrainz hasn't marked as such,
+// matched as whole words
+fn looks_like_imitation(rec: &MusicBrainzRecording) -> bool {
+    let mentions = |text: &str, phrases: &[&str]| {
+        let text = format!(" {} ", normalize_text(text));
+        phrases
+            .iter()
+            .any(|phrase| text.contains(&format!(" {} ", phrase)))
+    };
+    title_annotations(&rec.title).into_iter().any(|annotation| {
+        mentions(
+            annotation,
+            &["karaoke", "originally performed by", "tribute", "cover"],
+        )
+    }) || rec
+        .artist_credit
+        .iter()
+        .flatten()
+        .any(|credit| mentions(&credit.artist.name, &["karaoke", "tribute"]))
+}
+
+// Swap a match for the canonical version of the same song (original studio
+// recording, earliest release) among the results, by any of the credited artists
+fn canonical_for<'a>(
+    recordings: &'a [MusicBrainzRecording],
+    best_recording: &'a MusicBrainzRecording,
+) -> &'a MusicBrainzRecording {
+    let best_index = recordings
+        .iter()
+        .position(|rec| rec.id == best_recording.id)
+        .unwrap_or(0);
+    let best_artists = credited_artist_names(best_recording);
+    group_recordings(recordings)
+        .into_iter()
+        .find(|group| group.contains(&best_index))
+        .and_then(|group| {
+            choose_canonical(group.iter().map(|&i| &recordings[i]).filter(|rec| {
+                credited_artist_names(rec)
+                    .iter()
+                    .any(|name| best_artists.contains(name))
+            }))
+        })
+        .unwrap_or(best_recording)
+}
+
+// Resolve tracks using MusicBrainz
+async fn resolve_tracks_musicbrainz(
+    queries: Vec<String>,
+    app_state: &AppState,
+) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut ids = Vec::new();
+    let mut seen = CanonicalIndex::new();
+
+    for query in queries {
+        eprintln!("Searching for: {}", query);
+        // Search MusicBrainz
+        let recordings = search_musicbrainz(&query, app_state).await?;
+
+        if !recordings.is_empty() {
+            // Try to find the best match
+            let query_lower = query.to_lowercase();
+            let words: Vec<&str> = query.split_whitespace().collect();
+            
+            // Score each recording based on how well it matches the query
+            let mut scored_recordings: Vec<(&MusicBrainzRecording, i32)> = recordings
+                .iter()
+                .map(|rec| {
+                    let title_lower = rec.title.to_lowercase();
+                    // Every credited artist counts, not just the first
+                    let artist_names = credited_artist_names(rec);
+                    
+                    let mut score = 0;
+                    
+                    // HUGE bonus for exact artist match
+                    for word in &words {
+                        let word_lower = normalize_artist(word);
+                        if word_lower.is_empty() {
+                            continue;
+                        }
+                        // Check if this might be the artist name
+                        if artist_names.contains(&word_lower) {
+                            score += 100; // Exact artist match
+                        } else if artist_names.iter().any(|name| name.contains(&word_lower)) {
+                            score += 10;
+                        }
+                        
+                        if title_lower.contains(&word.to_lowercase()) {
+                            score += 5;
+                        }
+                    }
+                    
+                    // Check if the artist name in the result matches what we're searching for
+                    // Split the query by "by" to extract expected artist
+                    if query_lower.contains(" by ") {
+                        if let Some(expected_artist) = query_lower.split(" by ").nth(1) {
+                            let expected = split_credit(expected_artist.trim());
+                            // Fragments only count when the credit as a whole doesn't match
+                            let exact = if artist_names.contains(&expected[0]) {
+                                1
+                            } else {
+                                expected[1..]
+                                    .iter()
+                                    .filter(|name| artist_names.contains(name))
+                                    .count()
+                            };
+                            if exact > 0 {
+                                // Exact artist match, more for each collaborator named
+                                score += 200 + 20 * (exact as i32 - 1);
+                            } else if expected.iter().any(|expected| {
+                                artist_names.iter().any(|name| {
+                                    !name.is_empty()
+                                        && (name.contains(expected.as_str())
+                                            || expected.contains(name.as_str()))
+                                })
+                            }) {
+                                score += 50; // Partial match
+                            }
+                        }
+                    }
+                    
+                    // HEAVY penalties for covers, karaoke and tribute acts
+                    let flags = recording_flags(rec);
+                    if flags.is_imitation() || looks_like_imitation(rec) {
+                        score -= 100;
+                    }
+                    if flags.live {
+                        score -= 30;
+                    }
+                    if flags.compilation {
+                        score -= 10;
+                    }
+
+                    // Bonus for clean titles (no "Remastered", "Live", "Radio Edit"...)
+                    if normalize_title(&rec.title) == normalize_text(&rec.title) {
+                        score += 20;
+                    }
+
+                    (rec, score)
+                })
+                .collect();
+            
+            // Sort by score descending
+            scored_recordings.sort_by_key(|r| std::cmp::Reverse(r.1));
+            
+            // Debug: show top 5 results
+            eprintln!("Top search results for '{}' =>", query);
+            for (i, (rec, score)) in scored_recordings.iter().take(5).enumerate() {
+                let artist_name = recording_artist(rec);
+                eprintln!("  {}. {} by {} (score: {})", i+1, rec.title, artist_name, score);
+            }
+            
+            // Search results don't carry work relationships (or the cover/karaoke
+            // attributes on them), so look up the top few before choosing and take
+            // the first that isn't an imitation
+            let mut selected = None;
+            let mut checked = HashSet::new();
+            for (best_recording, _) in scored_recordings.iter() {
+                if checked.len() == IMITATION_CHECK_CANDIDATES {
+                    break;
+                }
+                let canonical_recording = canonical_for(&recordings, best_recording);
+                if !checked.insert(canonical_recording.id.clone()) {
+                    continue;
+                }
+
+                let mut keys = canonical_keys(canonical_recording);
+                let mut flags = recording_flags(canonical_recording);
+                if let Some(details) =
+                    fetch_recording_details(&canonical_recording.id, app_state).await
+                {
+                    for key in canonical_keys(&details) {
+                        if !keys.contains(&key) {
+                            keys.push(key);
+                        }
+                    }
+                    flags = lookup_flags(&details, app_state).await;
+                }
+
+                if flags.is_imitation() {
+                    eprintln!(
+                        "Skipping cover/karaoke/tribute recording {} by {}: {:?}",
+                        canonical_recording.title,
+                        recording_artist(canonical_recording),
+                        flags
+                    );
+                }
+                let candidate = (canonical_recording, keys, flags);
+                if !candidate.2.is_imitation() {
+                    selected = Some(candidate);
+                    break;
                 }
+                // Every candidate is an imitation: keep the best scored one
+                selected.get_or_insert(candidate);
+            }
+            let Some((canonical_recording, keys, flags)) = selected else {
+                continue;
+            };
+
+            let artists = artist_credits(canonical_recording);
+            let artist_name = display_credit(&artists);
+
+            eprintln!(
+                "Selected recording: {} by {} (from {} results)",
+                canonical_recording.title,
+                artist_name,
+                recordings.len()
+            );
+
+            let track_id = TrackId {
+                mbid: Some(canonical_recording.id.clone()),
+                spotify: None,
+                name: canonical_recording.title.clone(),
+                artist: artist_name,
+                artists,
+                canonical_keys: keys,
+                flags,
+                weight: 1.0,
+            };
+
+            if seen.insert(&track_id.canonical_keys) {
+                ids.push(track_id);
+            }
+        } else {
+            eprintln!("No recordings found for: {}", query);
+        }
+    }
+
+    Ok(ids)
+}
+
+// Representative recordings per artist/album seed; the seed's weight is split across them
+const ARTIST_SEED_TRACKS: usize = 5;
+const ALBUM_SEED_TRACKS: usize = 6;
+
+fn is_mbid(value: &str) -> bool {
+    value.len() == 36
+        && value.chars().enumerate().all(|(i, c)| {
+            if matches!(i, 8 | 13 | 18 | 23) {
+                c == '-'
+            } else {
+                c.is_ascii_hexdigit()
+            }
+        })
+}
+
+// Split "Name by Artist" / "Name - Artist" into its parts
+fn split_name_and_artist(query: &str) -> (String, Option<String>) {
+    for separator in [" by ", " - "] {
+        if let Some((name, artist)) = query.split_once(separator) {
+            return (name.trim().to_string(), Some(artist.trim().to_string()));
+        }
+    }
+    (query.trim().to_string(), None)
+}
+
+// GET a MusicBrainz web service path (e.g. "artist/<mbid>?inc=tags") as JSON
+async fn musicbrainz_get<T: DeserializeOwned>(
+    path: &str,
+    app_state: &AppState,
+) -> Result<T, Box<dyn std::error::Error + Send + Sync>> {
+    musicbrainz_lookup(path, app_state)
+        .await?
+        .ok_or_else(|| "MusicBrainz API error: 404 Not Found".into())
+}
+
+// Like musicbrainz_get, but None when MusicBrainz has no such entity
+async fn musicbrainz_lookup<T: DeserializeOwned>(
+    path: &str,
+    app_state: &AppState,
+) -> Result<Option<T>, Box<dyn std::error::Error + Send + Sync>> {
+    app_state.rate_limiter.wait().await;
+    let client = reqwest::Client::new();
+    let separator = if path.contains('?') { '&' } else { '?' };
+    let url = format!("https://musicbrainz.org/ws/2/{}{}fmt=json", path, separator);
+
+    let request = client.get(&url).header(
+        "User-Agent",
+        "NextTrack/0.1.0 (https://github.com/leebenson/nexttrack)",
+    );
+    let response = app_state
+        .upstreams
+        .send(Upstream::MusicBrainz, request)
+        .await?;
+
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(format!("MusicBrainz API error: {}", response.status()).into());
+    }
+
+    Ok(Some(response.json::<T>().await?))
+}
+
+// Turn a recording into a seed, with canonical keys and flags from a lookup
+async fn seed_from_recording(
+    mbid: &str,
+    name: &str,
+    artist: &str,
+    weight: f64,
+    app_state: &AppState,
+) -> TrackId {
+    let details = fetch_recording_details(mbid, app_state).await;
+    let artists = details.as_ref().map(artist_credits).unwrap_or_default();
+    TrackId {
+        mbid: Some(mbid.to_string()),
+        spotify: None,
+        name: name.to_string(),
+        artist: if artists.is_empty() {
+            artist.to_string()
+        } else {
+            display_credit(&artists)
+        },
+        artists,
+        canonical_keys: details.as_ref().map(canonical_keys).unwrap_or_default(),
+        flags: match &details {
+            Some(details) => lookup_flags(details, app_state).await,
+            None => RecordingFlags::default(),
+        },
+        weight,
+    }
+}
+
+// Expand an artist seed (MBID or name) into its most listened recordings
+async fn expand_artist_seed(
+    query: &str,
+    app_state: &AppState,
+) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+    let (artist_mbid, artist_name) = if is_mbid(query) {
+        (query.to_string(), None)
+    } else {
+        let search = format!(
+            "artist?query={}&limit=5",
+            urlencoding::encode(&format!("artist:\"{}\"", query))
+        );
+        let response: MusicBrainzArtistSearchResponse = musicbrainz_get(&search, app_state).await?;
+        // Prefer an exact name match over MusicBrainz's own ranking
+        let wanted = normalize_artist(query);
+        let best = response
+            .artists
+            .iter()
+            .max_by_key(|a| (normalize_artist(&a.name) == wanted, a.score.unwrap_or(0)))
+            .ok_or_else(|| format!("No artist found for: {}", query))?;
+        (best.id.clone(), Some(best.name.clone()))
+    };
+
+    eprintln!("Expanding artist seed: {} ({})", query, artist_mbid);
+
+    // ListenBrainz top recordings first (no key needed)
+    let client = reqwest::Client::new();
+    let url = format!(
+        "https://api.listenbrainz.org/1/popularity/top-recordings-for-artist/{}",
+        artist_mbid
+    );
+    let top_recordings = match app_state
+        .upstreams
+        .send(Upstream::ListenBrainz, client.get(&url))
+        .await
+    {
+        Ok(response) if response.status().is_success() => response
+            .json::<Vec<ListenBrainzTopRecording>>()
+            .await
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    let mut picked: Vec<(String, String, String)> = Vec::new(); // (mbid, name, artist)
+    let mut seen = HashSet::new();
+    for rec in top_recordings {
+        if picked.len() >= ARTIST_SEED_TRACKS {
+            break;
+        }
+        let Some(mbid) = rec.recording_mbid else {
+            continue;
+        };
+        // Top lists often contain several versions of the same song
+        if seen.insert(normalize_title(&rec.recording_name)) {
+            picked.push((mbid, rec.recording_name, rec.artist_name));
+        }
+    }
+
+    if !picked.is_empty() {
+        let weight = 1.0 / picked.len() as f64;
+        let mut seeds = Vec::new();
+        for (mbid, name, artist) in picked {
+            seeds.push(seed_from_recording(&mbid, &name, &artist, weight, app_state).await);
+        }
+        return Ok(seeds);
+    }
+
+    // Fall back to Last.fm top tracks, resolved through MusicBrainz
+    let artist_name = match artist_name {
+        Some(name) => name,
+        None => {
+            let artist: MusicBrainzArtistMatch =
+                musicbrainz_get(&format!("artist/{}", artist_mbid), app_state).await?;
+            artist.name
+        }
+    };
+    let lastfm_key = env::var("LASTFM_API_KEY")?;
+    let url = format!(
+        "https://ws.audioscrobbler.com/2.0/?method=artist.gettoptracks&artist={}&api_key={}&format=json&limit={}",
+        urlencoding::encode(&artist_name),
+        lastfm_key,
+        ARTIST_SEED_TRACKS * 2
+    );
+    let top_tracks = app_state
+        .upstreams
+        .send(Upstream::LastFm, client.get(&url))
+        .await?
+        .json::<LastFmTopTracks>()
+        .await?;
+    let queries: Vec<String> = top_tracks
+        .toptracks
+        .track
+        .into_iter()
+        .filter(|t| seen.insert(normalize_title(&t.name)))
+        .take(ARTIST_SEED_TRACKS)
+        .map(|t| format!("{} by {}", t.name, artist_name))
+        .collect();
+
+    let mut seeds = resolve_tracks_musicbrainz(queries, app_state).await?;
+    let weight = 1.0 / seeds.len().max(1) as f64;
+    for seed in &mut seeds {
+        seed.weight = weight;
+    }
+    Ok(seeds)
+}
+
+// Expand an album seed (release MBID, release-group MBID or name) into its tracklist
+async fn expand_album_seed(
+    query: &str,
+    app_state: &AppState,
+) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+    let release_path = |id: &str| format!("release/{}?inc=recordings+artist-credits", id);
+
+    let release: MusicBrainzReleaseLookup = if is_mbid(query) {
+        match musicbrainz_lookup(&release_path(query), app_state).await? {
+            Some(release) => release,
+            // Not a release - try it as a release group
+            None => {
+                let release_id = earliest_release_in_group(query, app_state).await?;
+                musicbrainz_get(&release_path(&release_id), app_state).await?
             }
         }
+    } else {
+        let (title, artist) = split_name_and_artist(query);
+        let mut lucene = format!("releasegroup:\"{}\"", title);
+        if let Some(artist) = artist {
+            lucene.push_str(&format!(" AND artist:\"{}\"", artist));
+        }
+        let search = format!(
+            "release-group?query={}&limit=5",
+            urlencoding::encode(&lucene)
+        );
+        let response: MusicBrainzReleaseGroupSearchResponse =
+            musicbrainz_get(&search, app_state).await?;
+        let group = response
+            .release_groups
+            .iter()
+            .find(|rg| rg.primary_type.as_deref() == Some("Album"))
+            .or_else(|| response.release_groups.first())
+            .ok_or_else(|| format!("No album found for: {}", query))?;
+        let release_id = earliest_release_in_group(&group.id, app_state).await?;
+        musicbrainz_get(&release_path(&release_id), app_state).await?
+    };
+
+    let recordings: Vec<MusicBrainzRecording> = release
+        .media
+        .into_iter()
+        .flat_map(|medium| medium.tracks)
+        .map(|track| track.recording)
+        .collect();
+    if recordings.is_empty() {
+        return Err(format!("Album has no tracks: {}", release.title).into());
+    }
+
+    eprintln!(
+        "Expanding album seed: {} ({} tracks)",
+        release.title,
+        recordings.len()
+    );
+
+    // Spread picks across the whole tracklist rather than taking the opening tracks,
+    // leaving out covers (and karaoke/tribute tracks) of other artists' songs
+    let count = recordings.len().min(ALBUM_SEED_TRACKS);
+    let mut seeds = Vec::new();
+    for i in 0..count {
+        let rec = &recordings[i * recordings.len() / count];
+        let artist = recording_artist(rec);
+        let seed = seed_from_recording(&rec.id, &rec.title, &artist, 1.0, app_state).await;
+        if seed.flags.is_imitation() {
+            eprintln!("Skipping album track {}: {:?}", seed.name, seed.flags);
+            continue;
+        }
+        seeds.push(seed);
+    }
+    if seeds.is_empty() {
+        return Err(format!("Album has only covers: {}", release.title).into());
+    }
+    let weight = 1.0 / seeds.len() as f64;
+    for seed in &mut seeds {
+        seed.weight = weight;
+    }
+    Ok(seeds)
+}
+
+// The original release of a release group: earliest official release
+async fn earliest_release_in_group(
+    release_group_id: &str,
+    app_state: &AppState,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let group: MusicBrainzReleaseGroupLookup = musicbrainz_get(
+        &format!("release-group/{}?inc=releases", release_group_id),
+        app_state,
+    )
+    .await?;
+    group
+        .releases
+        .iter()
+        .min_by_key(|release| {
+            (
+                release.status.as_deref() != Some("Official"),
+                release
+                    .date
+                    .clone()
+                    .filter(|date| !date.is_empty())
+                    .unwrap_or_else(|| "9999".to_string()),
+            )
+        })
+        .map(|release| release.id.clone())
+        .ok_or_else(|| format!("Release group has no releases: {}", release_group_id).into())
+}
+
+// Resolve every kind of seed in a request, de-duplicated per song
+async fn resolve_seeds(
+    req: &RecommendRequest,
+    app_state: &Arc<AppState>,
+    work: &WorkCache,
+) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut seeds = work.resolve(&req.tracks, app_state).await?;
+
+    for artist in &req.artists {
+        match expand_artist_seed(artist, app_state).await {
+            Ok(expanded) => seeds.extend(expanded),
+            Err(e) => eprintln!("Could not expand artist seed '{}': {}", artist, e),
+        }
+    }
+    for album in &req.albums {
+        match expand_album_seed(album, app_state).await {
+            Ok(expanded) => seeds.extend(expanded),
+            Err(e) => eprintln!("Could not expand album seed '{}': {}", album, e),
+        }
+    }
+
+    let mut seen = CanonicalIndex::new();
+    seeds.retain(|seed| seen.insert(&seed.canonical_keys));
+    Ok(seeds)
+}
+
+// Resolve tracks (legacy Spotify version - kept for migration)
+async fn resolve_tracks(
+    queries: Vec<String>,
+    token: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    let mut ids = Vec::new();
+    let mut seen = HashSet::new();
+    for query in queries {
+        // Check if query looks like a Spotify track ID (22 characters, alphanumeric)
+        if query.len() == 22 && query.chars().all(|c| c.is_alphanumeric()) {
+            // Verify it's a valid track ID by fetching track info
+            let track_url = format!("https://api.spotify.com/v1/tracks/{}", query);
+            let request = client
+                .get(&track_url)
+                .header(AUTHORIZATION, format!("Bearer {}", token));
+            let track_response = upstreams.send(Upstream::Spotify, request).await?;
+
+            if track_response.status().is_success() {
+                if seen.insert(query.clone()) {
+                    // For Spotify IDs, we need to fetch track info to get name/artist
+                    let track_info = track_response.json::<SpotifyTrack>().await?;
+                    ids.push(TrackId {
+                        spotify: Some(query),
+                        mbid: None,
+                        name: track_info.name,
+                        artist: track_info
+                            .artists
+                            .first()
+                            .map(|a| a.name.clone())
+                            .unwrap_or_else(|| "Unknown Artist".to_string()),
+                        artists: Vec::new(),
+                        canonical_keys: Vec::new(),
+                        flags: RecordingFlags::default(),
+                        weight: 1.0,
+                    });
+                }
+                continue;
+            }
+        }
+
+        // Otherwise, search for the track
+        let url = format!(
+            "https://api.spotify.com/v1/search?q={}&type=track&limit=10",
+            urlencoding::encode(&query)
+        );
+        let request = client
+            .get(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", token));
+        let res = upstreams
+            .send(Upstream::Spotify, request)
+            .await?
+            .json::<SpotifySearchResponse>()
+            .await?;
+        if res.tracks.items.is_empty() {
+            continue;
+        }
+        let lowercase_query = query.to_lowercase();
+        let best_match = res
+            .tracks
+            .items
+            .into_iter()
+            .min_by_key(|t| levenshtein(&t.name.to_lowercase(), &lowercase_query))
+            .unwrap();
+        if seen.insert(best_match.id.clone()) {
+            ids.push(TrackId {
+                spotify: Some(best_match.id),
+                mbid: None,
+                name: best_match.name.clone(),
+                artist: best_match
+                    .artists
+                    .first()
+                    .map(|a| a.name.clone())
+                    .unwrap_or_else(|| "Unknown Artist".to_string()),
+                artists: Vec::new(),
+                canonical_keys: Vec::new(),
+                flags: RecordingFlags::default(),
+                weight: 1.0,
+            });
+        }
+    }
+    Ok(ids)
+}
+
+// Fetch Spotify features
+async fn fetch_spotify_features(
+    track_id: &str,
+    token: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<SpotifyFeatures, Box<dyn std::error::Error + Send + Sync>> {
+    let client = reqwest::Client::new();
+    let url = format!("https://api.spotify.com/v1/audio-features/{}", track_id);
+    let request = client
+        .get(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", token));
+    let res = upstreams
+        .send(Upstream::Spotify, request)
+        .await?
+        .json::<SpotifyFeatures>()
+        .await?;
+    Ok(res)
+}
+
+// Fetch Genius lyrics. Genius is optional: without a key, callers fall back to
+// neutral sentiment.
+async fn fetch_genius_lyrics(
+    query: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    let genius_key = env::var("GENIUS_API_KEY").map_err(|_| "GENIUS_API_KEY not set")?;
+    let client = reqwest::Client::new();
+    // First search
+    let search_url = format!(
+        "https://api.genius.com/search?q={}",
+        urlencoding::encode(query)
+    );
+    let request = client
+        .get(&search_url)
+        .header(AUTHORIZATION, format!("Bearer {}", genius_key));
+    let res = upstreams
+        .send(Upstream::Genius, request)
+        .await?
+        .json::<GeniusSearchResponse>()
+        .await?;
+    if res.response.hits.is_empty() {
+        return Ok("".to_string());
+    }
+    let path = res.response.hits[0].result.path.clone();
+    let lyrics_url = format!("https://genius.com{}", path);
+    let text = upstreams
+        .send(Upstream::Genius, client.get(&lyrics_url))
+        .await?
+        .text()
+        .await?;
+    let document = Html::parse_document(&text);
+    let selector = Selector::parse(r#"div[data-lyrics-container="true"]"#).unwrap();
+    let lyrics = document
+        .select(&selector)
+        .flat_map(|el| el.text())
+        .collect::<String>();
+    Ok(lyrics)
+}
+
+// Tags
+// How much each source counts towards a track's tag vector
+const TAG_WEIGHT_RECORDING_GENRES: f64 = 1.0;
+const TAG_WEIGHT_RECORDING_TAGS: f64 = 0.7;
+const TAG_WEIGHT_LASTFM_TRACK: f64 = 0.8;
+const TAG_WEIGHT_ARTIST: f64 = 0.5;
+const TAG_MAX_PER_SOURCE: usize = 15;
+// Recordings with this many tags/genres of their own skip the Last.fm track lookup
+const TAG_MIN_RECORDING: usize = 3;
+const TAG_CACHE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+const TAG_CACHE_CAPACITY: usize = 20_000;
+
+// Tags that say nothing about how a track sounds
+const TAG_BLOCKLIST: [&str; 8] = [
+    "seen live",
+    "favorites",
+    "favourite",
+    "favorite songs",
+    "awesome",
+    "love",
+    "beautiful",
+    "my music",
+];
+
+fn normalize_tag(tag: &str) -> String {
+    tag.to_lowercase()
+        .replace(['_', '-'], " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Add one source's tags to a vector, scaled so its top tag counts `weight`
+fn add_tags(vector: &mut BTreeMap<String, f64>, tags: &[TagCount], weight: f64) {
+    let max = tags.iter().map(|tag| tag.count).max().unwrap_or(0);
+    if max <= 0 {
+        return;
+    }
+    for tag in tags.iter().take(TAG_MAX_PER_SOURCE) {
+        let name = normalize_tag(&tag.name);
+        if name.is_empty() || TAG_BLOCKLIST.contains(&name.as_str()) || tag.count <= 0 {
+            continue;
+        }
+        *vector.entry(name).or_insert(0.0) += weight * tag.count as f64 / max as f64;
+    }
+}
+
+async fn cached_tags(app_state: &AppState, key: &str) -> Option<Vec<TagCount>> {
+    app_state.tag_cache.lock().await.get(key)
+}
+
+async fn store_tags(app_state: &AppState, key: String, tags: &[TagCount]) {
+    app_state.tag_cache.lock().await.insert(key, tags.to_vec());
+}
+
+// Last.fm top tags for a track (artist and name) or an artist (name only)
+async fn fetch_lastfm_tags(
+    artist: &str,
+    track: Option<&str>,
+    app_state: &AppState,
+) -> Vec<TagCount> {
+    let key = match track {
+        Some(track) => format!(
+            "lastfm-track:{}|{}",
+            normalize_artist(artist),
+            normalize_title(track)
+        ),
+        None => format!("lastfm-artist:{}", normalize_artist(artist)),
+    };
+    if let Some(tags) = cached_tags(app_state, &key).await {
+        return tags;
+    }
+    let Ok(lastfm_key) = env::var("LASTFM_API_KEY") else {
+        return Vec::new();
+    };
+    let url = match track {
+        Some(track) => format!(
+            "https://ws.audioscrobbler.com/2.0/?method=track.gettoptags&artist={}&track={}&api_key={}&format=json&autocorrect=1",
+            urlencoding::encode(artist),
+            urlencoding::encode(track),
+            lastfm_key
+        ),
+        None => format!(
+            "https://ws.audioscrobbler.com/2.0/?method=artist.gettoptags&artist={}&api_key={}&format=json&autocorrect=1",
+            urlencoding::encode(artist),
+            lastfm_key
+        ),
+    };
+    let request = reqwest::Client::new().get(&url);
+    let tags: Vec<TagCount> = match app_state.upstreams.send(Upstream::LastFm, request).await {
+        Ok(response) if response.status().is_success() => response
+            .json::<LastFmTopTags>()
+            .await
+            .map(|res| {
+                res.toptags
+                    .tag
+                    .into_iter()
+                    .map(|tag| TagCount {
+                        name: tag.name,
+                        count: tag.count,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Ok(response) => {
+            eprintln!("Last.fm tags error: {}", response.status());
+            return Vec::new();
+        }
+        Err(e) => {
+            eprintln!("Last.fm tags request failed: {}", e);
+            return Vec::new();
+        }
+    };
+    store_tags(app_state, key, &tags).await;
+    tags
+}
+
+// MusicBrainz genres and tags for an artist (cached, rate limited)
+async fn fetch_musicbrainz_artist_tags(mbid: &str, app_state: &AppState) -> Vec<TagCount> {
+    let key = format!("mb-artist:{}", mbid);
+    if let Some(tags) = cached_tags(app_state, &key).await {
+        return tags;
+    }
+    let path = format!("artist/{}?inc=tags+genres", mbid);
+    let tags = match musicbrainz_get::<MusicBrainzArtistTags>(&path, app_state).await {
+        Ok(artist) => {
+            // Genres are curated, so they outrank free-form tags with the same votes
+            let mut tags = tag_counts(&artist.genres);
+            for tag in tag_counts(&artist.tags) {
+                if !tags.iter().any(|t| t.name == tag.name) {
+                    tags.push(tag);
+                }
+            }
+            tags
+        }
+        Err(e) => {
+            eprintln!("Failed to fetch artist tags for {}: {}", mbid, e);
+            return Vec::new();
+        }
+    };
+    store_tags(app_state, key, &tags).await;
+    tags
+}
+
+// Weighted tag vector for a track from its recording tags (already in `metadata`),
+// Last.fm track tags (only for sparsely tagged recordings) and its primary artist's
+// tags from Last.fm. The rate-limited MusicBrainz artist lookup is a last resort,
+// for tracks that would otherwise have no tags at all.
+async fn fetch_tag_vector(
+    name: &str,
+    metadata: &TrackMetadata,
+    artists: &[ArtistCredit],
+    app_state: &AppState,
+) -> BTreeMap<String, f64> {
+    let mut vector = BTreeMap::new();
+    add_tags(&mut vector, &metadata.genres, TAG_WEIGHT_RECORDING_GENRES);
+    add_tags(&mut vector, &metadata.tags, TAG_WEIGHT_RECORDING_TAGS);
+
+    let Some(primary) = artists.first() else {
+        return vector;
+    };
+    if metadata.genres.len() + metadata.tags.len() < TAG_MIN_RECORDING {
+        let display = display_credit(artists);
+        add_tags(
+            &mut vector,
+            &fetch_lastfm_tags(&display, Some(name), app_state).await,
+            TAG_WEIGHT_LASTFM_TRACK,
+        );
+    }
+
+    let mut artist_tags = fetch_lastfm_tags(&primary.name, None, app_state).await;
+    if artist_tags.is_empty() && vector.is_empty() {
+        if let Some(mbid) = &primary.mbid {
+            artist_tags = fetch_musicbrainz_artist_tags(mbid, app_state).await;
+        }
+    }
+    add_tags(&mut vector, &artist_tags, TAG_WEIGHT_ARTIST);
+    vector
+}
+
+// Fetch album art from MusicBrainz Cover Art Archive
+async fn fetch_album_art(mbid: &str, app_state: &AppState) -> Option<String> {
+    // First get recording details to find a release
+    let recording = fetch_recording_details(mbid, app_state).await?;
+
+    // Prefer the original release's artwork over compilations
+    let release_id = &primary_release(&recording)?.id;
+    fetch_release_cover_art(release_id, &app_state.upstreams).await
+}
+
+// Cover art for a release (the Cover Art Archive isn't rate limited)
+async fn fetch_release_cover_art(release_id: &str, upstreams: &UpstreamMonitor) -> Option<String> {
+    let client = reqwest::Client::new();
+    let cover_url = format!("https://coverartarchive.org/release/{}", release_id);
+
+    let request = client.get(&cover_url);
+    if let Ok(cover_resp) = upstreams.send(Upstream::CoverArtArchive, request).await {
+        if let Ok(cover_data) = cover_resp.json::<CoverArtArchiveResponse>().await {
+            // Return the large thumbnail if available, otherwise the full image
+            if let Some(first_image) = cover_data.images.first() {
+                return first_image
+                    .thumbnails
+                    .large
+                    .clone()
+                    .or_else(|| first_image.thumbnails.small.clone())
+                    .or_else(|| Some(first_image.image.clone()));
+            }
+        }
+    }
+
+    None
+}
+
+// Aggregate features using MusicBrainz/AcousticBrainz
+async fn aggregate_features_musicbrainz(
+    track: &TrackId,
+    app_state: &AppState,
+) -> Result<Track, Box<dyn std::error::Error + Send + Sync>> {
+    // AcousticBrainz features if we have an MBID (usually prefetched in bulk).
+    // Many tracks have no audio analysis, which leaves the features empty.
+    let mut features: BTreeMap<String, f64> = match &track.mbid {
+        Some(mbid) => fetch_acousticbrainz_features(mbid, app_state)
+            .await
+            .into_iter()
+            .collect(),
+        None => BTreeMap::new(),
+    };
+
+    // Get lyrics sentiment from Genius
+    let query = format!("{} {}", track.name, track.artist);
+    let sentiment = match fetch_genius_lyrics(&query, &app_state.upstreams).await {
+        Ok(lyrics) => vader_analyse(&lyrics),
+        Err(_) => 0.5, // Neutral sentiment as fallback
+    };
+    features.insert("sentiment".to_string(), sentiment);
+    let harmonic_key = harmonic_key(&features);
+
+    // Real popularity from ListenBrainz if we have an MBID (usually prefetched in bulk)
+    let counts = match &track.mbid {
+        Some(mbid) => app_state
+            .popularity
+            .counts(std::slice::from_ref(mbid))
+            .await
+            .remove(mbid),
+        None => None,
+    };
+    let popularity = match counts {
+        Some(counts) => app_state.popularity.score(counts.listens).await,
+        None => 0,
+    };
+
+    // Fetch album art and release metadata if we have an MBID
+    let (album_art, metadata, artists) = if let Some(mbid) = &track.mbid {
+        let details = fetch_recording_details(mbid, app_state).await;
+        let metadata = details.as_ref().map(track_metadata).unwrap_or_default();
+        let artists = if track.artists.is_empty() {
+            details.as_ref().map(artist_credits).unwrap_or_default()
+        } else {
+            track.artists.clone()
+        };
+        (fetch_album_art(mbid, app_state).await, metadata, artists)
+    } else {
+        (None, TrackMetadata::default(), track.artists.clone())
+    };
+    let tag_vector = fetch_tag_vector(&track.name, &metadata, &artists, app_state).await;
+
+    Ok(Track {
+        id: track
+            .mbid
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", track.name, track.artist)),
+        name: track.name.clone(),
+        artist: if artists.is_empty() {
+            track.artist.clone()
+        } else {
+            display_credit(&artists)
+        },
+        artists,
+        features,
+        popularity,
+        listen_count: counts.map(|counts| counts.listens),
+        user_count: counts.map(|counts| counts.users),
+        album_art,
+        flags: track.flags.clone(),
+        metadata,
+        tag_vector,
+        harmonic_key,
+        weight: track.weight,
+    })
+}
+
+// Aggregate features (legacy Spotify version)
+async fn aggregate_features(
+    track_id: &str,
+    token: &str,
+    upstreams: &UpstreamMonitor,
+) -> Result<Track, Box<dyn std::error::Error + Send + Sync>> {
+    let spotify_features = fetch_spotify_features(track_id, token, upstreams).await?;
+    // Fetch track info to get name and artist for Genius query
+    let client = reqwest::Client::new();
+    let track_url = format!("https://api.spotify.com/v1/tracks/{}", track_id);
+    let request = client
+        .get(&track_url)
+        .header(AUTHORIZATION, format!("Bearer {}", token));
+    let track_res = upstreams
+        .send(Upstream::Spotify, request)
+        .await?
+        .json::<SpotifyTrack>()
+        .await?;
+    let query = format!("{} {}", track_res.name, track_res.artists[0].name);
+    let sentiment = match fetch_genius_lyrics(&query, upstreams).await {
+        Ok(lyrics) => vader_analyse(&lyrics),
+        Err(_) => 0.5, // Neutral sentiment as fallback
+    };
+    let mut features = BTreeMap::new();
+    features.insert("energy".to_string(), spotify_features.energy);
+    features.insert("valence".to_string(), spotify_features.valence);
+    features.insert("tempo".to_string(), spotify_features.tempo);
+    features.insert("sentiment".to_string(), sentiment);
+    Ok(Track {
+        id: track_id.to_string(),
+        name: track_res.name,
+        artist: track_res.artists[0].name.clone(),
+        artists: Vec::new(),
+        features,
+        popularity: track_res.popularity,
+        listen_count: None,
+        user_count: None,
+        album_art: None, // Spotify version doesn't support album art yet
+        flags: RecordingFlags::default(),
+        metadata: TrackMetadata::default(),
+        tag_vector: BTreeMap::new(),
+        harmonic_key: None,
+        weight: 1.0,
+    })
+}
+
+// Generate candidates using Last.fm
+async fn generate_candidates(
+    seeds: Vec<TrackId>,
+    inputs: &Vec<Track>,
+    prefs: &Preferences,
+    token: &str,
+    seed: u64,
+    upstreams: &UpstreamMonitor,
+) -> Result<Vec<Track>, Box<dyn std::error::Error + Send + Sync>> {
+    if seeds.is_empty() {
+        return Err("No seed tracks provided".into());
+    }
+
+    let lastfm_key = env::var("LASTFM_API_KEY").map_err(|_| "LASTFM_API_KEY not set")?;
+    let client = reqwest::Client::new();
+    let mut candidate_queries = Vec::new();
+    for input in inputs {
+        let url = format!(
+            "https://ws.audioscrobbler.com/2.0/?method=track.getsimilar&track={}&artist={}&api_key={}&format=json&limit=50",
+            urlencoding::encode(&input.name),
+            urlencoding::encode(&input.artist),
+            lastfm_key
+        );
+        let res = upstreams
+            .send(Upstream::LastFm, client.get(&url))
+            .await?
+            .json::<LastFmSimilar>()
+            .await?;
+        for sim_track in res.similartracks.track {
+            if sim_track.match_score > 0.1 {
+                // threshold to filter low matches
+                let query = format!("{} by {}", sim_track.name, sim_track.artist.name);
+                candidate_queries.push(query);
+            }
+        }
+    }
+
+    let candidate_ids = resolve_tracks(candidate_queries, token, upstreams).await?;
+
+    let seed_ids: HashSet<String> = seeds.iter().filter_map(|s| s.spotify.clone()).collect();
+
+    let mut candidates = Vec::new();
+    for id in candidate_ids {
+        if let Some(spotify_id) = &id.spotify {
+            if seed_ids.contains(spotify_id) {
+                continue;
+            }
+        }
+        if let Some(spotify_id) = &id.spotify {
+            if let Ok(track) = aggregate_features(spotify_id, token, upstreams).await {
+                let obscurity_score = 1.0 - (track.popularity as f64 / 100.0);
+                if obscurity_score >= prefs.obscurity {
+                    candidates.push(track);
+                }
+            }
+        }
+    }
+    // Shuffle and truncate
+    candidates.shuffle(&mut StdRng::seed_from_u64(seed));
+    candidates.truncate(50);
+    Ok(candidates)
+}
+
+// Scoring trait
+trait ScoringFunction {
+    fn score(&self, inputs: &[Track], candidate: &Track) -> f64;
+}
+
+struct AudioSimilarityScorer {
+    weights: HashMap<String, f64>,
+}
+
+impl ScoringFunction for AudioSimilarityScorer {
+    fn score(&self, inputs: &[Track], candidate: &Track) -> f64 {
+        // Average each (non-harmonic) feature over the seeds that have it, weighted by
+        // each seed's share. Both vectors follow the same (sorted) keys, so their components line up.
+        let mut sums: BTreeMap<&str, (f64, f64)> = BTreeMap::new();
+        for input in inputs {
+            for (key, value) in input
+                .features
+                .iter()
+                .filter(|(key, _)| !HARMONIC_FEATURES.contains(&key.as_str()))
+            {
+                let (sum, weight) = sums.entry(key.as_str()).or_insert((0.0, 0.0));
+                *sum += input.weight * value;
+                *weight += input.weight;
+            }
+        }
+        let (a_vec, b_vec): (Vec<f64>, Vec<f64>) = sums
+            .into_iter()
+            .map(|(key, (sum, weight))| {
+                (sum / weight, *candidate.features.get(key).unwrap_or(&0.0))
+            })
+            .unzip();
+        cosine_similarity(&a_vec, &b_vec, &self.weights)
+    }
+}
+
+// Other scorers
+struct ObscurityScorer;
+
+impl ScoringFunction for ObscurityScorer {
+    fn score(&self, _inputs: &[Track], candidate: &Track) -> f64 {
+        1.0 - (candidate.popularity as f64 / 100.0)
+    }
+}
+
+// Genre affinity: cosine between the seeds' combined tag vector and the candidate's
+struct TagSimilarityScorer;
+
+impl ScoringFunction for TagSimilarityScorer {
+    fn score(&self, inputs: &[Track], candidate: &Track) -> f64 {
+        let mut profile: BTreeMap<&str, f64> = BTreeMap::new();
+        for input in inputs {
+            for (tag, value) in &input.tag_vector {
+                *profile.entry(tag.as_str()).or_insert(0.0) += input.weight * value;
+            }
+        }
+        let dot: f64 = candidate
+            .tag_vector
+            .iter()
+            .filter_map(|(tag, value)| profile.get(tag.as_str()).map(|p| p * value))
+            .sum();
+        let mag_a = profile.values().map(|x| x.powi(2)).sum::<f64>().sqrt();
+        let mag_b = candidate
+            .tag_vector
+            .values()
+            .map(|x| x.powi(2))
+            .sum::<f64>()
+            .sqrt();
+        if mag_a == 0.0 || mag_b == 0.0 {
+            return 0.0;
+        }
+        dot / (mag_a * mag_b)
+    }
+}
+
+// Key compatibility with the seeds on the Camelot wheel, neutral when a key is unknown
+struct HarmonicScorer;
+
+impl ScoringFunction for HarmonicScorer {
+    fn score(&self, inputs: &[Track], candidate: &Track) -> f64 {
+        let Some(key) = camelot_of(candidate) else {
+            return 0.5;
+        };
+        let (total, weight) = inputs
+            .iter()
+            .filter_map(|input| Some((camelot_of(input)?, input.weight)))
+            .fold((0.0, 0.0), |(total, weight), (seed_key, w)| {
+                (total + w * key_compatibility(seed_key, key), weight + w)
+            });
+        if weight == 0.0 {
+            0.5
+        } else {
+            total / weight
+        }
+    }
+}
+
+// Reorder results so each track mixes into the next: start from the track that
+// best follows the last seed, then repeatedly pick the most compatible remaining
+// track, preferring the higher ranked one on ties. Tracks without a key go last.
+fn sequence_harmonically(last_seed: Option<&Track>, tracks: Vec<Track>) -> Vec<Track> {
+    let (mut remaining, unkeyed): (Vec<Track>, Vec<Track>) = tracks
+        .into_iter()
+        .partition(|track| camelot_of(track).is_some());
+    let mut current = last_seed.and_then(camelot_of);
+    let mut sequenced = Vec::new();
+    while !remaining.is_empty() {
+        let next = match current {
+            Some(key) => remaining
+                .iter()
+                .enumerate()
+                .max_by(|(i, a), (j, b)| {
+                    let a = key_compatibility(key, camelot_of(a).unwrap());
+                    let b = key_compatibility(key, camelot_of(b).unwrap());
+                    a.partial_cmp(&b).unwrap().then(j.cmp(i))
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+            None => 0,
+        };
+        let track = remaining.remove(next);
+        current = camelot_of(&track);
+        sequenced.push(track);
+    }
+    sequenced.extend(unkeyed);
+    sequenced
+}
+
+// Share of similarity taken by tags when the seeds have audio features
+const TAG_SIMILARITY_WEIGHT: f64 = 0.4;
+
+fn has_audio_features(track: &Track) -> bool {
+    // Sentiment comes from lyrics, so it is present even without AcousticBrainz data
+    track.features.keys().any(|key| key != "sentiment")
+}
+
+// Audio similarity assumed for a candidate without features when the seeds can't
+// give a better guess
+const IMPUTED_AUDIO_SIMILARITY: f64 = 0.5;
+
+// What a candidate without features would typically score on audio: how similar
+// each seed with features sounds to the other seeds, on average
+fn imputed_audio_similarity(inputs: &[Track], audio_scorer: &AudioSimilarityScorer) -> f64 {
+    let with_features: Vec<&Track> = inputs.iter().filter(|t| has_audio_features(t)).collect();
+    if with_features.len() < 2 {
+        return IMPUTED_AUDIO_SIMILARITY;
+    }
+    let total: f64 = with_features
+        .iter()
+        .enumerate()
+        .map(|(i, seed)| {
+            let others: Vec<Track> = with_features
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, t)| (*t).clone())
+                .collect();
+            audio_scorer.score(&others, seed)
+        })
+        .sum();
+    total / with_features.len() as f64
+}
+
+// Audio and tag similarity blended the same way for every candidate, with the audio
+// term imputed for candidates without features. Tags alone when no seed has features.
+fn content_similarity(inputs: &[Track], candidate: &Track) -> f64 {
+    let tag_score = TagSimilarityScorer.score(inputs, candidate);
+    if !inputs.iter().any(has_audio_features) {
+        return tag_score;
+    }
+    let audio_scorer = AudioSimilarityScorer {
+        weights: hashmap! {"default".to_string() => 1.0},
+    };
+    let audio_score = if has_audio_features(candidate) {
+        audio_scorer.score(inputs, candidate)
+    } else {
+        imputed_audio_similarity(inputs, &audio_scorer)
+    };
+    (1.0 - TAG_SIMILARITY_WEIGHT) * audio_score + TAG_SIMILARITY_WEIGHT * tag_score
+}
+
+// Preference-weighted score of a candidate against the seeds
+fn score_candidate(inputs: &[Track], candidate: &Track, preferences: &Preferences) -> f64 {
+    // Low obscurity (0.0) = prefer popular tracks, high (1.0) = prefer obscure tracks
+    let obscurity_weight = preferences.obscurity;
+    let similarity_weight = 1.0 - obscurity_weight;
+    let score = similarity_weight * content_similarity(inputs, candidate)
+        + obscurity_weight * ObscurityScorer.score(inputs, candidate);
+    let harmonic_weight = preferences.harmonic.clamp(0.0, 1.0);
+    (1.0 - harmonic_weight) * score + harmonic_weight * HarmonicScorer.score(inputs, candidate)
+}
+
+// Orders tracks with equal scores: a hash (FNV-1a) of the request seed and track id,
+// so ties are broken the same way for the same seed whatever order candidates arrived in
+fn tie_breaker(seed: u64, id: &str) -> u64 {
+    seed.to_le_bytes()
+        .iter()
+        .chain(id.as_bytes())
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+// Most results any one artist can take when artist diversity is on
+const MAX_TRACKS_PER_ARTIST: usize = 2;
+
+// Take the best `limit` tracks from a list sorted by score. With artist diversity
+// on, a collaboration counts against every credited artist.
+fn select_top(scored: Vec<(Track, f64)>, limit: usize, preferences: &Preferences) -> Vec<Track> {
+    let mut per_artist: HashMap<String, usize> = HashMap::new();
+    let mut top = Vec::new();
+    for (track, _) in scored {
+        if top.len() >= limit {
+            break;
+        }
+        if preferences.artist_diversity {
+            let artists = artist_identities(&track);
+            if artists
+                .iter()
+                .any(|artist| per_artist.get(artist).copied().unwrap_or(0) >= MAX_TRACKS_PER_ARTIST)
+            {
+                continue;
+            }
+            for artist in artists {
+                *per_artist.entry(artist).or_insert(0) += 1;
+            }
+        }
+        top.push(track);
+    }
+    top
+}
+
+// Streaming runs: every event is buffered so a client can reconnect and replay
+// what it missed. Event ids are "<run_id>:<seq>", with seq starting at 1.
+// A run nobody is watching is cancelled after a grace period, so an abandoned
+// run doesn't keep the MusicBrainz rate limiter busy for everyone else.
+const RUN_RETENTION: Duration = Duration::from_secs(10 * 60); // After the run finishes
+const RUN_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const RUN_DISCONNECT_GRACE: Duration = Duration::from_secs(30); // Time to reconnect
+const SSE_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+struct RecommendationRun {
+    id: String,
+    log: TokioMutex<RunLog>,
+    // Number of buffered events; bumped (or touched on finish) to wake streams
+    latest: tokio::sync::watch::Sender<u64>,
+    cancel: CancellationToken,
+    steering: TokioMutex<Option<Steering>>, // Set once the seeds are resolved
+    work: Arc<WorkCache>,
+    disconnects: AtomicU64, // Bumped per disconnect; only the latest grace timer may cancel
+    result: CollectorSink,  // The outcome, kept typed for batch responses
+}
+
+#[derive(Default)]
+struct RunLog {
+    events: Vec<String>, // Serialized RecommendationEvents
+    finished_at: Option<Instant>,
+}
+
+impl RecommendationRun {
+    async fn emit(&self, event: RecommendationEvent) {
+        self.result.record(&event);
+        let data = match serde_json::to_string(&event) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("Failed to serialize event for run {}: {}", self.id, e);
+                return;
+            }
+        };
+        let mut log = self.log.lock().await;
+        log.events.push(data);
+        self.latest.send_replace(log.events.len() as u64);
+    }
+
+    async fn finish(&self) {
+        self.log.lock().await.finished_at = Some(Instant::now());
+        self.latest.send_modify(|_| {});
+    }
+
+    async fn is_finished(&self) -> bool {
+        self.log.lock().await.finished_at.is_some()
+    }
+
+    async fn wait_until_finished(&self) {
+        let mut latest = self.latest.subscribe();
+        while !self.is_finished().await {
+            if latest.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // The final recommendations, or why there are none
+    fn outcome(&self) -> Result<Vec<Track>, String> {
+        match self.result.outcome.lock().unwrap().as_ref() {
+            Some(Ok(page)) => Ok(page.tracks.clone()),
+            Some(Err(message)) => Err(message.clone()),
+            None => Err("The run did not finish".to_string()),
+        }
+    }
+
+    // Cancel the run unless someone reconnected within the grace period. A client
+    // that reconnects and drops again restarts the grace period from that disconnect.
+    async fn cancel_if_abandoned(&self) {
+        let disconnect = self.disconnects.fetch_add(1, Ordering::SeqCst) + 1;
+        tokio::time::sleep(RUN_DISCONNECT_GRACE).await;
+        if self.disconnects.load(Ordering::SeqCst) != disconnect {
+            return; // A later disconnect has its own timer
+        }
+        if self.latest.receiver_count() == 0 && !self.is_finished().await {
+            eprintln!("Cancelling run {}: client disconnected", self.id);
+            self.cancel.cancel();
+        }
+    }
+
+    // Events after `after`, and whether the run has finished
+    async fn events_after(&self, after: u64) -> (Vec<(u64, String)>, bool) {
+        let log = self.log.lock().await;
+        let events = log
+            .events
+            .iter()
+            .enumerate()
+            .skip(after as usize)
+            .map(|(i, data)| (i as u64 + 1, data.clone()))
+            .collect();
+        (events, log.finished_at.is_some())
+    }
+}
+
+struct RunRegistry {
+    runs: TokioMutex<HashMap<String, Arc<RecommendationRun>>>,
+}
+
+impl RunRegistry {
+    fn new() -> Self {
+        RunRegistry {
+            runs: TokioMutex::new(HashMap::new()),
+        }
+    }
+
+    async fn create(&self) -> Arc<RecommendationRun> {
+        self.create_sharing(Arc::default()).await
+    }
+
+    async fn create_with_id(&self, id: String) -> Arc<RecommendationRun> {
+        self.insert(id, Arc::default()).await
+    }
+
+    // A run that shares resolution and enrichment work with others (a batch)
+    async fn create_sharing(&self, work: Arc<WorkCache>) -> Arc<RecommendationRun> {
+        let id = format!("{:016x}", rand::Rng::gen::<u64>(&mut thread_rng()));
+        self.insert(id, work).await
+    }
+
+    async fn insert(&self, id: String, work: Arc<WorkCache>) -> Arc<RecommendationRun> {
+        let run = Arc::new(RecommendationRun {
+            id,
+            log: TokioMutex::new(RunLog::default()),
+            latest: tokio::sync::watch::channel(0).0,
+            cancel: CancellationToken::new(),
+            steering: TokioMutex::new(None),
+            work,
+            disconnects: AtomicU64::new(0),
+            result: CollectorSink::default(),
+        });
+        self.runs.lock().await.insert(run.id.clone(), run.clone());
+        run
+    }
+
+    // Drop runs that finished long enough ago. Run logs are checked on a snapshot,
+    // so the registry isn't held while waiting on them.
+    async fn sweep(&self) {
+        let runs: Vec<Arc<RecommendationRun>> = self.runs.lock().await.values().cloned().collect();
+        let mut expired = Vec::new();
+        for run in runs {
+            let finished_at = run.log.lock().await.finished_at;
+            if finished_at.is_some_and(|at| at.elapsed() > RUN_RETENTION) {
+                expired.push(run.id.clone());
+            }
+        }
+        if !expired.is_empty() {
+            let mut runs = self.runs.lock().await;
+            for id in expired {
+                runs.remove(&id);
+            }
+        }
+    }
+
+    async fn get(&self, id: &str) -> Option<Arc<RecommendationRun>> {
+        self.runs.lock().await.get(id).cloned()
+    }
+}
+
+// Work shared between runs: MusicBrainz resolutions per query and enriched tracks
+// per recording. Each run has its own, except the runs of a batch, which share one
+// so overlapping seed sets resolve and enrich each track once. Concurrent requests
+// for the same key wait on a single lookup; failures are dropped so they can be retried.
+type SharedWork<T> = Shared<BoxFuture<'static, Result<T, String>>>;
+
+#[derive(Default)]
+struct WorkCache {
+    resolved: TokioMutex<HashMap<String, SharedWork<Vec<TrackId>>>>,
+    enriched: TokioMutex<HashMap<String, SharedWork<Track>>>,
+}
+
+impl WorkCache {
+    async fn shared<T: Clone + Send + Sync + 'static>(
+        map: &TokioMutex<HashMap<String, SharedWork<T>>>,
+        key: String,
+        work: impl std::future::Future<Output = Result<T, String>> + Send + 'static,
+    ) -> Result<T, String> {
+        let lookup = map
+            .lock()
+            .await
+            .entry(key.clone())
+            .or_insert_with(|| work.boxed().shared())
+            .clone();
+        let result = lookup.await;
+        if result.is_err() {
+            map.lock().await.remove(&key);
+        }
+        result
+    }
+
+    // Resolve search queries, each at most once, in order
+    async fn resolve(
+        &self,
+        queries: &[String],
+        app_state: &Arc<AppState>,
+    ) -> Result<Vec<TrackId>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut ids = Vec::new();
+        for query in queries {
+            let state = app_state.clone();
+            let owned = query.clone();
+            let resolved = Self::shared(&self.resolved, query.clone(), async move {
+                resolve_tracks_musicbrainz(vec![owned], &state)
+                    .await
+                    .map_err(|e| e.to_string())
+            })
+            .await?;
+            ids.extend(resolved);
+        }
+        Ok(ids)
+    }
+
+    async fn enrich(
+        &self,
+        id: &TrackId,
+        app_state: &Arc<AppState>,
+    ) -> Result<Track, Box<dyn std::error::Error + Send + Sync>> {
+        let key = id
+            .mbid
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", id.name, id.artist));
+        let state = app_state.clone();
+        let owned = id.clone();
+        let mut track = Self::shared(&self.enriched, key, async move {
+            aggregate_features_musicbrainz(&owned, &state)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await?;
+        track.weight = id.weight; // Belongs to the seed, not the recording
+        Ok(track)
+    }
+}
+
+// Parse a Last-Event-ID header: "<run_id>:<seq>"
+fn last_event_id(headers: &HeaderMap) -> Option<(String, u64)> {
+    let value = headers.get("last-event-id")?.to_str().ok()?;
+    let (run_id, seq) = value.trim().rsplit_once(':')?;
+    Some((run_id.to_string(), seq.parse().ok()?))
+}
+
+// SSE stream of a run's events after `after`, ending once the run has finished
+// and everything has been sent. Keep-alive comments stop proxies closing idle streams.
+fn stream_run(run: Arc<RecommendationRun>, after: u64) -> impl IntoResponse {
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Event, axum::Error>>(10);
+    let run_id = run.id.clone();
+
+    tokio::spawn(async move {
+        if forward_run(&run, after, &tx, |data| Ok(Event::default().data(data))).await {
+            run.cancel_if_abandoned().await;
+        }
+    });
+
+    ([("x-run-id", run_id)], sse_response(rx))
+}
+
+type SseSender = tokio::sync::mpsc::Sender<Result<Event, axum::Error>>;
+
+// Send a run's events after `after` until it finishes, each event built from its
+// serialized data by `to_event`. Returns true if the client went away first.
+async fn forward_run(
+    run: &RecommendationRun,
+    after: u64,
+    tx: &SseSender,
+    to_event: impl Fn(String) -> Result<Event, axum::Error>,
+) -> bool {
+    let mut latest = run.latest.subscribe();
+    let mut sent = after;
+    loop {
+        latest.borrow_and_update();
+        let (events, finished) = run.events_after(sent).await;
+        for (seq, data) in events {
+            sent = seq;
+            let event = match to_event(data) {
+                Ok(event) => event.id(format!("{}:{}", run.id, seq)),
+                Err(e) => {
+                    eprintln!("Failed to build event {}:{}: {}", run.id, seq, e);
+                    continue;
+                }
+            };
+            if tx.send(Ok(event)).await.is_err() {
+                return true;
+            }
+        }
+        if finished {
+            return false;
+        }
+        tokio::select! {
+            changed = latest.changed() => {
+                if changed.is_err() {
+                    return false;
+                }
+            }
+            _ = tx.closed() => return true,
+        }
+    }
+}
+
+fn sse_response(rx: tokio::sync::mpsc::Receiver<Result<Event, axum::Error>>) -> impl IntoResponse {
+    Sse::new(tokio_stream::wrappers::ReceiverStream::new(rx)).keep_alive(
+        KeepAlive::new()
+            .interval(SSE_KEEP_ALIVE_INTERVAL)
+            .text("keep-alive"),
+    )
+}
+
+// Steering: what a run's candidates are scored against. Interactive sessions
+// change it mid-run; every change re-scores the candidates seen so far, and later
+// candidates and the final ranking use the new state. Added seeds only affect
+// scoring: the candidate pool still comes from the original seeds.
+struct Steering {
+    inputs: Vec<Track>, // Seeds, plus liked candidates
+    preferences: Preferences,
+    candidates: Vec<Track>,
+    liked: HashSet<String>,
+    disliked: HashSet<String>,
+    seed: u64, // For tie-breaks
+}
+
+#[derive(Serialize, ToSchema)]
+struct CandidateScore {
+    id: String,
+    score: f64,
+}
+
+impl Steering {
+    fn new(inputs: Vec<Track>, preferences: Preferences, seed: u64) -> Self {
+        Steering {
+            inputs,
+            preferences,
+            candidates: Vec::new(),
+            liked: HashSet::new(),
+            disliked: HashSet::new(),
+            seed,
+        }
+    }
+
+    fn score(&self, track: &Track) -> f64 {
+        score_candidate(&self.inputs, track, &self.preferences)
+    }
+
+    fn add(&mut self, track: Track) -> f64 {
+        let score = self.score(&track);
+        self.candidates.push(track);
+        score
+    }
+
+    fn candidate(&self, id: &str) -> Result<&Track, String> {
+        self.candidates
+            .iter()
+            .find(|track| track.id == id)
+            .ok_or_else(|| format!("Unknown candidate: {}", id))
+    }
+
+    // A liked candidate counts as a seed from now on
+    fn like(&mut self, id: &str) -> Result<(), String> {
+        let track = self.candidate(id)?.clone();
+        self.disliked.remove(id);
+        if self.liked.insert(id.to_string()) {
+            self.inputs.push(track);
+        }
+        Ok(())
+    }
+
+    // A disliked candidate is dropped from the results (and from the seeds, if liked)
+    fn dislike(&mut self, id: &str) -> Result<(), String> {
+        self.candidate(id)?;
+        if self.liked.remove(id) {
+            self.inputs.retain(|track| track.id != id);
+        }
+        self.disliked.insert(id.to_string());
+        Ok(())
+    }
+
+    fn add_seed(&mut self, track: Track) {
+        if !self.inputs.iter().any(|input| input.id == track.id) {
+            self.inputs.push(track);
+        }
+    }
+
+    fn remove_seed(&mut self, id: &str) -> Result<(), String> {
+        if !self.inputs.iter().any(|input| input.id == id) {
+            return Err(format!("Unknown seed: {}", id));
+        }
+        if self.inputs.len() == 1 {
+            return Err("Can't remove the last seed".to_string());
+        }
+        self.inputs.retain(|input| input.id != id);
+        self.liked.remove(id);
+        Ok(())
+    }
+
+    // Current scores of every candidate still in the running, best first
+    fn ranking(&self) -> Vec<(Track, f64)> {
+        let mut scored: Vec<(Track, f64)> = self
+            .candidates
+            .iter()
+            .filter(|track| !self.disliked.contains(&track.id))
+            .map(|track| (track.clone(), self.score(track)))
+            .collect();
+        scored.sort_by(|a, b| {
+            b.1.total_cmp(&a.1)
+                .then_with(|| tie_breaker(self.seed, &a.0.id).cmp(&tie_breaker(self.seed, &b.0.id)))
+        });
+        scored
+    }
+
+    fn rescored(&self) -> RecommendationEvent {
+        RecommendationEvent::Rescored {
+            candidates: self
+                .ranking()
+                .into_iter()
+                .map(|(track, score)| CandidateScore {
+                    id: track.id,
+                    score,
+                })
+                .collect(),
+        }
+    }
+
+    // The whole ranking, for paging through
+    fn pool(&self) -> RankedPool {
+        RankedPool {
+            tracks: select_top(self.ranking(), usize::MAX, &self.preferences),
+            last_seed: self.inputs.last().cloned(),
+            harmonic_sequence: self.preferences.harmonic_sequence,
+            seed: self.seed,
+            created_at: Instant::now(),
+        }
+    }
+}
+
+// Interactive sessions over WebSocket. The client opens with a Start message
+// and then receives the run's RecommendationEvents (as JSON text frames) while
+// it steers the run with the other messages. Closing the socket cancels the run.
+#[derive(Deserialize, ToSchema)]
+#[serde(tag = "type")]
+enum SessionMessage {
+    Start { request: RecommendRequest },
+    Like { track_id: String },
+    Dislike { track_id: String },
+    Preferences { preferences: Preferences },
+    AddSeed { query: String }, // Same formats as RecommendRequest.tracks
+    RemoveSeed { track_id: String },
+    Cancel,
+}
+
+#[utoipa::path(
+    get,
+    path = "/mb/recommend/session",
+    tag = "recommend",
+    summary = "Interactive session (WebSocket)",
+    description = "Upgrade to a WebSocket, send a SessionMessage of type Start, then receive RecommendationEvents as JSON text frames while steering the run with further SessionMessages. The server also sends {\"type\": \"Session\", \"run_id\"} once the run starts and {\"type\": \"SessionError\", \"message\"} for rejected messages.",
+    responses((status = 101, description = "Switching to the WebSocket protocol"))
+)]
+async fn recommend_session_handler(
+    State(app_state): State<Arc<AppState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    ws.on_upgrade(move |socket| run_session(app_state, socket))
+}
+
+async fn run_session(app_state: Arc<AppState>, mut socket: WebSocket) {
+    // Wait for the request
+    let req = loop {
+        match socket.recv().await {
+            Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                Ok(SessionMessage::Start { request }) => break request,
+                Ok(_) => {
+                    let _ = send_session_error(&mut socket, "Send a Start message first").await;
+                }
+                Err(e) => {
+                    let _ =
+                        send_session_error(&mut socket, &format!("Invalid message: {}", e)).await;
+                }
+            },
+            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+            Some(Ok(_)) => {}
+        }
+    };
+
+    let run = app_state.runs.create().await;
+    eprintln!("Starting interactive recommendation run {}", run.id);
+    let started = serde_json::json!({ "type": "Session", "run_id": run.id });
+    if socket
+        .send(Message::Text(started.to_string().into()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+    tokio::spawn(execute_run(app_state.clone(), req, run.clone()));
+
+    let mut latest = run.latest.subscribe();
+    let mut sent = 0;
+    let (errors, mut steering_errors) = tokio::sync::mpsc::unbounded_channel::<String>();
+    loop {
+        latest.borrow_and_update();
+        let (events, finished) = run.events_after(sent).await;
+        for (seq, data) in events {
+            if socket.send(Message::Text(data.into())).await.is_err() {
+                run.cancel.cancel();
+                return;
+            }
+            sent = seq;
+        }
+        if finished {
+            let _ = socket.send(Message::Close(None)).await;
+            return;
+        }
+
+        tokio::select! {
+            changed = latest.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+            Some(message) = steering_errors.recv() => {
+                if send_session_error(&mut socket, &message).await.is_err() {
+                    run.cancel.cancel();
+                    return;
+                }
+            }
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                        eprintln!("Cancelling run {}: session closed", run.id);
+                        run.cancel.cancel();
+                        return;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                let result = match serde_json::from_str::<SessionMessage>(&text) {
+                    Ok(message) => steer_run(&app_state, &run, message, &errors).await,
+                    Err(e) => Err(format!("Invalid message: {}", e)),
+                };
+                if let Err(message) = result {
+                    if send_session_error(&mut socket, &message).await.is_err() {
+                        run.cancel.cancel();
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Apply a steering message. Added seeds are resolved in the background, since that
+// goes through the rate limiter; errors from that arrive later on `errors`.
+async fn steer_run(
+    app_state: &Arc<AppState>,
+    run: &Arc<RecommendationRun>,
+    message: SessionMessage,
+    errors: &tokio::sync::mpsc::UnboundedSender<String>,
+) -> Result<(), String> {
+    match message {
+        SessionMessage::Start { .. } => Err("The session has already started".into()),
+        SessionMessage::Cancel => {
+            run.cancel.cancel();
+            Ok(())
+        }
+        SessionMessage::Like { track_id } => {
+            apply_steering(run, |steering| steering.like(&track_id)).await
+        }
+        SessionMessage::Dislike { track_id } => {
+            apply_steering(run, |steering| steering.dislike(&track_id)).await
+        }
+        SessionMessage::Preferences { preferences } => {
+            apply_steering(run, |steering| {
+                steering.preferences = preferences;
+                Ok(())
+            })
+            .await
+        }
+        SessionMessage::RemoveSeed { track_id } => {
+            apply_steering(run, |steering| steering.remove_seed(&track_id)).await
+        }
+        SessionMessage::AddSeed { query } => {
+            let (app_state, run, errors) = (app_state.clone(), run.clone(), errors.clone());
+            tokio::spawn(async move {
+                let result = tokio::select! {
+                    result = resolve_added_seed(&app_state, &query) => match result {
+                        Ok(track) => apply_steering(&run, |steering| {
+                            steering.add_seed(track);
+                            Ok(())
+                        })
+                        .await,
+                        Err(e) => Err(e),
+                    },
+                    _ = run.cancel.cancelled() => Ok(()),
+                };
+                if let Err(message) = result {
+                    let _ = errors.send(message);
+                }
+            });
+            Ok(())
+        }
+    }
+}
+
+// Change the run's steering state; the new scores go out to every watcher of the run
+async fn apply_steering(
+    run: &RecommendationRun,
+    change: impl FnOnce(&mut Steering) -> Result<(), String>,
+) -> Result<(), String> {
+    let event = {
+        let mut steering = run.steering.lock().await;
+        let steering = steering
+            .as_mut()
+            .ok_or("The run is still resolving its seeds; try again shortly")?;
+        change(steering)?;
+        steering.rescored()
+    };
+    run.emit(event).await;
+    Ok(())
+}
+
+// Resolve and enrich a seed added during a session
+async fn resolve_added_seed(app_state: &AppState, query: &str) -> Result<Track, String> {
+    let ids = resolve_tracks_musicbrainz(vec![query.to_string()], app_state)
+        .await
+        .map_err(|e| format!("Could not resolve seed '{}': {}", query, e))?;
+    let id = ids
+        .first()
+        .ok_or_else(|| format!("No recording found for '{}'", query))?;
+    prefetch_track_data(&track_mbids(&ids), app_state).await;
+    aggregate_features_musicbrainz(id, app_state)
+        .await
+        .map_err(|e| format!("Could not load seed '{}': {}", query, e))
+}
+
+async fn send_session_error(socket: &mut WebSocket, message: &str) -> Result<(), axum::Error> {
+    let error = serde_json::json!({ "type": "SessionError", "message": message });
+    socket.send(Message::Text(error.to_string().into())).await
+}
+
+// Progress reporting: the pipeline's stages with done/total counts, and an ETA
+// from the MusicBrainz requests left at the rate limiter's recent pace (the
+// limiter is what bounds a run; everything else is comparatively quick)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, ToSchema)]
+#[serde(rename_all = "snake_case")]
+enum Stage {
+    ResolveSeeds,
+    EnrichSeeds,
+    GenerateCandidates,
+    EnrichCandidates,
+    Rank,
+}
+
+// Rough MusicBrainz requests per unit of work
+const REQUESTS_PER_SEED_QUERY: f64 = 1.0; // Search
+const REQUESTS_PER_SEED: f64 = 2.0; // Recording lookup and work browse
+const REQUESTS_PER_CANDIDATE: f64 = 3.0; // Search, recording lookup and work browse
+const EXPECTED_CANDIDATES_PER_SEED: usize = LASTFM_SIMILAR_LIMIT; // Until we know
+
+struct ProgressTracker {
+    started: Instant,
+    seed_queries: usize,
+    seeds: Option<usize>,
+    candidates: Option<usize>,
+}
+
+impl ProgressTracker {
+    fn new(req: &RecommendRequest) -> Self {
+        ProgressTracker {
+            started: Instant::now(),
+            seed_queries: req.tracks.len() + req.artists.len() + req.albums.len(),
+            seeds: None,
+            candidates: None,
+        }
+    }
+
+    fn event(
+        &self,
+        stage: Stage,
+        done: usize,
+        total: usize,
+        limiter: &RateLimiter,
+    ) -> RecommendationEvent {
+        let remaining = total.saturating_sub(done) as f64;
+        let seeds = self.seeds.unwrap_or(self.seed_queries);
+        let candidates = self
+            .candidates
+            .unwrap_or(seeds * EXPECTED_CANDIDATES_PER_SEED) as f64;
+        let requests = match stage {
+            Stage::ResolveSeeds => {
+                remaining * REQUESTS_PER_SEED_QUERY
+                    + seeds as f64 * REQUESTS_PER_SEED
+                    + candidates * REQUESTS_PER_CANDIDATE
+            }
+            Stage::EnrichSeeds => {
+                remaining * REQUESTS_PER_SEED + candidates * REQUESTS_PER_CANDIDATE
+            }
+            Stage::GenerateCandidates => candidates * REQUESTS_PER_CANDIDATE,
+            Stage::EnrichCandidates => remaining * REQUESTS_PER_CANDIDATE,
+            Stage::Rank => 0.0,
+        };
+        RecommendationEvent::Progress {
+            stage,
+            done,
+            total,
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            eta_ms: (requests * limiter.seconds_per_request() * 1000.0) as u64,
+        }
+    }
+}
+
+// SSE event types for streaming
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "type")]
+enum RecommendationEvent {
+    Status { message: String },
+    Candidate { track: Box<Track>, score: f64 },
+    Complete { tracks: Vec<Track>, total: usize, next: Option<String>, seed: u64 },
+    Error { message: String },
+    Debug { message: String, data: Option<serde_json::Value> },
+    // After a session steered the run
+    Rescored { candidates: Vec<CandidateScore> },
+    // eta_ms estimates the time left for the whole run
+    Progress { stage: Stage, done: usize, total: usize, elapsed_ms: u64, eta_ms: u64 },
+}
+
+// Finishes a run however execute_run ends, including a panic in the pipeline
+struct FinishOnDrop(Arc<RecommendationRun>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        let run = self.0.clone();
+        let panicked = std::thread::panicking();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        runtime.spawn(async move {
+            if panicked {
+                run.emit(RecommendationEvent::Error {
+                    message: "Processing error: the run failed unexpectedly".to_string(),
+                })
+                .await;
+            }
+            run.finish().await;
+        });
+    }
+}
+
+// Streaming MusicBrainz recommend handler: starts a run and streams it. The run
+// continues if the client disconnects; it can reconnect with Last-Event-ID (here
+// or on /mb/recommend/stream/{run_id}) to resume.
+#[utoipa::path(
+    post,
+    path = "/mb/recommend/stream",
+    tag = "recommend",
+    summary = "Start a run and stream its events",
+    params(("Last-Event-ID" = Option<String>, Header, description = "\"<run_id>:<seq>\" to resume a run instead of starting one")),
+    request_body = RecommendRequest,
+    responses((
+        status = 200,
+        description = "Server-Sent Events; each data field is a RecommendationEvent and each id is \"<run_id>:<seq>\"",
+        content_type = "text/event-stream",
+        body = RecommendationEvent,
+        headers(("x-run-id" = String, description = "Id of the run, for resuming or cancelling"))
+    ))
+)]
+async fn recommend_musicbrainz_stream_handler(
+    State(app_state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(req): Json<RecommendRequest>,
+) -> impl IntoResponse {
+    // A client retrying the POST after a dropped connection resumes its run
+    if let Some((run_id, after)) = last_event_id(&headers) {
+        if let Some(run) = app_state.runs.get(&run_id).await {
+            return stream_run(run, after).into_response();
+        }
+    }
+
+    let run = app_state.runs.create().await;
+    eprintln!("Starting recommendation run {}", run.id);
+
+    // Spawn the recommendation task
+    tokio::spawn(execute_run(app_state, req, run.clone()));
+
+    stream_run(run, 0).into_response()
+}
+
+// Cancels runs (finished ones ignore it) when a handler's future is dropped
+struct CancelOnDrop(Vec<Arc<RecommendationRun>>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        for run in &self.0 {
+            run.cancel.cancel();
+        }
+    }
+}
+
+// Run the pipeline to completion; cancelling drops it at its next await
+async fn execute_run(app_state: Arc<AppState>, req: RecommendRequest, run: Arc<RecommendationRun>) {
+    let _finish = FinishOnDrop(run.clone());
+    let engine = RecommendationEngine::for_run(app_state, &run);
+    let message = tokio::select! {
+        // The engine reports its own failures as Error events
+        _ = engine.run(req) => None,
+        _ = run.cancel.cancelled() => Some("Run cancelled".to_string()),
+    };
+    if let Some(message) = message {
+        run.emit(RecommendationEvent::Error { message }).await;
+    }
+}
+
+// Resume (or watch) a run: replays events after Last-Event-ID, then follows live
+#[utoipa::path(
+    get,
+    path = "/mb/recommend/stream/{run_id}",
+    tag = "recommend",
+    summary = "Resume (or watch) a run's event stream",
+    params(
+        ("run_id" = String, Path),
+        ("Last-Event-ID" = Option<String>, Header, description = "Replay events after this one")
+    ),
+    responses(
+        (status = 200, content_type = "text/event-stream", body = RecommendationEvent),
+        (status = 404, body = String)
+    )
+)]
+async fn recommend_stream_resume_handler(
+    State(app_state): State<Arc<AppState>>,
+    Path(run_id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let Some(run) = app_state.runs.get(&run_id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Unknown or expired run: {}", run_id),
+        )
+            .into_response();
+    };
+    // Only honour an id from this run; otherwise replay from the start
+    let after = last_event_id(&headers)
+        .filter(|(id, _)| *id == run_id)
+        .map(|(_, seq)| seq)
+        .unwrap_or(0);
+    stream_run(run, after).into_response()
+}
+
+#[derive(Serialize, ToSchema)]
+struct RunCancelled {
+    run_id: String,
+    status: &'static str,
+}
+
+// Batch recommendations: several named seed sets, each with its own preferences,
+// run side by side as ordinary runs that share one WorkCache
+const MAX_BATCH_SETS: usize = 50;
+
+#[derive(Deserialize, ToSchema)]
+struct BatchRequest {
+    sets: Vec<SeedSet>,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SeedSet {
+    name: String, // Unique within the batch, e.g. a playlist name
+    #[serde(flatten)]
+    request: RecommendRequest,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchResponse {
+    results: Vec<SeedSetResult>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SeedSetResult {
+    name: String,
+    run_id: String,
+    tracks: Vec<Track>,
+    error: Option<String>,
+}
+
+// Multiplexed stream event: one of a set's RecommendationEvents
+#[derive(Serialize, ToSchema)]
+struct BatchEvent {
+    set: String,
+    #[schema(value_type = RecommendationEvent)]
+    event: serde_json::Value, // As buffered by the set's run
+}
+
+// Validate the batch and start one run per set, in request order
+async fn start_batch(
+    app_state: &Arc<AppState>,
+    batch: BatchRequest,
+) -> Result<Vec<(String, Arc<RecommendationRun>)>, Response> {
+    if batch.sets.is_empty() || batch.sets.len() > MAX_BATCH_SETS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A batch needs 1 to {} seed sets", MAX_BATCH_SETS),
+        )
+            .into_response());
+    }
+    let mut names = HashSet::new();
+    if let Some(set) = batch
+        .sets
+        .iter()
+        .find(|set| !names.insert(set.name.as_str()))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Duplicate seed set name: {}", set.name),
+        )
+            .into_response());
+    }
+
+    let work = Arc::new(WorkCache::default());
+    let mut runs = Vec::new();
+    for set in batch.sets {
+        let run = app_state.runs.create_sharing(work.clone()).await;
+        eprintln!("Starting batch run {} for '{}'", run.id, set.name);
+        tokio::spawn(execute_queued_run(
+            app_state.clone(),
+            set.request,
+            run.clone(),
+        ));
+        runs.push((set.name, run));
+    }
+    Ok(runs)
+}
+
+#[utoipa::path(
+    post,
+    path = "/mb/recommend/batch",
+    tag = "recommend",
+    summary = "Recommend for several seed sets at once",
+    description = "Runs every set concurrently, sharing MusicBrainz lookups between them, and returns when all have finished. A set that fails has an error instead of tracks.",
+    request_body = BatchRequest,
+    responses((status = 200, body = BatchResponse), (status = 400, body = String))
+)]
+async fn recommend_batch_handler(
+    State(app_state): State<Arc<AppState>>,
+    Json(batch): Json<BatchRequest>,
+) -> impl IntoResponse {
+    let runs = match start_batch(&app_state, batch).await {
+        Ok(runs) => runs,
+        Err(response) => return response,
+    };
+    // Nobody can collect the results once the client has gone
+    let _cancel = CancelOnDrop(runs.iter().map(|(_, run)| run.clone()).collect());
+
+    let mut results = Vec::new();
+    for (name, run) in runs {
+        run.wait_until_finished().await;
+        let (tracks, error) = match run.outcome() {
+            Ok(tracks) => (tracks, None),
+            Err(e) => (Vec::new(), Some(e)),
+        };
+        results.push(SeedSetResult {
+            name,
+            run_id: run.id.clone(),
+            tracks,
+            error,
+        });
+    }
+    Json(BatchResponse { results }).into_response()
+}
+
+#[utoipa::path(
+    post,
+    path = "/mb/recommend/batch/stream",
+    tag = "recommend",
+    summary = "Recommend for several seed sets, streaming all their events",
+    description = "One Server-Sent Events stream for the whole batch. Each event id is \"<run_id>:<seq>\", so a dropped set can be resumed on /mb/recommend/stream/{run_id}.",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, content_type = "text/event-stream", body = BatchEvent),
+        (status = 400, body = String)
+    )
+)]
+async fn recommend_batch_stream_handler(
+    State(app_state): State<Arc<AppState>>,
+    Json(batch): Json<BatchRequest>,
+) -> impl IntoResponse {
+    let runs = match start_batch(&app_state, batch).await {
+        Ok(runs) => runs,
+        Err(response) => return response,
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel(10);
+    for (name, run) in runs {
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            let to_event = |data: String| {
+                let event = serde_json::from_str(&data).map_err(axum::Error::new)?;
+                Event::default().json_data(BatchEvent {
+                    set: name.clone(),
+                    event,
+                })
+            };
+            if forward_run(&run, 0, &tx, to_event).await {
+                run.cancel_if_abandoned().await;
+            }
+        });
+    }
+    sse_response(rx).into_response()
+}
+
+// Cancel a streaming run, e.g. when the user navigates away or starts over
+#[utoipa::path(
+    delete,
+    path = "/mb/recommend/{run_id}",
+    tag = "recommend",
+    summary = "Cancel a run (or job)",
+    params(("run_id" = String, Path)),
+    responses(
+        (status = 202, body = RunCancelled),
+        (status = 404, body = String),
+        (status = 409, description = "The run has already finished", body = String)
+    )
+)]
+async fn cancel_recommendation_handler(
+    State(app_state): State<Arc<AppState>>,
+    Path(run_id): Path<String>,
+) -> impl IntoResponse {
+    let Some(run) = app_state.runs.get(&run_id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            format!("Unknown or expired run: {}", run_id),
+        )
+            .into_response();
+    };
+    if run.is_finished().await {
+        return (
+            StatusCode::CONFLICT,
+            format!("Run {} has already finished", run_id),
+        )
+            .into_response();
+    }
+    run.cancel.cancel();
+    eprintln!("Cancelling run {}: requested by client", run_id);
+    (
+        StatusCode::ACCEPTED,
+        Json(RunCancelled {
+            run_id,
+            status: "cancelling",
+        }),
+    )
+        .into_response()
+}
+
+// Recommendation jobs: the streaming pipeline run in the background, with its
+// events folded into a status document persisted under JOBS_DIR. A job's id is
+// also its run id, so it can be followed on /mb/recommend/stream/{id} and
+// cancelled with DELETE /mb/recommend/{id}. Unfinished jobs rerun after a restart.
+const JOB_RETENTION: Duration = Duration::from_secs(24 * 60 * 60); // After the job finishes
+const MAX_CONCURRENT_JOBS: usize = 2; // They share one rate limiter anyway
+const JOB_PARTIAL_RESULTS: usize = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, ToSchema)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, ToSchema)]
+#[serde(default)]
+struct JobProgress {
+    message: String,
+    candidates_scored: usize,
+    stage: Option<Stage>,
+    done: usize,
+    total: usize,
+    eta_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+struct ScoredTrack {
+    track: Track,
+    score: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, ToSchema)]
+struct Job {
+    id: String,
+    status: JobStatus,
+    request: RecommendRequest,
+    created_at: u64, // Unix seconds
+    updated_at: u64,
+    finished_at: Option<u64>,
+    progress: JobProgress,
+    partial_results: Vec<ScoredTrack>, // Best candidates so far
+    results: Option<Vec<Track>>,
+    error: Option<String>,
+}
+
+impl Job {
+    fn is_finished(&self) -> bool {
+        !matches!(self.status, JobStatus::Queued | JobStatus::Running)
+    }
+
+    // Fold a serialized RecommendationEvent into the job; true if it's worth persisting
+    fn apply(&mut self, event: &serde_json::Value) -> bool {
+        let message = event["message"].as_str().unwrap_or_default().to_string();
+        match event["type"].as_str() {
+            Some("Status") => {
+                self.status = JobStatus::Running;
+                self.progress.message = message;
+                true
+            }
+            Some("Progress") => {
+                let Ok(stage) = serde_json::from_value(event["stage"].clone()) else {
+                    return false;
+                };
+                self.progress.stage = Some(stage);
+                self.progress.done = event["done"].as_u64().unwrap_or_default() as usize;
+                self.progress.total = event["total"].as_u64().unwrap_or_default() as usize;
+                self.progress.eta_ms = event["eta_ms"].as_u64();
+                false
+            }
+            Some("Candidate") => {
+                self.progress.candidates_scored += 1;
+                let (Ok(track), Some(score)) = (
+                    serde_json::from_value::<Track>(event["track"].clone()),
+                    event["score"].as_f64(),
+                ) else {
+                    return false;
+                };
+                self.partial_results.push(ScoredTrack { track, score });
+                self.partial_results
+                    .sort_by(|a, b| b.score.total_cmp(&a.score));
+                self.partial_results.truncate(JOB_PARTIAL_RESULTS);
+                false
+            }
+            Some("Complete") => {
+                self.status = JobStatus::Completed;
+                self.results = serde_json::from_value(event["tracks"].clone()).ok();
+                self.progress.message = "Complete".to_string();
+                true
+            }
+            Some("Error") => {
+                self.status = JobStatus::Failed;
+                self.error = Some(message);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+struct JobStore {
+    dir: std::path::PathBuf,
+    jobs: TokioMutex<HashMap<String, Job>>,
+    slots: tokio::sync::Semaphore,
+    writes: TokioMutex<()>, // Serializes job files; taken before `jobs`, never while holding it
+}
+
+impl JobStore {
+    // Load persisted jobs, dropping those past retention
+    fn load(dir: std::path::PathBuf) -> Self {
+        let mut jobs = HashMap::new();
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            eprintln!("Failed to create jobs directory {}: {}", dir.display(), e);
+        }
+        for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let job = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| serde_json::from_str::<Job>(&text).ok());
+            match job {
+                Some(job) if !Self::expired(&job) => {
+                    jobs.insert(job.id.clone(), job);
+                }
+                _ => {
+                    let _ = std::fs::remove_file(&path);
+                }
+            }
+        }
+        JobStore {
+            dir,
+            jobs: TokioMutex::new(jobs),
+            slots: tokio::sync::Semaphore::new(MAX_CONCURRENT_JOBS),
+            writes: TokioMutex::new(()),
+        }
+    }
+
+    fn expired(job: &Job) -> bool {
+        job.finished_at
+            .is_some_and(|at| unix_now().saturating_sub(at) > JOB_RETENTION.as_secs())
+    }
+
+    fn path(&self, id: &str) -> std::path::PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    // Write a job's current state via a temporary file, so a crash never leaves a
+    // half-written job. The state is read after taking the write lock, so whichever
+    // write comes last has the latest state; the file I/O runs outside the job map lock.
+    async fn persist(&self, id: &str) {
+        let _write = self.writes.lock().await;
+        let json = match self.jobs.lock().await.get(id).map(serde_json::to_string) {
+            Some(Ok(json)) => json,
+            Some(Err(e)) => {
+                eprintln!("Failed to serialize job {}: {}", id, e);
+                return;
+            }
+            None => return,
+        };
+        let tmp = self.dir.join(format!("{}.json.tmp", id));
+        let result = match tokio::fs::write(&tmp, json).await {
+            Ok(()) => tokio::fs::rename(&tmp, self.path(id)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to persist job {}: {}", id, e);
+        }
+    }
+
+    async fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().await.get(id).cloned()
+    }
+
+    async fn insert(&self, job: Job) {
+        let id = job.id.clone();
+        let expired: Vec<String> = {
+            let mut jobs = self.jobs.lock().await;
+            let expired = jobs
+                .iter()
+                .filter(|(_, existing)| Self::expired(existing))
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in &expired {
+                jobs.remove(id);
+            }
+            jobs.insert(id.clone(), job);
+            expired
+        };
+        self.persist(&id).await;
+        for id in expired {
+            let _ = tokio::fs::remove_file(self.path(&id)).await;
+        }
+    }
+
+    async fn update(&self, id: &str, f: impl FnOnce(&mut Job) -> bool) {
+        let changed = {
+            let mut jobs = self.jobs.lock().await;
+            let Some(job) = jobs.get_mut(id) else {
+                return;
+            };
+            let changed = f(job);
+            if changed {
+                job.updated_at = unix_now();
+            }
+            changed
+        };
+        if changed {
+            self.persist(id).await;
+        }
+    }
+
+    // Jobs a restart interrupted, reset to start over
+    async fn take_unfinished(&self) -> Vec<Job> {
+        let unfinished: Vec<Job> = {
+            let mut jobs = self.jobs.lock().await;
+            jobs.values_mut()
+                .filter(|job| !job.is_finished())
+                .map(|job| {
+                    job.status = JobStatus::Queued;
+                    job.progress = JobProgress::default();
+                    job.partial_results.clear();
+                    job.updated_at = unix_now();
+                    job.clone()
+                })
+                .collect()
+        };
+        for job in &unfinished {
+            self.persist(&job.id).await;
+        }
+        unfinished
+    }
+}
+
+// Run the pipeline once one of the MAX_CONCURRENT_JOBS slots is free. Jobs and
+// batch runs share the slots, so neither can start an unbounded number of pipelines.
+async fn execute_queued_run(
+    app_state: Arc<AppState>,
+    req: RecommendRequest,
+    run: Arc<RecommendationRun>,
+) {
+    let permit = tokio::select! {
+        permit = app_state.jobs.slots.acquire() => permit.ok(),
+        _ = run.cancel.cancelled() => None,
+    };
+    // Cancelled while queued (the select can pick the permit even then)
+    if permit.is_none() || run.cancel.is_cancelled() {
+        run.emit(RecommendationEvent::Error {
+            message: "Run cancelled".to_string(),
+        })
+        .await;
+        run.finish().await;
+        return;
+    }
+    execute_run(app_state.clone(), req, run).await;
+    drop(permit);
+}
+
+// Queue a job's run behind the concurrency limit, and fold its events into the job
+async fn start_job(app_state: Arc<AppState>, job: &Job) {
+    let run = app_state.runs.create_with_id(job.id.clone()).await;
+    let req = job.request.clone();
+
+    tokio::spawn(execute_queued_run(app_state.clone(), req, run.clone()));
+
+    tokio::spawn(async move {
+        let mut latest = run.latest.subscribe();
+        let mut seen = 0;
+        loop {
+            latest.borrow_and_update();
+            let (events, finished) = run.events_after(seen).await;
+            let cancelled = run.cancel.is_cancelled();
+            app_state
+                .jobs
+                .update(&run.id, |job| {
+                    let mut changed = false;
+                    for (seq, data) in &events {
+                        seen = *seq;
+                        if let Ok(event) = serde_json::from_str(data) {
+                            changed |= job.apply(&event);
+                        }
+                    }
+                    if finished {
+                        if cancelled && job.status != JobStatus::Completed {
+                            job.status = JobStatus::Cancelled;
+                        }
+                        job.finished_at = Some(unix_now());
+                        changed = true;
+                    }
+                    changed
+                })
+                .await;
+            if finished || latest.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+// Requeue jobs interrupted by a restart
+async fn resume_jobs(app_state: Arc<AppState>) {
+    let jobs = app_state.jobs.take_unfinished().await;
+    if !jobs.is_empty() {
+        println!("Resuming {} unfinished recommendation jobs", jobs.len());
+    }
+    for job in &jobs {
+        start_job(app_state.clone(), job).await;
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+struct JobCreated {
+    job_id: String,
+    status: JobStatus,
+    status_url: String,
+    stream_url: String, // Live events, while the server that runs the job is up
+}
+
+#[utoipa::path(
+    post,
+    path = "/jobs/recommend",
+    tag = "jobs",
+    summary = "Queue a recommendation job",
+    request_body = RecommendRequest,
+    responses((status = 202, body = JobCreated))
+)]
+async fn create_job_handler(
+    State(app_state): State<Arc<AppState>>,
+    Json(req): Json<RecommendRequest>,
+) -> impl IntoResponse {
+    let now = unix_now();
+    let job = Job {
+        id: format!("{:016x}", rand::Rng::gen::<u64>(&mut thread_rng())),
+        status: JobStatus::Queued,
+        request: req,
+        created_at: now,
+        updated_at: now,
+        finished_at: None,
+        progress: JobProgress::default(),
+        partial_results: Vec::new(),
+        results: None,
+        error: None,
+    };
+    app_state.jobs.insert(job.clone()).await;
+    start_job(app_state, &job).await;
+    eprintln!("Queued recommendation job {}", job.id);
+
+    (
+        StatusCode::ACCEPTED,
+        Json(JobCreated {
+            status_url: format!("{}/jobs/{}", API_VERSION_PREFIX, job.id),
+            stream_url: format!("{}/mb/recommend/stream/{}", API_VERSION_PREFIX, job.id),
+            job_id: job.id,
+            status: job.status,
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{job_id}",
+    tag = "jobs",
+    summary = "Job status, progress and results",
+    params(("job_id" = String, Path)),
+    responses((status = 200, body = Job), (status = 404, body = String))
+)]
+async fn get_job_handler(
+    State(app_state): State<Arc<AppState>>,
+    Path(job_id): Path<String>,
+) -> impl IntoResponse {
+    match app_state.jobs.get(&job_id).await {
+        Some(job) => Json(job).into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("Unknown or expired job: {}", job_id),
+        )
+            .into_response(),
+    }
+}
+
+// Result pages: a run ranks its whole candidate pool and returns one page of it,
+// plus a continuation token when there is more. The pool is kept for a while so
+// the next page comes from GET /mb/recommend/more without re-running the pipeline.
+// Tokens are "<pool_id>:<offset>:<limit>".
+const DEFAULT_RECOMMENDATION_LIMIT: usize = 20;
+const MAX_RECOMMENDATION_LIMIT: usize = 100;
+const RESULT_POOL_RETENTION: Duration = Duration::from_secs(30 * 60);
+const MAX_RESULT_POOLS: usize = 1_000; // The oldest pool goes first beyond this
+
+struct RankedPool {
+    tracks: Vec<Track>,       // Best first, with artist diversity applied
+    last_seed: Option<Track>, // Where harmonic sequencing starts
+    harmonic_sequence: bool,
+    seed: u64,
+    created_at: Instant,
+}
+
+#[derive(Clone)]
+struct RecommendResponse {
+    tracks: Vec<Track>,
+    total: usize,         // Tracks in the whole ranking
+    next: Option<String>, // Continuation token for the next page
+    seed: u64,            // Send it back to reproduce these results
+}
+
+// A page goes out as a plain track array, the body POST /mb/recommend has always
+// returned; the paging details ride in headers
+fn page_response(page: RecommendResponse) -> Response {
+    let mut headers = vec![
+        ("x-total-count", page.total.to_string()),
+        ("x-recommendation-seed", page.seed.to_string()),
+    ];
+    if let Some(next) = page.next {
+        headers.push(("x-next-token", next));
+    }
+    (StatusCode::OK, AppendHeaders(headers), Json(page.tracks)).into_response()
+}
+
+impl RankedPool {
+    // With harmonic sequencing, each page is ordered on its own from the last seed
+    fn page(&self, pool_id: &str, offset: usize, limit: usize) -> RecommendResponse {
+        let mut tracks: Vec<Track> = self
+            .tracks
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .cloned()
+            .collect();
+        if self.harmonic_sequence {
+            tracks = sequence_harmonically(self.last_seed.as_ref(), tracks);
+        }
+        let end = offset + tracks.len();
+        RecommendResponse {
+            next: (end < self.tracks.len()).then(|| format!("{}:{}:{}", pool_id, end, limit)),
+            total: self.tracks.len(),
+            seed: self.seed,
+            tracks,
+        }
+    }
+}
+
+struct ResultPools {
+    pools: TokioMutex<HashMap<String, Arc<RankedPool>>>,
+}
+
+impl ResultPools {
+    fn new() -> Self {
+        ResultPools {
+            pools: TokioMutex::new(HashMap::new()),
+        }
+    }
+
+    // Keep a pool and return its first requested page
+    async fn insert(&self, pool: RankedPool, offset: usize, limit: usize) -> RecommendResponse {
+        let id = format!("{:016x}", rand::Rng::gen::<u64>(&mut thread_rng()));
+        let page = pool.page(&id, offset, limit);
+        if page.next.is_none() {
+            return page;
+        }
+        let mut pools = self.pools.lock().await;
+        if pools.len() >= MAX_RESULT_POOLS {
+            pools.retain(|_, pool| pool.created_at.elapsed() < RESULT_POOL_RETENTION);
+        }
+        if pools.len() >= MAX_RESULT_POOLS {
+            let oldest = pools
+                .iter()
+                .min_by_key(|(_, pool)| pool.created_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                pools.remove(&oldest);
+            }
+        }
+        pools.insert(id, Arc::new(pool));
+        page
+    }
+
+    // Drop expired pools (run periodically, so idle servers don't hold them)
+    async fn sweep(&self) {
+        self.pools
+            .lock()
+            .await
+            .retain(|_, pool| pool.created_at.elapsed() < RESULT_POOL_RETENTION);
+    }
+
+    async fn get(&self, id: &str) -> Option<Arc<RankedPool>> {
+        let pool = self.pools.lock().await.get(id).cloned()?;
+        (pool.created_at.elapsed() < RESULT_POOL_RETENTION).then_some(pool)
+    }
+}
+
+fn parse_continuation(token: &str) -> Option<(&str, usize, usize)> {
+    let mut parts = token.split(':');
+    let id = parts.next()?;
+    let offset = parts.next()?.parse().ok()?;
+    let limit = parts.next()?.parse().ok()?;
+    parts.next().is_none().then_some((id, offset, limit))
+}
+
+#[derive(Deserialize, IntoParams)]
+struct MoreParams {
+    token: String,        // `next` from the previous page
+    limit: Option<usize>, // Defaults to the previous page's size
+}
+
+#[utoipa::path(
+    get,
+    path = "/mb/recommend/more",
+    tag = "recommend",
+    summary = "Next page of a finished run's recommendations",
+    description = "Pages through the already ranked pool; nothing is re-run. Pools expire 30 minutes after the run.",
+    params(MoreParams),
+    responses(
+        (status = 200, body = Vec<Track>, headers(
+            ("x-total-count" = usize, description = "Tracks in the whole ranking"),
+            ("x-next-token" = String, description = "Continuation token for the next page, if any"),
+            ("x-recommendation-seed" = String, description = "Seed to send back to reproduce the results")
+        )),
+        (status = 400, body = String),
+        (status = 404, body = String)
+    )
+)]
+async fn recommend_more_handler(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<MoreParams>,
+) -> impl IntoResponse {
+    let Some((id, offset, limit)) = parse_continuation(&params.token) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            "Invalid continuation token".to_string(),
+        )
+            .into_response();
+    };
+    let Some(pool) = app_state.result_pools.get(id).await else {
+        return (
+            StatusCode::NOT_FOUND,
+            "These results have expired; run the request again".to_string(),
+        )
+            .into_response();
+    };
+    let limit = params
+        .limit
+        .unwrap_or(limit)
+        .clamp(1, MAX_RECOMMENDATION_LIMIT);
+    page_response(pool.page(id, offset, limit))
+}
+
+// The recommendation pipeline: resolve and enrich the seeds, collect similar tracks
+// from Last.fm as candidates, score them and rank them. Everything it produces goes
+// to its sink as RecommendationEvents, so every transport (streams, sessions, jobs,
+// batches and the plain JSON endpoint) gets the same results for the same request.
+const LASTFM_SIMILAR_LIMIT: usize = 20; // Similar tracks per seed
+
+trait EventSink: Send + Sync {
+    fn emit(&self, event: RecommendationEvent) -> impl std::future::Future<Output = ()> + Send;
+}
+
+impl EventSink for RecommendationRun {
+    async fn emit(&self, event: RecommendationEvent) {
+        RecommendationRun::emit(self, event).await
+    }
+}
+
+// Keeps only the outcome: the final ranking or the error that ended the run
+#[derive(Default)]
+struct CollectorSink {
+    outcome: std::sync::Mutex<Option<Result<RecommendResponse, String>>>,
+}
+
+impl CollectorSink {
+    fn record(&self, event: &RecommendationEvent) {
+        let outcome = match event {
+            RecommendationEvent::Complete {
+                tracks,
+                total,
+                next,
+                seed,
+            } => Ok(RecommendResponse {
+                tracks: tracks.clone(),
+                total: *total,
+                next: next.clone(),
+                seed: *seed,
+            }),
+            RecommendationEvent::Error { message } => Err(message.clone()),
+            _ => return,
+        };
+        *self.outcome.lock().unwrap() = Some(outcome);
+    }
+}
+
+impl EventSink for CollectorSink {
+    async fn emit(&self, event: RecommendationEvent) {
+        self.record(&event);
+    }
+}
+
+struct RecommendationEngine<'a, S: EventSink> {
+    app_state: Arc<AppState>,
+    sink: &'a S,
+    work: &'a WorkCache,
+    steering: &'a TokioMutex<Option<Steering>>, // Set once the seeds are enriched
+}
+
+impl<'a> RecommendationEngine<'a, RecommendationRun> {
+    fn for_run(app_state: Arc<AppState>, run: &'a RecommendationRun) -> Self {
+        RecommendationEngine {
+            app_state,
+            sink: run,
+            work: &run.work,
+            steering: &run.steering,
+        }
+    }
+}
+
+impl<S: EventSink> RecommendationEngine<'_, S> {
+    // Record a scored candidate, returning its score under the current steering
+    async fn add_candidate(&self, track: Track) -> f64 {
+        match self.steering.lock().await.as_mut() {
+            Some(steering) => steering.add(track),
+            None => 0.0,
+        }
+    }
+
+    async fn run(&self, req: RecommendRequest) -> Result<(), RunError> {
+        let result = self.recommend(&req).await;
+        if let Err(e) = &result {
+            self.sink
+                .emit(RecommendationEvent::Error {
+                    message: e.message().to_string(),
+                })
+                .await;
+        }
+        result
+    }
+
+    async fn recommend(&self, req: &RecommendRequest) -> Result<(), RunError> {
+        let mut progress = ProgressTracker::new(req);
+
+        // Send initial status
+        self.sink
+            .emit(RecommendationEvent::Status {
+                message: "Starting recommendation process...".to_string(),
+            })
+            .await;
+
+        let seeds = self.resolve_inputs(req, &mut progress).await?;
+        let inputs = self.enrich_inputs(&seeds, &mut progress).await?;
+        // From here on, candidates are scored against the run's (steerable) state
+        *self.steering.lock().await = Some(Steering::new(
+            inputs.clone(),
+            req.preferences.clone(),
+            req.seed_or_random(),
+        ));
+        let candidate_queries = self.generate_candidates(&inputs, &mut progress).await?;
+        self.enrich_candidates(&seeds, &candidate_queries, &mut progress)
+            .await;
+        self.rank(req, &progress).await;
+        Ok(())
+    }
+
+    // Resolve the requested tracks, artists and albums to seed recordings
+    async fn resolve_inputs(
+        &self,
+        req: &RecommendRequest,
+        progress: &mut ProgressTracker,
+    ) -> Result<Vec<TrackId>, RunError> {
+        let limiter = &self.app_state.rate_limiter;
+        self.sink
+            .emit(RecommendationEvent::Status {
+                message: "Searching for input tracks...".to_string(),
+            })
+            .await;
+        let seed_queries = progress.seed_queries;
+        self.sink
+            .emit(progress.event(Stage::ResolveSeeds, 0, seed_queries, limiter))
+            .await;
+
+        let seeds = resolve_seeds(req, &self.app_state, self.work)
+            .await
+            .map_err(|e| RunError::Upstream(format!("Processing error: {}", e)))?;
+        self.app_state.suggest_cache.remember_seeds(&seeds).await;
+        progress.seeds = Some(seeds.len());
+        self.sink
+            .emit(progress.event(Stage::ResolveSeeds, seed_queries, seed_queries, limiter))
+            .await;
+
+        self.sink
+            .emit(RecommendationEvent::Status {
+                message: format!("Found {} input tracks", seeds.len()),
+            })
+            .await;
+        if seeds.is_empty() {
+            return Err(RunError::BadInput(
+                "No valid input tracks found".to_string(),
+            ));
+        }
+        Ok(seeds)
+    }
+
+    // Fetch features, tags and popularity for each seed
+    async fn enrich_inputs(
+        &self,
+        seeds: &[TrackId],
+        progress: &mut ProgressTracker,
+    ) -> Result<Vec<Track>, RunError> {
+        let limiter = &self.app_state.rate_limiter;
+        prefetch_track_data(&track_mbids(seeds), &self.app_state).await;
+        let mut inputs = Vec::new();
+        for (i, seed) in seeds.iter().enumerate() {
+            self.sink
+                .emit(RecommendationEvent::Status {
+                    message: format!("Processing track {}/{}: {}", i + 1, seeds.len(), seed.name),
+                })
+                .await;
+            self.sink
+                .emit(progress.event(Stage::EnrichSeeds, i, seeds.len(), limiter))
+                .await;
+
+            // Send debug info about selected track
+            self.sink
+                .emit(RecommendationEvent::Debug {
+                    message: format!("Selected: {} by {}", seed.name, seed.artist),
+                    data: None,
+                })
+                .await;
+
+            if let Ok(track) = self.work.enrich(seed, &self.app_state).await {
+                inputs.push(track);
+            }
+        }
+        self.sink
+            .emit(progress.event(Stage::EnrichSeeds, seeds.len(), seeds.len(), limiter))
+            .await;
+
+        // The seeds resolved, so MusicBrainz failed to describe every one of them
+        if inputs.is_empty() {
+            return Err(RunError::Upstream(
+                "Could not look up any of the input tracks".to_string(),
+            ));
+        }
+        progress.seeds = Some(inputs.len());
+        Ok(inputs)
+    }
+
+    // Ask Last.fm for tracks similar to each seed, as "title by artist" queries
+    async fn generate_candidates(
+        &self,
+        inputs: &[Track],
+        progress: &mut ProgressTracker,
+    ) -> Result<Vec<String>, RunError> {
+        let limiter = &self.app_state.rate_limiter;
+        self.sink
+            .emit(RecommendationEvent::Status {
+                message: "Finding similar tracks...".to_string(),
+            })
+            .await;
+
+        let lastfm_key = env::var("LASTFM_API_KEY")
+            .map_err(|_| RunError::Internal("LASTFM_API_KEY is not set".to_string()))?;
+        let client = reqwest::Client::new();
+        let mut candidate_queries = Vec::new();
+
+        for (i, input) in inputs.iter().enumerate() {
+            self.sink
+                .emit(progress.event(Stage::GenerateCandidates, i, inputs.len(), limiter))
+                .await;
+            // Album and artist seeds share a weight of 1, so each of their tracks asks
+            // for a share of the candidates rather than a full list
+            let limit = ((LASTFM_SIMILAR_LIMIT as f64 * input.weight).ceil() as usize)
+                .clamp(1, LASTFM_SIMILAR_LIMIT);
+            let url = format!(
+                "https://ws.audioscrobbler.com/2.0/?method=track.getsimilar&track={}&artist={}&api_key={}&format=json&limit={}",
+                urlencoding::encode(&input.name),
+                urlencoding::encode(&input.artist),
+                lastfm_key,
+                limit
+            );
+
+            let request = client.get(&url);
+            match self
+                .app_state
+                .upstreams
+                .send(Upstream::LastFm, request)
+                .await
+            {
+                Ok(response) => {
+                    let status = response.status();
+                    if status.is_success() {
+                        if let Ok(res) = response.json::<LastFmSimilar>().await {
+                            for sim_track in res.similartracks.track {
+                                if sim_track.match_score > 0.1 {
+                                    let query =
+                                        format!("{} by {}", sim_track.name, sim_track.artist.name);
+                                    candidate_queries.push(query);
+                                }
+                            }
+                        }
+                    } else {
+                        eprintln!("Last.fm API error: {}", status);
+                        // Don't use fallback data - just continue with fewer results
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Last.fm request error: {}", e);
+                    continue;
+                }
+            }
+        }
+        self.sink
+            .emit(progress.event(
+                Stage::GenerateCandidates,
+                inputs.len(),
+                inputs.len(),
+                limiter,
+            ))
+            .await;
+
+        self.sink
+            .emit(RecommendationEvent::Status {
+                message: format!(
+                    "Found {} similar tracks to process",
+                    candidate_queries.len()
+                ),
+            })
+            .await;
+
+        // If no candidates found, return error
+        if candidate_queries.is_empty() {
+            return Err(RunError::Upstream(
+                "Could not find similar tracks. Please ensure you have a valid Last.fm API key."
+                    .to_string(),
+            ));
+        }
+        Ok(candidate_queries)
+    }
+
+    // Resolve and enrich the candidates in batches, scoring each as it arrives
+    async fn enrich_candidates(
+        &self,
+        seeds: &[TrackId],
+        candidate_queries: &[String],
+        progress: &mut ProgressTracker,
+    ) {
+        let limiter = &self.app_state.rate_limiter;
+        // Seeds and candidates are de-duplicated per song, not per MBID, so remasters
+        // and compilation appearances of a seed (or of each other) are skipped
+        let mut seen = CanonicalIndex::new();
+        for seed in seeds {
+            seen.insert(&seed.canonical_keys);
+        }
+
+        let mut found_count = 0;
+        let mut not_found_count = 0;
+        let batch_size = 10; // Increased for faster processing
+
+        progress.candidates = Some(candidate_queries.len());
+        for (batch_num, chunk) in candidate_queries.chunks(batch_size).enumerate() {
+            self.sink
+                .emit(RecommendationEvent::Status {
+                    message: format!(
+                        "Processing batch {}/{}",
+                        batch_num + 1,
+                        candidate_queries.len().div_ceil(batch_size)
+                    ),
+                })
+                .await;
+            self.sink
+                .emit(progress.event(
+                    Stage::EnrichCandidates,
+                    batch_num * batch_size,
+                    candidate_queries.len(),
+                    limiter,
+                ))
+                .await;
+
+            let batch_ids = match self.work.resolve(chunk, &self.app_state).await {
+                Ok(ids) => ids,
+                Err(_) => continue,
+            };
+            // Audio features and listen counts for the whole batch in a few requests
+            prefetch_track_data(&track_mbids(&batch_ids), &self.app_state).await;
+
+            for id in batch_ids {
+                if !seen.insert(&id.canonical_keys) {
+                    continue;
+                }
+                if id.flags.is_imitation() {
+                    eprintln!(
+                        "Skipping cover/karaoke/tribute candidate: {} by {}",
+                        id.name, id.artist
+                    );
+                    continue;
+                }
+
+                if let Ok(track) = self.work.enrich(&id, &self.app_state).await {
+                    let score = self.add_candidate(track.clone()).await;
+
+                    // Send candidate immediately
+                    self.sink
+                        .emit(RecommendationEvent::Candidate {
+                            track: Box::new(track),
+                            score,
+                        })
+                        .await;
+
+                    found_count += 1;
+                } else {
+                    not_found_count += 1;
+                }
+            }
+        }
+        self.sink
+            .emit(progress.event(
+                Stage::EnrichCandidates,
+                candidate_queries.len(),
+                candidate_queries.len(),
+                limiter,
+            ))
+            .await;
+
+        // Send summary debug info
+        self.sink
+            .emit(RecommendationEvent::Debug {
+                message: format!(
+                    "Summary: {} candidates searched, {} tracks found, {} not found in MusicBrainz",
+                    candidate_queries.len(),
+                    found_count,
+                    not_found_count
+                ),
+                data: None,
+            })
+            .await;
+    }
+
+    // Rank with whatever seeds, feedback and preferences the run ended up with
+    async fn rank(&self, req: &RecommendRequest, progress: &ProgressTracker) {
+        let limiter = &self.app_state.rate_limiter;
+        self.sink
+            .emit(progress.event(Stage::Rank, 0, 1, limiter))
+            .await;
+        let Some(pool) = self.steering.lock().await.as_ref().map(Steering::pool) else {
+            return;
+        };
+        let page = self
+            .app_state
+            .result_pools
+            .insert(pool, req.offset, req.page_size())
+            .await;
+        self.sink
+            .emit(progress.event(Stage::Rank, 1, 1, limiter))
+            .await;
+
+        self.sink
+            .emit(RecommendationEvent::Complete {
+                tracks: page.tracks,
+                total: page.total,
+                next: page.next,
+                seed: page.seed,
+            })
+            .await;
+    }
+}
+
+// Why a run ended without recommendations
+enum RunError {
+    BadInput(String), // Nothing in the request could be used as a seed
+    Upstream(String), // MusicBrainz or Last.fm couldn't supply what the run needed
+    Internal(String),
+}
+
+impl RunError {
+    fn message(&self) -> &str {
+        match self {
+            RunError::BadInput(message)
+            | RunError::Upstream(message)
+            | RunError::Internal(message) => message,
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            RunError::BadInput(_) => StatusCode::BAD_REQUEST,
+            RunError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            RunError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// Original MusicBrainz recommend handler (kept for compatibility)
+#[utoipa::path(
+    post,
+    path = "/mb/recommend",
+    tag = "recommend",
+    summary = "Recommend tracks (blocks until the run completes)",
+    request_body = RecommendRequest,
+    responses(
+        (status = 200, body = Vec<Track>, headers(
+            ("x-total-count" = usize, description = "Tracks in the whole ranking"),
+            ("x-next-token" = String, description = "Continuation token for GET /mb/recommend/more, if there are more"),
+            ("x-recommendation-seed" = String, description = "Seed to send back to reproduce the results")
+        )),
+        (status = 400, description = "None of the requested seeds could be found", body = String),
+        (status = 500, body = String),
+        (status = 502, description = "MusicBrainz or Last.fm failed", body = String)
+    )
+)]
+async fn recommend_musicbrainz_handler(
+    State(app_state): State<Arc<AppState>>,
+    Json(req): Json<RecommendRequest>,
+) -> impl IntoResponse {
+    eprintln!("Recommendation request: {:?}", req);
+
+    // The same engine as the streaming endpoints, collecting only the outcome
+    let collector = CollectorSink::default();
+    let work = WorkCache::default();
+    let steering = TokioMutex::new(None);
+    let engine = RecommendationEngine {
+        app_state,
+        sink: &collector,
+        work: &work,
+        steering: &steering,
+    };
+    // Bad input is the caller's to fix; upstream failures are not
+    if let Err(e) = engine.run(req).await {
+        eprintln!("Recommendation error: {}", e.message());
+        return (e.status(), e.message().to_string()).into_response();
+    }
+
+    match collector.outcome.into_inner().unwrap() {
+        Some(Ok(page)) => page_response(page),
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Recommendation ended without a result".to_string(),
+        )
+            .into_response(),
+    }
+}
+
+// Legacy Spotify recommend handler
+#[utoipa::path(
+    post,
+    path = "/recommend",
+    tag = "spotify",
+    summary = "Recommend from Spotify (legacy)",
+    request_body = RecommendRequest,
+    responses(
+        (status = 200, body = Vec<Track>, headers(("x-recommendation-seed" = String, description = "Seed to send back to reproduce the results"))),
+        (status = 400, body = String),
+        (status = 500, body = String),
+        (status = 501, description = "Spotify credentials are not configured", body = ApiError),
+        (status = 502, description = "Spotify could not be reached", body = ApiError)
+    )
+)]
+async fn recommend_handler(
+    State(app_state): State<Arc<AppState>>,
+    Json(req): Json<RecommendRequest>,
+) -> impl IntoResponse {
+    let Some(token_manager) = app_state.spotify_token_manager.as_ref() else {
+        return spotify_not_configured().await.into_response();
+    };
+    let token = match token_manager.get_token().await {
+        Ok(token) => token,
+        Err(e) => return spotify_unavailable(e).into_response(),
+    };
+    let (offset, limit, seed) = (req.offset, req.page_size(), req.seed_or_random());
+    let upstreams = &app_state.upstreams;
+    let seeds = match resolve_tracks(req.tracks, &token, upstreams).await {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Error resolving tracks: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error resolving tracks: {}", e),
+            )
+                .into_response();
+        }
+    };
+    let mut inputs = Vec::new();
+    for seed in &seeds {
+        if let Some(spotify_id) = &seed.spotify {
+            if let Ok(track) = aggregate_features(spotify_id, &token, upstreams).await {
+                inputs.push(track);
+            }
+        }
+    }
+    if inputs.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No valid input tracks".to_string()).into_response();
+    }
+    let candidates = match generate_candidates(
+        seeds.clone(),
+        &inputs,
+        &req.preferences,
+        &token,
+        seed,
+        upstreams,
+    )
+    .await
+    {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error generating candidates: {}", e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Error generating candidates: {}", e),
+            )
+                .into_response();
+        }
+    };
+    // Fetch features for candidates if needed for scoring
+    let mut candidates_with_features = Vec::new();
+    for cand in candidates {
+        candidates_with_features.push(cand);
+    }
+    // Score
+    let audio_scorer = AudioSimilarityScorer {
+        weights: hashmap! {"default".to_string() => 1.0},
+    };
+    let obscurity_scorer = ObscurityScorer;
+    let mut scored: Vec<(Track, f64)> = candidates_with_features
+        .into_iter()
+        .map(|cand| {
+            let score = 0.6 * audio_scorer.score(&inputs, &cand)
+                + 0.4 * obscurity_scorer.score(&inputs, &cand);
+            (cand, score)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+    let top = scored
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .map(|(t, _)| t)
+        .collect::<Vec<_>>();
+    (
+        StatusCode::OK,
+        [("x-recommendation-seed", seed.to_string())],
+        Json(top),
+    )
+        .into_response()
+}
+
+// MusicBrainz search handler
+#[utoipa::path(
+    get,
+    path = "/mb/search/{query}",
+    tag = "musicbrainz",
+    summary = "Search recordings, ranked by popularity",
+    params(("query" = String, Path, description = "Free text, \"Title - Artist\" or a recording MBID"), SearchParams),
+    responses(
+        (status = 200, body = SearchResponse),
+        (status = 400, description = "Unknown, expired or mismatched cursor", body = String),
+        (status = 500, body = String)
+    )
+)]
+async fn search_musicbrainz_handler(
+    State(app_state): State<Arc<AppState>>,
+    Path(query): Path<String>,
+    Query(params): Query<SearchParams>,
+) -> impl IntoResponse {
+    let limit = params.limit.unwrap_or(10).clamp(1, 100);
+    let include: HashSet<&str> = params
+        .include
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .collect();
+    let wants = |field: &str| include.contains(field) || include.contains("all");
+
+    // Push filters into the Lucene query so pages are filled with matching recordings
+    let mut lucene_query = build_recording_query(&query);
+    if let Some(artist) = &params.artist {
+        lucene_query = format!(
+            "({}) AND artist:\"{}\"",
+            lucene_query,
+            artist.replace('"', "")
+        );
+    }
+    if params.year_from.is_some() || params.year_to.is_some() {
+        lucene_query = format!(
+            "({}) AND firstreleasedate:[{} TO {}]",
+            lucene_query,
+            params
+                .year_from
+                .map(|y| y.to_string())
+                .unwrap_or_else(|| "*".to_string()),
+            params
+                .year_to
+                .map(|y| format!("{}-12-31", y))
+                .unwrap_or_else(|| "*".to_string())
+        );
+    }
+    let release_type = params.release_type.as_deref().map(str::to_lowercase);
+    if let Some(release_type) = &release_type {
+        let field = match release_type.as_str() {
+            "album" | "single" | "ep" | "broadcast" | "other" => "primarytype",
+            _ => "secondarytype",
+        };
+        lucene_query = format!("({}) AND {}:\"{}\"", lucene_query, field, release_type);
+    }
+
+    let (offset, mut seen) = match &params.cursor {
+        Some(id) => match app_state.search_cursors.lock().await.get(id) {
+            Some(cursor) if cursor.query == lucene_query => (cursor.offset, cursor.seen),
+            Some(_) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Cursor belongs to a different search".to_string(),
+                )
+                    .into_response()
+            }
+            None => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    "Unknown or expired cursor".to_string(),
+                )
+                    .into_response()
+            }
+        },
+        None => (params.offset.unwrap_or(0), HashSet::new()),
+    };
+
+    // Lucene only approximates the filters (dates and types are per release), so re-check
+    let matches_filters = |rec: &MusicBrainzRecording| {
+        let flags = recording_flags(rec);
+        if (params.exclude_live && flags.live) || (params.exclude_karaoke && flags.karaoke) {
+            return false;
+        }
+        if params.year_from.is_some() || params.year_to.is_some() {
+            let Some(year) = release_year(rec.first_release_date.as_deref()) else {
+                return false;
+            };
+            if params.year_from.is_some_and(|from| year < from)
+                || params.year_to.is_some_and(|to| year > to)
+            {
+                return false;
+            }
+        }
+        if let Some(release_type) = &release_type {
+            let matches_type = rec.releases.iter().flatten().any(|release| {
+                release.release_group.as_ref().is_some_and(|rg| {
+                    rg.primary_type
+                        .as_deref()
+                        .is_some_and(|t| t.eq_ignore_ascii_case(release_type))
+                        || rg
+                            .secondary_types
+                            .iter()
+                            .any(|t| t.eq_ignore_ascii_case(release_type))
+                })
+            });
+            if !matches_type {
+                return false;
+            }
+        }
+        true
+    };
+
+    // Versions of the same song collapse into one result, and the filters and songs
+    // shown on earlier pages drop rows, so read upstream pages until `limit` songs are
+    // filled. Each kept row remembers its upstream position, for the next page's offset.
+    let mut recordings: Vec<MusicBrainzRecording> = Vec::new();
+    let mut positions: Vec<usize> = Vec::new();
+    let mut total = 0;
+    let mut fetched = 0;
+    for _ in 0..SEARCH_MAX_PAGES {
+        let page = match search_musicbrainz_page(
+            &lucene_query,
+            SEARCH_PAGE_SIZE,
+            offset + fetched,
+            &app_state,
+        )
+        .await
+        {
+            Ok(page) => page,
+            Err(e) if fetched == 0 => {
+                return (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Search error: {}", e),
+                )
+                    .into_response()
+            }
+            Err(e) => {
+                eprintln!("Search error past offset {}: {}", offset + fetched, e);
+                break;
+            }
+        };
+        total = page.count.unwrap_or(0);
+        let page_len = page.recordings.len();
+        for rec in page.recordings {
+            if matches_filters(&rec) {
+                positions.push(offset + fetched);
+                recordings.push(rec);
+            }
+            fetched += 1;
+        }
+        if page_len == 0
+            || offset + fetched >= total
+            || unseen_groups(&recordings, &seen).len() > limit
+        {
+            break;
+        }
+    }
+
+    let (recordings, next_offset) = take_songs(recordings, &positions, &seen, limit);
+    let next_offset = next_offset.or(Some(offset + fetched).filter(|next| *next < total));
+
+    // The next page skips every version of the songs on this one and the ones before
+    let next_cursor = match next_offset {
+        Some(next_offset) => {
+            seen.extend(recordings.iter().flat_map(canonical_keys));
+            let id = format!("{:016x}", rand::Rng::gen::<u64>(&mut thread_rng()));
+            app_state.search_cursors.lock().await.insert(
+                id.clone(),
+                SearchCursor {
+                    query: lucene_query.clone(),
+                    offset: next_offset,
+                    seen,
+                },
+            );
+            Some(id)
+        }
+        None => None,
+    };
+
+    // Collect all MBIDs for popularity lookup
+    let mbids: Vec<String> = recordings.iter().map(|r| r.id.clone()).collect();
+
+    // Fetch real listen counts from ListenBrainz
+    let counts_map = app_state.popularity.counts(&mbids).await;
+
+    // Group versions of the same song and keep only the canonical recording of each
+    // (result, primary release for cover art, first release date for sorting)
+    let mut results: Vec<(SearchResult, Option<String>, String)> = Vec::new();
+
+    for group in group_recordings(&recordings) {
+        let Some(rec) = choose_canonical(group.iter().map(|&i| &recordings[i])) else {
+            continue;
+        };
+
+        let artists = artist_credits(rec);
+
+        // Listens are often spread over remasters, so use the group's most popular version
+        let counts = group
+            .iter()
+            .filter_map(|&i| counts_map.get(&recordings[i].id).copied())
+            .max_by_key(|counts| counts.listens);
+        let popularity = match counts {
+            Some(counts) => app_state.popularity.score(counts.listens).await,
+            None => 0,
+        };
+
+        // Cover art needs the release even when the release itself wasn't asked for
+        let mut metadata = track_metadata(rec);
+        let release_id = metadata.release.as_ref().map(|release| release.id.clone());
+        if !wants("duration") {
+            metadata.duration_ms = None;
+        }
+        if !wants("release_date") {
+            metadata.first_release_date = None;
+        }
+        if !wants("release") {
+            metadata.release = None;
+            metadata.release_group = None;
+        }
+        let track = Track {
+            id: rec.id.clone(),
+            name: rec.title.clone(),
+            artist: display_credit(&artists),
+            artists,
+            features: BTreeMap::new(),
+            popularity,
+            listen_count: counts.map(|counts| counts.listens),
+            user_count: counts.map(|counts| counts.users),
+            album_art: None, // Filled in below when cover art is requested
+            flags: recording_flags(rec),
+            metadata,
+            tag_vector: BTreeMap::new(),
+            harmonic_key: None,
+            weight: 1.0,
+        };
+        results.push((
+            SearchResult {
+                track,
+                features_available: None,
+            },
+            release_id,
+            rec.first_release_date
+                .clone()
+                .filter(|date| !date.is_empty())
+                .unwrap_or_else(|| "9999".to_string()),
+        ));
+    }
+
+    match params.sort.as_deref() {
+        Some("relevance") => {} // MusicBrainz order
+        Some("date") => results.sort_by(|a, b| a.2.cmp(&b.2)),
+        // Sort by popularity (descending)
+        _ => results.sort_by_key(|(result, _, _)| std::cmp::Reverse(result.track.popularity)),
+    }
+
+    if wants("cover_art") {
+        let upstreams = &app_state.upstreams;
+        let covers =
+            futures::future::join_all(results.iter().map(|(_, release_id, _)| async move {
+                match release_id {
+                    Some(id) => fetch_release_cover_art(id, upstreams).await,
+                    None => None,
+                }
+            }))
+            .await;
+        for ((result, _, _), cover) in results.iter_mut().zip(covers) {
+            result.track.album_art = cover;
+        }
+    }
+
+    if wants("features") {
+        let ids: Vec<String> = results.iter().map(|(r, _, _)| r.track.id.clone()).collect();
+        // Unknown (None) for recordings whose chunk failed
+        let counts = fetch_acousticbrainz_counts(&ids, &app_state.upstreams).await;
+        for (result, _, _) in results.iter_mut() {
+            result.features_available = counts.get(&result.track.id).map(|&count| count > 0);
+        }
+    }
+
+    for (result, _, _) in &results {
+        app_state
+            .suggest_cache
+            .remember(Suggestion::new(
+                result.track.id.clone(),
+                result.track.name.clone(),
+                result.track.artist.clone(),
+            ))
+            .await;
+    }
+
+    let audio_features_available = results
+        .iter()
+        .any(|(result, _, _)| result.features_available == Some(true));
+    let tracks: Vec<SearchResult> = results.into_iter().map(|(result, _, _)| result).collect();
+
+    // Add metadata about features availability
+    let response = SearchResponse {
+        tracks,
+        pagination: Pagination {
+            offset,
+            limit,
+            upstream_total: total,
+            next_cursor,
+        },
+        metadata: SearchMetadata {
+            source: "MusicBrainz + ListenBrainz",
+            audio_features_available,
+            popularity_data: "Real listen counts from ListenBrainz",
+            note: "Use track IDs with /mb/recommend for audio features via AcousticBrainz",
+        },
+    };
+
+    Json(response).into_response()
+}
+
+// Upstream rows per search request, and how many requests one page may take
+const SEARCH_PAGE_SIZE: usize = 50;
+const SEARCH_MAX_PAGES: usize = 4;
+
+const SEARCH_CURSOR_TTL: Duration = Duration::from_secs(30 * 60);
+const SEARCH_CURSOR_CAPACITY: usize = 10_000;
+
+// Where a search continues: the next upstream row and the canonical keys of every
+// song already returned, so later versions of those songs aren't shown again
+#[derive(Clone)]
+struct SearchCursor {
+    query: String, // Lucene query the cursor was issued for
+    offset: usize,
+    seen: HashSet<String>,
+}
+
+// Groups of versions of the same song, minus songs returned on earlier pages
+fn unseen_groups(recordings: &[MusicBrainzRecording], seen: &HashSet<String>) -> Vec<Vec<usize>> {
+    group_recordings(recordings)
+        .into_iter()
+        .filter(|group| {
+            !group
+                .iter()
+                .flat_map(|&i| canonical_keys(&recordings[i]))
+                .any(|key| seen.contains(&key))
+        })
+        .collect()
+}
+
+// Keep the rows of the first `limit` unseen songs. The next page starts at the first
+// row of the song after them, or None if the rows ran out first.
+fn take_songs(
+    recordings: Vec<MusicBrainzRecording>,
+    positions: &[usize],
+    seen: &HashSet<String>,
+    limit: usize,
+) -> (Vec<MusicBrainzRecording>, Option<usize>) {
+    let mut groups = unseen_groups(&recordings, seen);
+    let next_offset = groups.get(limit).map(|next| positions[next[0]]);
+    groups.truncate(limit);
+    let kept: HashSet<usize> = groups.iter().flatten().copied().collect();
+    let recordings = recordings
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| kept.contains(i))
+        .map(|(_, rec)| rec)
+        .collect();
+    (recordings, next_offset)
+}
+
+// Type-ahead handler: answers from cache when possible, coalesces identical
+// in-flight lookups and never waits longer than the latency budget
+const SUGGEST_LATENCY_BUDGET: Duration = Duration::from_millis(1200);
+
+#[utoipa::path(
+    get,
+    path = "/mb/suggest",
+    tag = "musicbrainz",
+    summary = "Type-ahead suggestions for the track input",
+    params(SuggestParams),
+    responses((status = 200, body = SuggestResponse))
+)]
+async fn suggest_musicbrainz_handler(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<SuggestParams>,
+) -> impl IntoResponse {
+    let query = normalize_text(&params.q);
+    let limit = params.limit.unwrap_or(8).clamp(1, 10);
+
+    if query.chars().count() < 2 {
+        return Json(SuggestResponse {
+            suggestions: Vec::new(),
+            source: "none",
+        })
+        .into_response();
+    }
+
+    let cache = &app_state.suggest_cache;
+    if let Some(mut suggestions) = cache.get(&query).await {
+        suggestions.truncate(limit);
+        return Json(SuggestResponse {
+            suggestions,
+            source: "cache",
+        })
+        .into_response();
+    }
+
+    let local = cache.local_matches(&query, limit).await;
+
+    // Don't queue behind recommendation runs for a keystroke
+    if app_state.rate_limiter.is_busy() {
+        return Json(SuggestResponse {
+            suggestions: local,
+            source: "cache",
+        })
+        .into_response();
+    }
+
+    let lookup = {
+        let mut in_flight = cache.in_flight.lock().await;
+        in_flight
+            .entry(query.clone())
+            .or_insert_with(|| {
+                // Spawned so the lookup still fills the cache if every caller gives up
+                let state = app_state.clone();
+                let key = query.clone();
+                let handle = tokio::spawn(async move {
+                    let result = match fetch_suggestions(&key, &state).await {
+                        Ok(suggestions) => {
+                            state.suggest_cache.store(&key, suggestions.clone()).await;
+                            Some(suggestions)
+                        }
+                        Err(e) => {
+                            eprintln!("Suggestion lookup failed for '{}': {}", key, e);
+                            None
+                        }
+                    };
+                    // Only after storing, so a caller arriving in between hits the cache
+                    // instead of starting a second lookup
+                    state.suggest_cache.in_flight.lock().await.remove(&key);
+                    result
+                });
+                async move { handle.await.ok().flatten() }.boxed().shared()
+            })
+            .clone()
+    };
+
+    match tokio::time::timeout(SUGGEST_LATENCY_BUDGET, lookup).await {
+        Ok(Some(mut suggestions)) => {
+            suggestions.truncate(limit);
+            Json(SuggestResponse {
+                suggestions,
+                source: "musicbrainz",
+            })
+                .into_response()
+        }
+        _ => Json(SuggestResponse {
+            suggestions: local,
+            source: "cache",
+        })
+        .into_response(),
+    }
+}
+
+// Legacy Spotify search handler
+#[utoipa::path(
+    get,
+    path = "/search/{query}",
+    tag = "spotify",
+    summary = "Search Spotify (legacy)",
+    params(("query" = String, Path)),
+    responses(
+        (status = 200, description = "Spotify search response", body = Object),
+        (status = 501, description = "Spotify credentials are not configured", body = ApiError),
+        (status = 502, description = "Spotify could not be reached", body = ApiError)
+    )
+)]
+async fn search_handler(
+    State(app_state): State<Arc<AppState>>,
+    Path(query): Path<String>,
+) -> impl IntoResponse {
+    let Some(token_manager) = app_state.spotify_token_manager.as_ref() else {
+        return spotify_not_configured().await.into_response();
+    };
+    let token = match token_manager.get_token().await {
+        Ok(token) => token,
+        Err(e) => return spotify_unavailable(e).into_response(),
+    };
+    let client = reqwest::Client::new();
+    let url = format!(
+        "https://api.spotify.com/v1/search?q={}&type=track&limit=10",
+        urlencoding::encode(&query)
+    );
+    let request = client
+        .get(&url)
+        .header(AUTHORIZATION, format!("Bearer {}", token));
+    let res = match app_state
+        .upstreams
+        .send(Upstream::Spotify, request)
+        .await
+        .and_then(|response| response.error_for_status())
+    {
+        Ok(response) => response.json::<SpotifySearchResponse>().await,
+        Err(e) => Err(e),
+    };
+    match res {
+        Ok(res) => (StatusCode::OK, Json(res)).into_response(),
+        Err(e) => spotify_unavailable(e.into()).into_response(),
+    }
+}
+
+// Recompute the popularity reference from the listen counts seen so far
+#[utoipa::path(
+    post,
+    path = "/mb/popularity/refresh",
+    tag = "musicbrainz",
+    summary = "Recompute the listen-count percentile scale",
+    responses(
+        (status = 200, description = "Summary of the refreshed scale", body = Object),
+        (status = 500, body = String)
+    )
+)]
+async fn refresh_popularity_handler(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    match app_state.popularity.refresh().await {
+        Ok(summary) => Json(summary).into_response(),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Popularity refresh failed: {}", e),
+        )
+            .into_response(),
+    }
+}
+
+// Upload limit for /mb/analysis (a few minutes of FLAC)
+const MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct AnalysisParams {
+    mbid: Option<String>,   // Recording to attach the features to
+    format: Option<String>, // File extension hint, e.g. "mp3"
+}
+
+#[derive(Serialize, ToSchema)]
+struct AnalysisResponse {
+    mbid: Option<String>,
+    features: HashMap<String, f64>,
+}
+
+// Analyse an uploaded audio file (raw request body). With an MBID, the features
+// fill in whatever AcousticBrainz lacks for that recording in later recommendations.
+#[utoipa::path(
+    post,
+    path = "/mb/analysis",
+    tag = "musicbrainz",
+    summary = "Analyse an uploaded audio file",
+    params(AnalysisParams),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "Raw audio (FLAC, MP3, Ogg or WAV)"),
+    responses(
+        (status = 200, body = AnalysisResponse),
+        (status = 400, body = String),
+        (status = 422, body = String)
+    )
+)]
+async fn analyse_upload_handler(
+    State(app_state): State<Arc<AppState>>,
+    Query(params): Query<AnalysisParams>,
+    body: Bytes,
+) -> impl IntoResponse {
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Empty audio upload".to_string()).into_response();
+    }
+    if let Some(mbid) = &params.mbid {
+        if !is_mbid(mbid) {
+            return (StatusCode::BAD_REQUEST, format!("Invalid MBID: {}", mbid)).into_response();
+        }
+    }
+
+    let format = params.format.clone();
+    let result =
+        tokio::task::spawn_blocking(move || analysis::analyse_bytes(body, format.as_deref())).await;
+    let features = match result {
+        Ok(Ok(result)) => result.features(),
+        Ok(Err(e)) => {
+            return (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("Could not analyse audio: {}", e),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Analysis failed: {}", e),
+            )
+                .into_response()
+        }
+    };
+
+    if let Some(mbid) = &params.mbid {
+        // AcousticBrainz values (if any) take precedence over the local estimate
+        prefetch_acousticbrainz_features(std::slice::from_ref(mbid), &app_state).await;
+        let mut cache = app_state.feature_cache.lock().await;
+        let mut entry = cache.get(mbid).unwrap_or_default();
+        for (key, value) in &features {
+            entry.features.entry(key.clone()).or_insert(*value);
+        }
+        cache.insert(mbid.clone(), entry);
     }
-    // Shuffle and truncate
-    candidates.shuffle(&mut thread_rng());
-    candidates.truncate(50);
-    Ok(candidates)
+
+    Json(AnalysisResponse {
+        mbid: params.mbid,
+        features,
+    })
+    .into_response()
 }
 
-// Scoring trait
-trait ScoringFunction {
-    fn score(&self, inputs: &Vec<Track>, candidate: &Track) -> f64;
+// Operations: liveness, readiness and upstream status for load balancers,
+// orchestrators and on-call. Served at the root, outside API versioning.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+// /status is unauthenticated, so probes run at most this often, and skip upstreams
+// whose real traffic is at least this recent; in between it reports what it knows
+const PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Any HTTP answer from these means the service is reachable
+fn probe_url(upstream: Upstream) -> &'static str {
+    match upstream {
+        Upstream::MusicBrainz => "https://musicbrainz.org/ws/2/",
+        Upstream::LastFm => "https://ws.audioscrobbler.com/2.0/",
+        Upstream::ListenBrainz => "https://api.listenbrainz.org/1/",
+        Upstream::AcousticBrainz => "https://acousticbrainz.org/api/v1/",
+        Upstream::CoverArtArchive => "https://coverartarchive.org/",
+        Upstream::Genius => "https://api.genius.com/",
+        Upstream::Spotify => "https://api.spotify.com/v1/",
+    }
 }
 
-struct AudioSimilarityScorer {
-    weights: HashMap<String, f64>,
+fn credentials(upstream: Upstream) -> &'static str {
+    let vars: &[&str] = match upstream {
+        Upstream::LastFm => &["LASTFM_API_KEY"],
+        Upstream::Genius => &["GENIUS_API_KEY"],
+        Upstream::Spotify => &["SPOTIFY_CLIENT_ID", "SPOTIFY_CLIENT_SECRET"],
+        _ => return "not_required",
+    };
+    if vars
+        .iter()
+        .all(|var| env::var(var).is_ok_and(|value| !value.is_empty()))
+    {
+        "configured"
+    } else {
+        "missing"
+    }
 }
 
-impl ScoringFunction for AudioSimilarityScorer {
-    fn score(&self, inputs: &Vec<Track>, candidate: &Track) -> f64 {
-        // Average input features
-        let mut avg = HashMap::new();
-        for key in inputs[0].features.keys() {
-            let sum: f64 = inputs
-                .iter()
-                .map(|t| *t.features.get(key).unwrap_or(&0.0))
-                .sum();
-            avg.insert(key.clone(), sum / inputs.len() as f64);
-        }
-        let a_vec: Vec<f64> = avg.values().cloned().collect();
-        let b_vec: Vec<f64> = candidate.features.values().cloned().collect();
-        cosine_similarity(&a_vec, &b_vec, &self.weights)
+async fn probe_upstream(app_state: &AppState, upstream: Upstream) {
+    let last_outcome = app_state.upstreams.snapshot(upstream).last_outcome();
+    if last_outcome.is_some_and(|at| unix_now().saturating_sub(at) < PROBE_INTERVAL.as_secs()) {
+        return;
     }
+    // A MusicBrainz probe only takes a free rate limiter slot and never queues for
+    // one; while real traffic holds the limiter, that traffic tells us enough
+    if upstream == Upstream::MusicBrainz && !app_state.rate_limiter.try_acquire() {
+        return;
+    }
+    let request = reqwest::Client::new()
+        .get(probe_url(upstream))
+        .header(
+            "User-Agent",
+            "NextTrack/0.1.0 (https://github.com/leebenson/nexttrack)",
+        )
+        .timeout(PROBE_TIMEOUT);
+    // Probes carry no credentials, so an auth failure still means the service answered
+    let result = request.send().await;
+    app_state.upstreams.record(upstream, &result, false);
 }
 
-// Other scorers
-struct ObscurityScorer;
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "operations",
+    summary = "Liveness: the process is up and serving",
+    responses((status = 200, body = String, example = "ok"))
+)]
+async fn healthz_handler() -> impl IntoResponse {
+    "ok"
+}
 
-impl ScoringFunction for ObscurityScorer {
-    fn score(&self, _inputs: &Vec<Track>, candidate: &Track) -> f64 {
-        1.0 - (candidate.popularity as f64 / 100.0)
+#[derive(Serialize, ToSchema)]
+struct ReadinessResponse {
+    ready: bool,
+    checks: Vec<ReadinessCheck>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ReadinessCheck {
+    name: &'static str,
+    ok: bool,
+    detail: Option<String>,
+}
+
+// Ready when the configuration is usable and the on-disk stores are available.
+// Only keys something needs are checked: Last.fm for recommendations, Spotify when
+// its legacy routes are on. Genius is optional (sentiment defaults to neutral).
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "operations",
+    summary = "Readiness: configuration and on-disk stores",
+    responses(
+        (status = 200, body = ReadinessResponse),
+        (status = 503, description = "A check failed", body = ReadinessResponse)
+    )
+)]
+async fn readyz_handler(State(app_state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut checks = vec![ReadinessCheck {
+        name: "lastfm_api_key",
+        ok: credentials(Upstream::LastFm) == "configured",
+        detail: None,
+    }];
+    if app_state.spotify_token_manager.is_some() {
+        checks.push(ReadinessCheck {
+            name: "spotify_credentials",
+            ok: credentials(Upstream::Spotify) == "configured",
+            detail: None,
+        });
+    }
+
+    let probe = app_state.jobs.dir.join(".readyz");
+    let jobs_dir = async {
+        tokio::fs::create_dir_all(&app_state.jobs.dir).await?;
+        tokio::fs::write(&probe, b"").await?;
+        tokio::fs::remove_file(&probe).await
     }
+    .await;
+    checks.push(ReadinessCheck {
+        name: "jobs_dir",
+        ok: jobs_dir.is_ok(),
+        detail: jobs_dir.err().map(|e| e.to_string()),
+    });
+
+    if let Ok(path) = env::var("FEATURE_STORE_PATH") {
+        let ok = app_state.feature_store.is_some();
+        checks.push(ReadinessCheck {
+            name: "feature_store",
+            ok,
+            detail: (!ok).then(|| format!("{} could not be opened", path)),
+        });
+    }
+
+    let ready = checks.iter().all(|check| check.ok);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessResponse { ready, checks }))
 }
 
-// SSE event types for streaming
-#[derive(Serialize)]
-#[serde(tag = "type")]
-enum RecommendationEvent {
-    Status { message: String },
-    Candidate { track: Track, score: f64 },
-    Complete { tracks: Vec<Track> },
-    Error { message: String },
-    Debug { message: String, data: Option<serde_json::Value> },
+#[derive(Deserialize, IntoParams)]
+struct StatusParams {
+    #[serde(default)]
+    probe: bool, // Check every upstream now instead of reporting recent traffic
 }
 
-// Streaming MusicBrainz recommend handler using channels
-async fn recommend_musicbrainz_stream_handler(
+#[derive(Serialize, ToSchema)]
+struct StatusResponse {
+    upstreams: BTreeMap<Upstream, UpstreamStatus>,
+    rate_limiter: RateLimiterStatus, // MusicBrainz
+}
+
+#[derive(Serialize, ToSchema)]
+struct RateLimiterStatus {
+    queue_depth: usize,
+    seconds_per_request: f64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct UpstreamStatus {
+    reachable: Option<bool>, // Unknown until the upstream has been used or probed
+    credentials: &'static str,
+    #[serde(flatten)]
+    health: UpstreamHealth,
+}
+
+#[utoipa::path(
+    get,
+    path = "/status",
+    tag = "operations",
+    summary = "Upstream health and MusicBrainz rate limiter depth",
+    description = "Reports recent traffic to each upstream. With probe=true it checks the upstreams itself, at most once a minute.",
+    params(StatusParams),
+    responses((status = 200, body = StatusResponse))
+)]
+async fn status_handler(
     State(app_state): State<Arc<AppState>>,
-    Json(req): Json<RecommendRequest>,
+    Query(params): Query<StatusParams>,
 ) -> impl IntoResponse {
-    use tokio::sync::mpsc;
+    if params.probe && app_state.upstreams.start_probe() {
+        futures::future::join_all(
+            UPSTREAMS
+                .iter()
+                .map(|upstream| probe_upstream(&app_state, *upstream)),
+        )
+        .await;
+    }
 
-    let (tx, rx) = mpsc::channel::<Result<Event, axum::Error>>(10);
+    let upstreams: BTreeMap<Upstream, UpstreamStatus> = UPSTREAMS
+        .iter()
+        .map(|&upstream| {
+            let health = app_state.upstreams.snapshot(upstream);
+            let status = UpstreamStatus {
+                reachable: health.reachable(),
+                credentials: credentials(upstream),
+                health,
+            };
+            (upstream, status)
+        })
+        .collect();
+    let limiter = &app_state.rate_limiter;
+    Json(StatusResponse {
+        upstreams,
+        rate_limiter: RateLimiterStatus {
+            queue_depth: limiter.queue_depth(),
+            seconds_per_request: limiter.seconds_per_request(),
+        },
+    })
+}
 
-    // Spawn the recommendation task
-    tokio::spawn(async move {
-        let result = process_recommendations(app_state, req, tx.clone()).await;
-        if let Err(e) = result {
-            let _ = tx
-                .send(Ok(Event::default()
-                    .json_data(RecommendationEvent::Error {
-                        message: format!("Processing error: {}", e),
-                    })
-                    .unwrap()))
-                .await;
+// OpenAPI document, generated from the handlers' annotations and the API types.
+// Served at /openapi.json and rendered at /docs.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "NextTrack API",
+        description = "Music recommendations from MusicBrainz, ListenBrainz, AcousticBrainz and Last.fm"
+    ),
+    paths(
+        search_musicbrainz_handler,
+        suggest_musicbrainz_handler,
+        refresh_popularity_handler,
+        analyse_upload_handler,
+        recommend_musicbrainz_handler,
+        recommend_musicbrainz_stream_handler,
+        recommend_stream_resume_handler,
+        cancel_recommendation_handler,
+        recommend_session_handler,
+        recommend_more_handler,
+        recommend_batch_handler,
+        recommend_batch_stream_handler,
+        create_job_handler,
+        get_job_handler,
+        search_handler,
+        recommend_handler,
+        healthz_handler,
+        readyz_handler,
+        status_handler,
+    ),
+    servers((url = "/v1", description = "Current API version")),
+    // Not referenced by any response body, so listed explicitly
+    components(schemas(SessionMessage)),
+    tags(
+        (name = "musicbrainz", description = "Search, suggestions and data maintenance"),
+        (name = "recommend", description = "Recommendation runs: blocking, streamed or interactive"),
+        (name = "jobs", description = "Background recommendation jobs"),
+        (name = "spotify", description = "Legacy routes, only with Spotify credentials"),
+        (name = "operations", description = "Health and status, served at the root")
+    ),
+    modifiers(&OperationsAtRoot)
+)]
+struct ApiDoc;
+
+// The operations routes live outside /v1, so they override the document's server
+struct OperationsAtRoot;
+
+impl Modify for OperationsAtRoot {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        for path in ["/healthz", "/readyz", "/status"] {
+            if let Some(item) = openapi.paths.paths.get_mut(path) {
+                item.servers = Some(vec![utoipa::openapi::Server::new("/")]);
+            }
         }
-    });
-
-    let stream = tokio_stream::wrappers::ReceiverStream::new(rx);
-    Sse::new(stream)
+    }
 }
 
-// Helper function to process recommendations and send events
-async fn process_recommendations(
-    app_state: Arc<AppState>,
-    req: RecommendRequest,
-    tx: tokio::sync::mpsc::Sender<Result<Event, axum::Error>>,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    // Send initial status
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Status {
-            message: "Starting recommendation process...".to_string(),
-        },
-    )?))
-    .await?;
+async fn openapi_handler() -> impl IntoResponse {
+    Json(ApiDoc::openapi())
+}
 
-    // Resolve input tracks
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Status {
-            message: "Searching for input tracks...".to_string(),
-        },
-    )?))
-    .await?;
+// API versioning: every route is served under /v1. The original unversioned
+// paths still work for deployed clients, but their responses carry a
+// Deprecation header and a Link to the /v1 successor.
+const API_VERSION_PREFIX: &str = "/v1";
 
-    let seeds = resolve_tracks_musicbrainz(req.tracks, &app_state.rate_limiter).await?;
+// Structured error for clients that branch on `error` rather than the status text
+#[derive(Serialize, ToSchema)]
+struct ApiError {
+    error: &'static str,
+    message: String,
+}
 
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Status {
-            message: format!("Found {} input tracks", seeds.len()),
-        },
-    )?))
-    .await?;
+// Legacy Spotify routes without SPOTIFY_CLIENT_ID/SPOTIFY_CLIENT_SECRET
+async fn spotify_not_configured() -> impl IntoResponse {
+    (
+        StatusCode::NOT_IMPLEMENTED,
+        Json(ApiError {
+            error: "spotify_not_configured",
+            message: "Spotify credentials are not configured on this server; use the /mb endpoints"
+                .to_string(),
+        }),
+    )
+}
 
-    // Process seeds
-    let mut inputs = Vec::new();
-    for (i, seed) in seeds.iter().enumerate() {
-        tx.send(Ok(Event::default().json_data(
-            RecommendationEvent::Status {
-                message: format!("Processing track {}/{}: {}", i + 1, seeds.len(), seed.name),
-            },
-        )?))
-        .await?;
-        
-        // Send debug info about selected track
-        tx.send(Ok(Event::default().json_data(
-            RecommendationEvent::Debug {
-                message: format!("Selected: {} by {}", seed.name, seed.artist),
-                data: None,
-            },
-        )?))
-        .await?;
+// Legacy Spotify routes when Spotify fails or returns something unexpected
+fn spotify_unavailable(e: Box<dyn std::error::Error + Send + Sync>) -> impl IntoResponse {
+    eprintln!("Spotify request failed: {}", e);
+    (
+        StatusCode::BAD_GATEWAY,
+        Json(ApiError {
+            error: "spotify_unavailable",
+            message: format!("Spotify request failed: {}", e),
+        }),
+    )
+}
 
-        if let Ok(track) = aggregate_features_musicbrainz(&seed, &app_state.rate_limiter).await {
-            inputs.push(track);
-        }
+async fn deprecated_path(request: Request, next: Next) -> Response {
+    let successor = format!("{}{}", API_VERSION_PREFIX, request.uri().path());
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert("deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+    {
+        headers.insert(LINK, link);
     }
+    response
+}
 
-    if inputs.is_empty() {
-        tx.send(Ok(Event::default().json_data(
-            RecommendationEvent::Error {
-                message: "No valid input tracks found".to_string(),
-            },
-        )?))
-        .await?;
-        return Ok(());
+fn api_routes(spotify_enabled: bool) -> Router<Arc<AppState>> {
+    let routes = Router::new()
+        // MusicBrainz routes (always available)
+        .route("/mb/search/{query}", get(search_musicbrainz_handler))
+        .route("/mb/suggest", get(suggest_musicbrainz_handler))
+        .route("/mb/popularity/refresh", post(refresh_popularity_handler))
+        .route(
+            "/mb/analysis",
+            post(analyse_upload_handler).layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/mb/recommend", post(recommend_musicbrainz_handler))
+        .route(
+            "/mb/recommend/stream",
+            post(recommend_musicbrainz_stream_handler),
+        )
+        .route(
+            "/mb/recommend/stream/{run_id}",
+            get(recommend_stream_resume_handler),
+        )
+        .route(
+            "/mb/recommend/{run_id}",
+            delete(cancel_recommendation_handler),
+        )
+        .route("/mb/recommend/session", get(recommend_session_handler))
+        .route("/mb/recommend/more", get(recommend_more_handler))
+        .route("/mb/recommend/batch", post(recommend_batch_handler))
+        .route(
+            "/mb/recommend/batch/stream",
+            post(recommend_batch_stream_handler),
+        )
+        .route("/jobs/recommend", post(create_job_handler))
+        .route("/jobs/{job_id}", get(get_job_handler));
+
+    // Legacy Spotify routes (if credentials available)
+    if spotify_enabled {
+        routes
+            .route("/search/{query}", get(search_handler))
+            .route("/recommend", post(recommend_handler))
+    } else {
+        routes
+            .route("/search/{query}", get(spotify_not_configured))
+            .route("/recommend", post(spotify_not_configured))
     }
+}
 
-    // Get similar tracks from Last.fm
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Status {
-            message: "Finding similar tracks...".to_string(),
-        },
-    )?))
-    .await?;
+// Main
+#[tokio::main]
+async fn main() {
+    // Check for required environment variables
+    let use_spotify =
+        env::var("SPOTIFY_CLIENT_ID").is_ok() && env::var("SPOTIFY_CLIENT_SECRET").is_ok();
+    // Missing keys don't stop the server; /readyz reports the required ones
+    if env::var("LASTFM_API_KEY").is_err() {
+        eprintln!("LASTFM_API_KEY is not set - recommendations will fail until it is");
+    }
+    if env::var("GENIUS_API_KEY").is_err() {
+        println!("No Genius API key - lyrics sentiment defaults to neutral");
+    }
 
-    let lastfm_key = env::var("LASTFM_API_KEY")?;
-    let client = reqwest::Client::new();
-    let mut candidate_queries = Vec::new();
+    println!("Starting NextTrack API...");
+    println!("MusicBrainz endpoints (under /v1; unversioned paths are deprecated):");
+    println!("  - GET  /v1/mb/search/:query");
+    println!("  - GET  /v1/mb/suggest?q=...");
+    println!("  - POST /v1/mb/recommend");
+    println!("  - POST /v1/mb/recommend/stream (Server-Sent Events)");
+    println!("  - GET  /v1/mb/recommend/stream/:run_id (resume with Last-Event-ID)");
+    println!("  - GET  /v1/mb/recommend/more?token=... (next page of results)");
+    println!("  - DELETE /v1/mb/recommend/:run_id (cancel a streaming run)");
+    println!("  - GET  /v1/mb/recommend/session (WebSocket, steerable run)");
+    println!("  - POST /v1/mb/recommend/batch (several seed sets; /batch/stream multiplexes)");
+    println!("  - POST /v1/jobs/recommend (background job, returns a job id)");
+    println!("  - GET  /v1/jobs/:job_id");
+    println!("  - POST /v1/mb/analysis?mbid=... (raw audio body)");
+    println!("  - POST /v1/mb/popularity/refresh");
+    println!("API docs: /docs (OpenAPI document at /openapi.json)");
+    println!("Operations: /healthz, /readyz, /status (?probe=true checks every upstream)");
 
-    for input in &inputs {
-        let url = format!(
-            "https://ws.audioscrobbler.com/2.0/?method=track.getsimilar&track={}&artist={}&api_key={}&format=json&limit=20",
-            urlencoding::encode(&input.name),
-            urlencoding::encode(&input.artist),
-            lastfm_key
+    // Create app state
+    let upstreams = Arc::new(UpstreamMonitor::default());
+    let spotify_token_manager = if use_spotify {
+        let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap();
+        let client_secret = env::var("SPOTIFY_CLIENT_SECRET").unwrap();
+        println!(
+            "Spotify credentials found - enabling legacy endpoints: /search/:query, /recommend"
         );
+        Some(Arc::new(TokenManager::new(
+            client_id,
+            client_secret,
+            upstreams.clone(),
+        )))
+    } else {
+        println!("No Spotify credentials - only MusicBrainz endpoints available");
+        None
+    };
 
-        match client.get(&url).send().await {
-            Ok(response) => {
-                let status = response.status();
-                eprintln!("Last.fm response status: {}", status);
-
-                if status.is_success() {
-                    if let Ok(res) = response.json::<LastFmSimilar>().await {
-                        for sim_track in res.similartracks.track {
-                            if sim_track.match_score > 0.1 {
-                                let query = format!("{} by {}", sim_track.name, sim_track.artist.name);
-                                candidate_queries.push(query);
-                            }
-                        }
-                    }
-                } else {
-                    eprintln!("Last.fm API error: {}", status);
-                    // Don't use fallback data - just continue with fewer results
+    let feature_store =
+        env::var("FEATURE_STORE_PATH").ok().and_then(|path| {
+            match FeatureStore::open(std::path::Path::new(&path)) {
+                Ok(store) => {
+                    println!(
+                        "Local feature store: {} ({} recordings)",
+                        path,
+                        store.len().unwrap_or(0)
+                    );
+                    Some(Arc::new(store))
+                }
+                Err(e) => {
+                    eprintln!("Failed to open feature store {}: {}", path, e);
+                    None
                 }
             }
-            Err(e) => {
-                eprintln!("Last.fm request error: {}", e);
-                continue;
-            }
-        }
-    }
+        });
 
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Status {
-            message: format!(
-                "Found {} similar tracks to process",
-                candidate_queries.len()
-            ),
-        },
-    )?))
-    .await?;
+    let app_state = Arc::new(AppState {
+        rate_limiter: Arc::new(RateLimiter::new()),
+        spotify_token_manager,
+        recording_cache: Arc::new(TokioMutex::new(BoundedCache::new(
+            RECORDING_CACHE_TTL,
+            RECORDING_CACHE_CAPACITY,
+        ))),
+        original_artist_cache: Arc::new(TokioMutex::new(BoundedCache::new(
+            RECORDING_CACHE_TTL,
+            RECORDING_CACHE_CAPACITY,
+        ))),
+        suggest_cache: Arc::new(SuggestCache::new()),
+        search_cursors: Arc::new(TokioMutex::new(BoundedCache::new(
+            SEARCH_CURSOR_TTL,
+            SEARCH_CURSOR_CAPACITY,
+        ))),
+        tag_cache: Arc::new(TokioMutex::new(BoundedCache::new(
+            TAG_CACHE_TTL,
+            TAG_CACHE_CAPACITY,
+        ))),
+        feature_cache: Arc::new(TokioMutex::new(BoundedCache::new(
+            FEATURE_CACHE_TTL,
+            FEATURE_CACHE_CAPACITY,
+        ))),
+        feature_store,
+        popularity: Arc::new(PopularityIndex::load(
+            env::var("POPULARITY_SCALE_PATH")
+                .unwrap_or_else(|_| "popularity_scale.json".to_string())
+                .into(),
+            upstreams.clone(),
+        )),
+        runs: Arc::new(RunRegistry::new()),
+        result_pools: Arc::new(ResultPools::new()),
+        jobs: Arc::new(JobStore::load(
+            env::var("JOBS_DIR")
+                .unwrap_or_else(|_| "jobs".to_string())
+                .into(),
+        )),
+        upstreams,
+    });
+    resume_jobs(app_state.clone()).await;
 
-    // If no candidates found, return error
-    if candidate_queries.is_empty() {
-        tx.send(Ok(Event::default().json_data(
-            RecommendationEvent::Error {
-                message: "Could not find similar tracks. Please ensure you have a valid Last.fm API key.".to_string(),
-            },
-        )?))
-        .await?;
-        return Ok(());
-    }
+    // Forget finished runs once nobody can resume them, and their expired pages
+    let runs = app_state.runs.clone();
+    let result_pools = app_state.result_pools.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RUN_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            runs.sweep().await;
+            result_pools.sweep().await;
+        }
+    });
 
-    // Process candidates in batches
-    let seed_mbids: HashSet<String> = seeds.iter().filter_map(|s| s.mbid.clone()).collect();
+    // Keep the popularity reference current as lookups add to its sample
+    let popularity = app_state.popularity.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POPULARITY_REFRESH_INTERVAL);
+        interval.tick().await; // The first tick is immediate
+        loop {
+            interval.tick().await;
+            if let Err(e) = popularity.refresh().await {
+                eprintln!("Popularity refresh failed: {}", e);
+            }
+        }
+    });
 
-    let mut all_candidates = Vec::new();
-    let mut not_found_count = 0;
-    let batch_size = 10; // Increased for faster processing
+    // Configure CORS
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+        .allow_headers(Any)
+        // Lets browser clients learn the run id before the first event arrives,
+        // and notice they're calling deprecated paths
+        .expose_headers([
+            axum::http::HeaderName::from_static("x-run-id"),
+            axum::http::HeaderName::from_static("x-total-count"),
+            axum::http::HeaderName::from_static("x-next-token"),
+            axum::http::HeaderName::from_static("x-recommendation-seed"),
+            axum::http::HeaderName::from_static("deprecation"),
+            LINK,
+        ]);
+
+    let api = api_routes(use_spotify);
+    let app = Router::new()
+        .nest(API_VERSION_PREFIX, api.clone())
+        .merge(api.layer(middleware::from_fn(deprecated_path)))
+        .route("/openapi.json", get(openapi_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/status", get(status_handler))
+        .merge(Scalar::with_url("/docs", ApiDoc::openapi()))
+        .layer(cors)
+        .with_state(app_state);
 
-    for (batch_num, chunk) in candidate_queries.chunks(batch_size).enumerate() {
-        tx.send(Ok(Event::default().json_data(
-            RecommendationEvent::Status {
-                message: format!(
-                    "Processing batch {}/{}",
-                    batch_num + 1,
-                    (candidate_queries.len() + batch_size - 1) / batch_size
-                ),
-            },
-        )?))
-        .await?;
+    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    println!("Server listening on http://0.0.0.0:3000");
+    axum::serve(listener, app).await.unwrap();
+}
 
-        let batch_ids =
-            match resolve_tracks_musicbrainz(chunk.to_vec(), &app_state.rate_limiter).await {
-                Ok(ids) => ids,
-                Err(_) => continue,
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        for id in batch_ids {
-            if let Some(mbid) = &id.mbid {
-                if seed_mbids.contains(mbid) {
-                    continue;
-                }
-            }
+    fn recording(value: serde_json::Value) -> MusicBrainzRecording {
+        serde_json::from_value(value).unwrap()
+    }
 
-            if let Ok(track) = aggregate_features_musicbrainz(&id, &app_state.rate_limiter).await {
-                // Calculate score based on preferences
-                let audio_scorer = AudioSimilarityScorer {
-                    weights: hashmap! {"default".to_string() => 1.0},
-                };
-                let obscurity_scorer = ObscurityScorer;
-
-                // Adjust scoring weights based on obscurity preference
-                // Low obscurity (0.0) = prefer popular tracks
-                // High obscurity (1.0) = prefer obscure tracks
-                let obscurity_weight = req.preferences.obscurity;
-                let similarity_weight = 1.0 - obscurity_weight;
-                
-                let score = similarity_weight * audio_scorer.score(&inputs, &track)
-                    + obscurity_weight * obscurity_scorer.score(&inputs, &track);
-
-                // Send candidate immediately
-                tx.send(Ok(Event::default().json_data(
-                    RecommendationEvent::Candidate {
-                        track: track.clone(),
-                        score,
-                    },
-                )?))
-                .await?;
+    fn track(id: &str, artist: &str, features: serde_json::Value) -> Track {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": id,
+            "artist": artist,
+            "features": features,
+            "popularity": 50,
+        }))
+        .unwrap()
+    }
 
-                all_candidates.push((track, score));
-            } else {
-                not_found_count += 1;
-            }
+    fn preferences(artist_diversity: bool) -> Preferences {
+        Preferences {
+            energy: 0.5,
+            obscurity: 0.5,
+            mood: 0.5,
+            artist_diversity,
+            harmonic: 0.0,
+            harmonic_sequence: false,
         }
     }
 
-    // Send summary debug info
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Debug {
-            message: format!(
-                "Summary: {} candidates searched, {} tracks found, {} not found in MusicBrainz",
-                candidate_queries.len(), all_candidates.len(), not_found_count
-            ),
-            data: None,
-        },
-    )?))
-    .await?;
+    #[test]
+    fn normalize_title_strips_version_markers() {
+        assert_eq!(normalize_title("Song (2011 Remaster)"), "song");
+        assert_eq!(normalize_title("Song - Live at Wembley"), "song");
+        assert_eq!(normalize_title("Song [Radio Edit]"), "song");
+        assert_eq!(normalize_title("Song (Alternate Take)"), "song");
+        assert_eq!(normalize_title("Song (Take 2)"), "song");
+        assert_eq!(normalize_title("Song (Mono Mix)"), "song");
+    }
 
-    // Sort and send top results
-    all_candidates.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    let top_tracks: Vec<Track> = all_candidates
-        .into_iter()
-        .take(20) // Show more results since we're not filtering
-        .map(|(track, _)| track)
-        .collect();
+    #[test]
+    fn normalize_title_keeps_title_words() {
+        assert_eq!(
+            normalize_title("(Don't Fear) The Reaper"),
+            "don t fear the reaper"
+        );
+        assert_eq!(normalize_title("Song (Take Me Home)"), "song take me home");
+        assert_eq!(
+            normalize_title("Song (Oliver's Theme)"),
+            "song oliver s theme"
+        );
+        assert_eq!(normalize_title("Song - Alive"), "song alive");
+        assert_eq!(normalize_title("Song (Credit Roll)"), "song credit roll");
+        // A remix is a different song
+        assert_eq!(normalize_title("Song (Club Remix)"), "song club remix");
+    }
 
-    tx.send(Ok(Event::default().json_data(
-        RecommendationEvent::Complete { tracks: top_tracks },
-    )?))
-    .await?;
+    #[test]
+    fn group_recordings_merges_versions_only() {
+        let credit = |name: &str| serde_json::json!([{ "name": name, "artist": { "id": name, "name": name } }]);
+        let recordings = vec![
+            recording(
+                serde_json::json!({ "id": "1", "title": "Song", "artist-credit": credit("A") }),
+            ),
+            recording(
+                serde_json::json!({ "id": "2", "title": "Other", "artist-credit": credit("A") }),
+            ),
+            recording(
+                serde_json::json!({ "id": "3", "title": "Song (Remastered)", "artist-credit": credit("A") }),
+            ),
+            recording(
+                serde_json::json!({ "id": "4", "title": "Song", "artist-credit": credit("B") }),
+            ),
+            recording(
+                serde_json::json!({ "id": "5", "title": "Other (Live)", "artist-credit": credit("A"), "isrcs": ["X1"] }),
+            ),
+            recording(
+                serde_json::json!({ "id": "6", "title": "Renamed", "artist-credit": credit("A"), "isrcs": ["x1"] }),
+            ),
+        ];
+        assert_eq!(
+            group_recordings(&recordings),
+            vec![vec![0, 2], vec![1, 4, 5], vec![3]]
+        );
+    }
 
-    Ok(())
-}
+    #[test]
+    fn live_disambiguation_matches_whole_words() {
+        let rec = |disambiguation: &str| {
+            recording(
+                serde_json::json!({ "id": "1", "title": "Song", "disambiguation": disambiguation }),
+            )
+        };
+        assert!(is_live_recording(&rec("live, 1994-06-01: Wembley")));
+        assert!(!is_live_recording(&rec("Alive mix")));
+        assert!(!is_live_recording(&rec("Oliver's version")));
+    }
 
-// Original MusicBrainz recommend handler (kept for compatibility)
-async fn recommend_musicbrainz_handler(
-    State(app_state): State<Arc<AppState>>,
-    Json(req): Json<RecommendRequest>,
-) -> impl IntoResponse {
-    eprintln!("Recommendation request: {:?}", req);
+    #[test]
+    fn looks_like_imitation_matches_whole_words() {
+        let rec = |title: &str, artist: &str| {
+            recording(serde_json::json!({
+                "id": "1",
+                "title": title,
+                "artist-credit": [{ "artist": { "id": artist, "name": artist } }],
+            }))
+        };
+        assert!(looks_like_imitation(&rec("Song (Karaoke Version)", "A")));
+        assert!(looks_like_imitation(&rec(
+            "Song - Originally Performed by B",
+            "A"
+        )));
+        assert!(looks_like_imitation(&rec("Song [Tribute to B]", "A")));
+        assert!(looks_like_imitation(&rec("Song (Acoustic Cover)", "A")));
+        assert!(looks_like_imitation(&rec("Song", "The Tribute Band")));
+        assert!(!looks_like_imitation(&rec("Song (Club Remix)", "A")));
+        assert!(!looks_like_imitation(&rec("Song (Acoustic)", "A")));
+        assert!(!looks_like_imitation(&rec("A vs B", "A")));
+        assert!(!looks_like_imitation(&rec("Cover Me", "Bruce Springsteen")));
+        assert!(!looks_like_imitation(&rec("Discovery", "Daft Punk")));
+        assert!(!looks_like_imitation(&rec("Song", "Karaokes")));
+    }
 
-    // Resolve input tracks using MusicBrainz
-    let seeds = match resolve_tracks_musicbrainz(req.tracks, &app_state.rate_limiter).await {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Error resolving tracks: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Error resolving tracks: {}", e),
+    #[test]
+    fn suggestion_matches_word_prefixes() {
+        let suggestion = Suggestion::new("1".into(), "Bohemian Rhapsody".into(), "Queen".into());
+        assert!(suggestion_matches(&suggestion, "bohem rhap que"));
+        assert!(suggestion_matches(&suggestion, "rhapsody by queen"));
+        assert!(!suggestion_matches(&suggestion, "rhapsody king"));
+    }
+
+    #[tokio::test]
+    async fn local_matches_narrow_a_cached_prefix_and_add_popular() {
+        let cache = SuggestCache::new();
+        cache
+            .store(
+                "bo",
+                vec![
+                    Suggestion::new("1".into(), "Bohemian Rhapsody".into(), "Queen".into()),
+                    Suggestion::new("2".into(), "Born to Run".into(), "Bruce Springsteen".into()),
+                ],
             )
-                .into_response();
-        }
-    };
+            .await;
+        cache
+            .remember(Suggestion::new(
+                "3".into(),
+                "Bohemian Like You".into(),
+                "The Dandy Warhols".into(),
+            ))
+            .await;
+
+        let ids = |matches: Vec<Suggestion>| matches.into_iter().map(|s| s.id).collect::<Vec<_>>();
+        assert_eq!(ids(cache.local_matches("bohem", 10).await), ["1", "3"]);
+        assert_eq!(ids(cache.local_matches("bohem", 1).await), ["1"]);
+    }
+
+    #[test]
+    fn is_mbid_checks_uuid_shape() {
+        assert!(is_mbid("b10bbbfc-cf9e-42e0-be17-e2c3e1d2600d"));
+        assert!(!is_mbid("b10bbbfc-cf9e-42e0-be17-e2c3e1d2600"));
+        assert!(!is_mbid("b10bbbfc_cf9e_42e0_be17_e2c3e1d2600d"));
+        assert!(!is_mbid("The Beatles"));
+    }
 
-    eprintln!("Resolved {} seeds", seeds.len());
+    #[test]
+    fn split_name_and_artist_accepts_by_and_dash() {
+        assert_eq!(
+            split_name_and_artist("Abbey Road by The Beatles"),
+            ("Abbey Road".to_string(), Some("The Beatles".to_string()))
+        );
+        assert_eq!(
+            split_name_and_artist(" Abbey Road - The Beatles "),
+            ("Abbey Road".to_string(), Some("The Beatles".to_string()))
+        );
+        assert_eq!(
+            split_name_and_artist("Abbey Road"),
+            ("Abbey Road".to_string(), None)
+        );
+    }
 
-    let mut inputs = Vec::new();
-    for seed in &seeds {
-        eprintln!("Processing seed: {:?}", seed);
-        if let Ok(track) = aggregate_features_musicbrainz(&seed, &app_state.rate_limiter).await {
-            eprintln!("Added input track: {} by {}", track.name, track.artist);
-            inputs.push(track);
-        }
+    #[test]
+    fn split_credit_keeps_band_names_whole() {
+        assert_eq!(
+            split_credit("Earth, Wind & Fire"),
+            vec!["earth wind and fire"]
+        );
+        assert_eq!(
+            split_credit("Simon & Garfunkel"),
+            vec!["simon and garfunkel"]
+        );
+        assert_eq!(
+            split_credit("Artist A feat. Artist B"),
+            vec!["artist a feat artist b", "artist a", "artist b"]
+        );
     }
 
-    if inputs.is_empty() {
-        return (StatusCode::BAD_REQUEST, "No valid input tracks".to_string()).into_response();
+    #[test]
+    fn take_songs_fills_the_page_with_unseen_songs() {
+        let rec = |id: &str, title: &str| {
+            recording(serde_json::json!({
+                "id": id,
+                "title": title,
+                "artist-credit": [{ "artist": { "id": "a", "name": "A" } }],
+            }))
+        };
+        let rows = vec![
+            rec("1", "Song"),
+            rec("2", "Other"),
+            rec("3", "Song (Live)"),
+            rec("4", "Third"),
+            rec("5", "Other (2011 Remaster)"),
+        ];
+        let positions = [10, 11, 12, 13, 14];
+        let ids =
+            |kept: Vec<MusicBrainzRecording>| kept.into_iter().map(|r| r.id).collect::<Vec<_>>();
+
+        // Versions are kept with their song; the next page starts at the third song
+        let (kept, next) = take_songs(rows.clone(), &positions, &HashSet::new(), 2);
+        assert_eq!(ids(kept), ["1", "2", "3", "5"]);
+        assert_eq!(next, Some(13));
+
+        // Songs returned on an earlier page are skipped with all their versions
+        let seen: HashSet<String> = canonical_keys(&rows[0]).into_iter().collect();
+        let (kept, next) = take_songs(rows, &positions, &seen, 2);
+        assert_eq!(ids(kept), ["2", "4", "5"]);
+        assert_eq!(next, None);
     }
 
-    // Generate candidates using Last.fm (doesn't require Spotify token)
-    let lastfm_key = match env::var("LASTFM_API_KEY") {
-        Ok(key) => key,
-        Err(_) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "LASTFM_API_KEY not set".to_string(),
-            )
-                .into_response()
-        }
-    };
+    #[test]
+    fn track_metadata_prefers_the_earliest_official_studio_release() {
+        let rec = recording(serde_json::json!({
+            "id": "1",
+            "title": "Song",
+            "artist-credit": [{ "artist": { "id": "a1", "name": "A" } }],
+            "first-release-date": "1975-10-31",
+            "isrcs": ["GBUM71029604"],
+            "tags": [
+                { "name": "Rock", "count": 3 },
+                { "name": "opera", "count": 5 },
+                { "name": "spam", "count": 0 },
+            ],
+            "releases": [
+                {
+                    "id": "comp", "title": "Greatest Hits", "status": "Official", "date": "1974",
+                    "release-group": { "id": "rg1", "title": "Greatest Hits", "primary-type": "Album", "secondary-types": ["Compilation"] },
+                },
+                {
+                    "id": "album", "title": "A Night at the Opera", "status": "Official", "date": "1975-11-21",
+                    "release-group": { "id": "rg2", "title": "A Night at the Opera", "primary-type": "Album" },
+                },
+            ],
+        }));
+        let metadata = track_metadata(&rec);
+        assert_eq!(metadata.release.map(|r| r.id).as_deref(), Some("album"));
+        assert_eq!(
+            metadata.release_group.map(|rg| rg.id).as_deref(),
+            Some("rg2")
+        );
+        assert_eq!(metadata.first_release_date.as_deref(), Some("1975-10-31"));
+        assert_eq!(metadata.isrcs, ["GBUM71029604"]);
+        assert_eq!(
+            metadata
+                .tags
+                .iter()
+                .map(|t| t.name.as_str())
+                .collect::<Vec<_>>(),
+            ["opera", "rock"]
+        );
 
-    let client = reqwest::Client::new();
-    let mut candidate_queries = Vec::new();
+        // Absent fields are left out rather than sent as null
+        let json = serde_json::to_value(TrackMetadata::default()).unwrap();
+        assert!(json.get("release").is_none());
+        assert!(json.get("duration_ms").is_none());
+    }
 
-    for input in &inputs {
-        let url = format!(
-            "https://ws.audioscrobbler.com/2.0/?method=track.getsimilar&track={}&artist={}&api_key={}&format=json&limit=50",
-            urlencoding::encode(&input.name),
-            urlencoding::encode(&input.artist),
-            lastfm_key
+    #[test]
+    fn select_top_caps_tracks_per_artist() {
+        let mut collaboration = track("ab", "A & B", serde_json::json!({}));
+        collaboration.artists = ["A", "B"]
+            .iter()
+            .map(|name| ArtistCredit {
+                name: name.to_string(),
+                mbid: None,
+                joinphrase: String::new(),
+            })
+            .collect();
+        let scored = vec![
+            (track("a1", "A", serde_json::json!({})), 1.0),
+            (collaboration, 0.9),
+            (track("a2", "A", serde_json::json!({})), 0.8),
+            (track("b1", "B", serde_json::json!({})), 0.7),
+            (track("b2", "B", serde_json::json!({})), 0.6),
+        ];
+        let ids = |tracks: Vec<Track>| tracks.into_iter().map(|t| t.id).collect::<Vec<_>>();
+        assert_eq!(
+            ids(select_top(scored.clone(), 10, &preferences(true))),
+            vec!["a1", "ab", "b1"]
         );
-
-        eprintln!(
-            "Fetching similar tracks from Last.fm for: {} by {}",
-            input.name, input.artist
+        assert_eq!(
+            ids(select_top(scored, 2, &preferences(false))),
+            vec!["a1", "ab"]
         );
-
-        match client.get(&url).send().await {
-            Ok(response) => {
-                let status = response.status();
-                eprintln!("Last.fm response status: {}", status);
-
-                if let Ok(res) = response.json::<LastFmSimilar>().await {
-                    eprintln!("Found {} similar tracks", res.similartracks.track.len());
-                    for sim_track in res.similartracks.track {
-                        if sim_track.match_score > 0.1 {
-                            let query = format!("{} {}", sim_track.name, sim_track.artist.name);
-                            candidate_queries.push(query);
-                        }
-                    }
-                } else {
-                    eprintln!("Failed to parse Last.fm response");
-                }
-            }
-            Err(e) => {
-                eprintln!("Last.fm request failed: {}", e);
-                continue;
-            }
-        }
     }
 
-    eprintln!("Found {} candidate queries", candidate_queries.len());
+    #[test]
+    fn audio_similarity_lines_up_features_by_key() {
+        let scorer = AudioSimilarityScorer {
+            weights: HashMap::new(),
+        };
+        let seeds = vec![
+            track(
+                "s1",
+                "A",
+                serde_json::json!({ "tempo": 0.2, "danceability": 0.8, "key": 0.0 }),
+            ),
+            track(
+                "s2",
+                "B",
+                serde_json::json!({ "danceability": 0.4, "tempo": 0.6, "key": 5.0 }),
+            ),
+        ];
+        // The seeds' average, in whatever order: keys are ignored for similarity
+        let average = track(
+            "c1",
+            "C",
+            serde_json::json!({ "key": 9.0, "tempo": 0.4, "danceability": 0.6 }),
+        );
+        assert!((scorer.score(&seeds, &average) - 1.0).abs() < 1e-9);
+        // The same values on the wrong features
+        let swapped = track(
+            "c2",
+            "C",
+            serde_json::json!({ "tempo": 0.6, "danceability": 0.4 }),
+        );
+        assert!(scorer.score(&seeds, &swapped) < 0.99);
+    }
 
-    // Resolve candidates using MusicBrainz
-    let candidate_ids =
-        match resolve_tracks_musicbrainz(candidate_queries, &app_state.rate_limiter).await {
-            Ok(ids) => ids,
-            Err(e) => {
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Error resolving candidates: {}", e),
-                )
-                    .into_response()
-            }
+    #[test]
+    fn audio_similarity_averages_each_feature_over_seeds_that_have_it() {
+        let scorer = AudioSimilarityScorer {
+            weights: HashMap::new(),
         };
+        // The first seed has no features and the others have one each
+        let seeds = vec![
+            track("s1", "A", serde_json::json!({})),
+            track("s2", "B", serde_json::json!({ "tempo": 0.2 })),
+            track("s3", "C", serde_json::json!({ "danceability": 0.8 })),
+        ];
+        let candidate = track(
+            "c1",
+            "D",
+            serde_json::json!({ "tempo": 0.2, "danceability": 0.8 }),
+        );
+        assert!((scorer.score(&seeds, &candidate) - 1.0).abs() < 1e-9);
+    }
 
-    eprintln!("Resolved {} candidate tracks", candidate_ids.len());
+    #[test]
+    fn camelot_numbers_keys_around_the_wheel() {
+        assert_eq!(camelot(0, true), (8, true)); // C major: 8B
+        assert_eq!(camelot(9, false), (8, false)); // A minor: 8A
+        assert_eq!(camelot(7, true), (9, true)); // G major: 9B
+        assert_eq!(camelot(5, true), (7, true)); // F major: 7B
+        assert_eq!(camelot(4, false), (9, false)); // E minor: 9A
+        assert_eq!(camelot(6, true), (2, true)); // F# major: 2B
+    }
 
-    // Filter out seeds
-    let seed_mbids: HashSet<String> = seeds.iter().filter_map(|s| s.mbid.clone()).collect();
+    #[test]
+    fn key_compatibility_follows_the_wheel() {
+        assert_eq!(key_compatibility((8, true), (8, true)), 1.0);
+        assert_eq!(key_compatibility((8, true), (8, false)), 0.9); // Relative minor
+        assert_eq!(key_compatibility((8, true), (9, true)), 0.9);
+        assert_eq!(key_compatibility((12, true), (1, true)), 0.9); // Wraps around
+        assert_eq!(key_compatibility((8, true), (10, true)), 0.6); // Energy boost
+        assert_eq!(key_compatibility((8, true), (9, false)), 0.6); // Diagonal
+        assert_eq!(key_compatibility((8, true), (2, true)), 0.1);
+        assert_eq!(
+            key_compatibility((3, false), (5, true)),
+            key_compatibility((5, true), (3, false))
+        );
+    }
 
-    let mut candidates = Vec::new();
-    for id in candidate_ids {
-        if let Some(mbid) = &id.mbid {
-            if seed_mbids.contains(mbid) {
-                eprintln!("Skipping seed track: {}", mbid);
-                continue;
-            }
-        }
+    #[test]
+    fn acousticbrainz_levels_merge_into_one_feature_set() {
+        let document = |value: serde_json::Value| -> AcousticBrainzResponse {
+            serde_json::from_value(value).unwrap()
+        };
+        let mut merged = document(serde_json::json!({
+            "lowlevel": { "average_loudness": 0.8, "dynamic_complexity": 3.5 },
+            "rhythm": { "bpm": 120.0 },
+            "tonal": { "key_key": "A", "key_scale": "minor", "key_strength": 0.6 },
+        }));
+        merged.merge(document(serde_json::json!({
+            "highlevel": {
+                "danceability": { "all": { "danceable": 0.7 } },
+                "mood_happy": { "all": { "happy": 0.3 } },
+            },
+            // The first document's values win
+            "rhythm": { "bpm": 90.0 },
+        })));
+
+        let features = convert_acousticbrainz_features(&merged);
+        assert_eq!(features["tempo"], 0.6);
+        assert_eq!(features["loudness"], 0.8);
+        assert_eq!(features["danceability"], 0.7);
+        assert_eq!(features["valence"], 0.3);
+        assert_eq!(features["key"], 9.0);
+        assert_eq!(features["mode"], 0.0);
+        assert!(!features.contains_key("energy"));
+    }
 
-        if let Ok(track) = aggregate_features_musicbrainz(&id, &app_state.rate_limiter).await {
-            // Don't filter - include all tracks
-            candidates.push(track);
+    #[test]
+    fn bounded_cache_evicts_oldest_tenth_past_capacity() {
+        let mut cache = BoundedCache::new(Duration::from_secs(60), 20);
+        for i in 0..21 {
+            cache.insert(i.to_string(), i);
         }
+        // 21 entries, capacity 20: exactly a tenth (2) of the oldest go
+        assert_eq!(cache.entries.len(), 19);
+        assert_eq!(cache.get("0"), None);
+        assert_eq!(cache.get("1"), None);
+        assert_eq!(cache.get("2"), Some(2));
+        assert_eq!(cache.get("20"), Some(20));
     }
 
-    eprintln!("Found {} candidates after filtering", candidates.len());
+    #[test]
+    fn popularity_prior_follows_its_anchors() {
+        let scale = PopularityScale::prior();
+        assert_eq!(scale.score(0), 0);
+        assert_eq!(scale.score(50), 50);
+        assert_eq!(scale.score(5_000), 90);
+        assert_eq!(scale.score(u64::MAX), 100);
+        assert!(scale.score(10) < scale.score(100));
+    }
 
-    // Score candidates
-    let audio_scorer = AudioSimilarityScorer {
-        weights: hashmap! {"default".to_string() => 1.0},
-    };
-    let obscurity_scorer = ObscurityScorer;
+    #[test]
+    fn popularity_refresh_uses_the_sample_and_the_top_listens() {
+        let mut scale = PopularityScale::prior();
+        for listens in 0..POPULARITY_MIN_SAMPLE as u64 - 1 {
+            scale.observe(listens);
+        }
+        // Too small a sample keeps the prior
+        assert!(!scale.refresh(None, 1));
+        assert_eq!(scale.refreshed_at, 0);
+
+        scale.observe(POPULARITY_MIN_SAMPLE as u64 - 1);
+        assert!(scale.refresh(Some(1_000_000), 2));
+        assert_eq!(scale.refreshed_at, 2);
+        assert_eq!(scale.score(250), 50);
+        assert_eq!(scale.breakpoints[100], 1_000_000f64.ln_1p());
+        assert!(scale.score(5_000) < 100);
+    }
 
-    // Adjust scoring weights based on obscurity preference
-    let obscurity_weight = req.preferences.obscurity;
-    let similarity_weight = 1.0 - obscurity_weight;
+    #[test]
+    fn last_event_id_splits_run_and_sequence() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), None);
+        headers.insert("last-event-id", "0123abcd:7".parse().unwrap());
+        assert_eq!(last_event_id(&headers), Some(("0123abcd".to_string(), 7)));
+        headers.insert("last-event-id", "0123abcd".parse().unwrap());
+        assert_eq!(last_event_id(&headers), None);
+    }
 
-    let mut scored: Vec<(Track, f64)> = candidates
-        .into_iter()
-        .map(|cand| {
-            let score = similarity_weight * audio_scorer.score(&inputs, &cand)
-                + obscurity_weight * obscurity_scorer.score(&inputs, &cand);
-            (cand, score)
-        })
-        .collect();
+    #[tokio::test]
+    async fn resumed_runs_replay_only_missed_events() {
+        let registry = RunRegistry::new();
+        let run = registry.create().await;
+        for message in ["one", "two", "three"] {
+            run.emit(RecommendationEvent::Status {
+                message: message.to_string(),
+            })
+            .await;
+        }
 
-    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    let top = scored
-        .into_iter()
-        .take(20) // Show more results
-        .map(|(t, _)| t)
-        .collect::<Vec<_>>();
+        let resumed = registry.get(&run.id).await.unwrap();
+        let (events, finished) = resumed.events_after(1).await;
+        let sequence: Vec<u64> = events.iter().map(|(seq, _)| *seq).collect();
+        assert_eq!(sequence, vec![2, 3]);
+        assert!(events[0].1.contains("\"two\""));
+        assert!(!finished);
+
+        run.finish().await;
+        let (events, finished) = resumed.events_after(3).await;
+        assert!(events.is_empty());
+        assert!(finished);
+
+        // Recently finished runs stay resumable
+        registry.sweep().await;
+        assert!(registry.get(&run.id).await.is_some());
+    }
 
-    (StatusCode::OK, Json(top)).into_response()
-}
+    #[tokio::test(start_paused = true)]
+    async fn only_the_latest_disconnect_cancels_an_abandoned_run() {
+        let registry = RunRegistry::new();
+        let run = registry.create().await;
+
+        // Someone watching keeps the run alive
+        let watcher = run.latest.subscribe();
+        run.cancel_if_abandoned().await;
+        assert!(!run.cancel.is_cancelled());
+        drop(watcher);
+
+        // A reconnect-and-drop mid-grace supersedes the first timer
+        let first = tokio::spawn({
+            let run = run.clone();
+            async move { run.cancel_if_abandoned().await }
+        });
+        tokio::time::sleep(RUN_DISCONNECT_GRACE / 2).await;
+        let second = tokio::spawn({
+            let run = run.clone();
+            async move { run.cancel_if_abandoned().await }
+        });
+        first.await.unwrap();
+        assert!(!run.cancel.is_cancelled());
+        second.await.unwrap();
+        assert!(run.cancel.is_cancelled());
+    }
 
-// Legacy Spotify recommend handler
-async fn recommend_handler(
-    State(app_state): State<Arc<AppState>>,
-    Json(req): Json<RecommendRequest>,
-) -> impl IntoResponse {
-    let token_manager = app_state
-        .spotify_token_manager
-        .as_ref()
-        .expect("Spotify token manager not initialized");
-    let token = token_manager.get_token().await;
-    let seeds = match resolve_tracks(req.tracks, &token).await {
-        Ok(s) => s,
-        Err(e) => {
-            eprintln!("Error resolving tracks: {}", e);
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Error resolving tracks: {}", e),
-            )
-                .into_response();
+    fn job(id: &str) -> Job {
+        Job {
+            id: id.to_string(),
+            status: JobStatus::Queued,
+            request: RecommendRequest {
+                tracks: vec!["Song by Artist".to_string()],
+                artists: Vec::new(),
+                albums: Vec::new(),
+                preferences: preferences(false),
+                limit: None,
+                offset: 0,
+                seed: None,
+            },
+            created_at: unix_now(),
+            updated_at: unix_now(),
+            finished_at: None,
+            progress: JobProgress::default(),
+            partial_results: Vec::new(),
+            results: None,
+            error: None,
         }
-    };
-    let mut inputs = Vec::new();
-    for seed in &seeds {
-        if let Some(spotify_id) = &seed.spotify {
-            if let Ok(track) = aggregate_features(spotify_id, &token).await {
-                inputs.push(track);
-            }
+    }
+
+    #[test]
+    fn jobs_fold_events_and_keep_the_best_candidates() {
+        let mut job = job("job");
+        assert!(job.apply(&serde_json::json!({ "type": "Status", "message": "Resolving" })));
+        assert_eq!(job.status, JobStatus::Running);
+
+        for i in 0..JOB_PARTIAL_RESULTS + 5 {
+            let candidate = serde_json::json!({
+                "type": "Candidate",
+                "track": track(&i.to_string(), "Artist", serde_json::json!({})),
+                "score": i as f64,
+            });
+            assert!(!job.apply(&candidate));
         }
+        assert_eq!(job.progress.candidates_scored, JOB_PARTIAL_RESULTS + 5);
+        assert_eq!(job.partial_results.len(), JOB_PARTIAL_RESULTS);
+        assert_eq!(
+            job.partial_results[0].score,
+            (JOB_PARTIAL_RESULTS + 4) as f64
+        );
+        assert_eq!(job.partial_results[JOB_PARTIAL_RESULTS - 1].score, 5.0);
+
+        assert!(job.apply(&serde_json::json!({ "type": "Error", "message": "Boom" })));
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.error.as_deref(), Some("Boom"));
+        assert!(job.is_finished());
     }
-    if inputs.is_empty() {
-        return (StatusCode::BAD_REQUEST, "No valid input tracks".to_string()).into_response();
+
+    #[tokio::test]
+    async fn unfinished_jobs_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("jobs-{}", std::process::id()));
+        let store = JobStore::load(dir.clone());
+        let mut running = job("running");
+        running.apply(&serde_json::json!({ "type": "Status", "message": "Scoring" }));
+        store.insert(running).await;
+        let mut done = job("done");
+        done.apply(&serde_json::json!({ "type": "Complete", "tracks": [] }));
+        store.insert(done).await;
+        drop(store);
+
+        let store = JobStore::load(dir.clone());
+        let unfinished = store.take_unfinished().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].id, "running");
+        assert_eq!(unfinished[0].status, JobStatus::Queued);
+        assert!(unfinished[0].progress.message.is_empty());
     }
-    let candidates =
-        match generate_candidates(seeds.clone(), &inputs, &req.preferences, &token).await {
-            Ok(c) => c,
-            Err(e) => {
-                eprintln!("Error generating candidates: {}", e);
-                return (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Error generating candidates: {}", e),
-                )
-                    .into_response();
-            }
+
+    #[test]
+    fn steering_likes_become_seeds_and_dislikes_drop_out() {
+        let seeds = vec![track(
+            "s",
+            "S",
+            serde_json::json!({ "tempo": 0.2, "danceability": 0.9 }),
+        )];
+        let mut steering = Steering::new(seeds, preferences(false), 0);
+        steering.add(track(
+            "fast",
+            "A",
+            serde_json::json!({ "tempo": 0.9, "danceability": 0.2 }),
+        ));
+        steering.add(track(
+            "slow",
+            "B",
+            serde_json::json!({ "tempo": 0.2, "danceability": 0.9 }),
+        ));
+        let order = |steering: &Steering| {
+            steering
+                .ranking()
+                .into_iter()
+                .map(|(track, _)| track.id)
+                .collect::<Vec<_>>()
         };
-    // Fetch features for candidates if needed for scoring
-    let mut candidates_with_features = Vec::new();
-    for cand in candidates {
-        candidates_with_features.push(cand);
+        assert_eq!(order(&steering), vec!["slow", "fast"]);
+
+        steering.like("fast").unwrap();
+        assert_eq!(steering.inputs.len(), 2);
+        let before = steering.score(&steering.candidates[0]);
+        steering.remove_seed("s").unwrap();
+        assert!(steering.score(&steering.candidates[0]) > before);
+        assert_eq!(order(&steering), vec!["fast", "slow"]);
+        assert!(steering.remove_seed("fast").is_err()); // The last seed stays
+
+        steering.dislike("fast").unwrap();
+        assert!(steering.liked.is_empty());
+        assert_eq!(order(&steering), vec!["slow"]);
+        assert!(steering.like("missing").is_err());
     }
-    // Score
-    let audio_scorer = AudioSimilarityScorer {
-        weights: hashmap! {"default".to_string() => 1.0},
-    };
-    let obscurity_scorer = ObscurityScorer;
-    let mut scored: Vec<(Track, f64)> = candidates_with_features
-        .into_iter()
-        .map(|cand| {
-            let score = 0.6 * audio_scorer.score(&inputs, &cand)
-                + 0.4 * obscurity_scorer.score(&inputs, &cand);
-            (cand, score)
-        })
-        .collect();
-    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-    let top = scored
-        .into_iter()
-        .take(20) // Show more results
-        .map(|(t, _)| t)
-        .collect::<Vec<_>>();
-    (StatusCode::OK, Json(top)).into_response()
-}
-
-// MusicBrainz search handler
-async fn search_musicbrainz_handler(
-    State(app_state): State<Arc<AppState>>,
-    Path(query): Path<String>,
-) -> impl IntoResponse {
-    let recordings = match search_musicbrainz(&query, &app_state.rate_limiter).await {
-        Ok(recs) => recs,
-        Err(e) => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Search error: {}", e),
-            )
-                .into_response()
-        }
-    };
 
-    // Collect all MBIDs for popularity lookup
-    let mbids: Vec<String> = recordings.iter().map(|r| r.id.clone()).collect();
+    #[test]
+    fn progress_eta_counts_the_musicbrainz_requests_left() {
+        let req = RecommendRequest {
+            tracks: vec!["One".to_string(), "Two".to_string()],
+            artists: Vec::new(),
+            albums: Vec::new(),
+            preferences: preferences(false),
+            limit: None,
+            offset: 0,
+            seed: None,
+        };
+        let limiter = RateLimiter::new(); // One request a second
+        let mut progress = ProgressTracker::new(&req);
+        let eta = |event: RecommendationEvent| match event {
+            RecommendationEvent::Progress { eta_ms, .. } => eta_ms,
+            _ => panic!("not a progress event"),
+        };
 
-    // Fetch real popularity data from ListenBrainz
-    let popularity_map = match fetch_listenbrainz_popularity(mbids).await {
-        Ok(map) => map,
-        Err(e) => {
-            eprintln!("Failed to fetch popularity: {}", e);
-            HashMap::new()
-        }
-    };
+        // Two searches, two seed lookups and Last.fm's worth of candidates per seed
+        let expected = 2.0 * REQUESTS_PER_SEED_QUERY
+            + 2.0 * REQUESTS_PER_SEED
+            + (2 * EXPECTED_CANDIDATES_PER_SEED) as f64 * REQUESTS_PER_CANDIDATE;
+        assert_eq!(
+            eta(progress.event(Stage::ResolveSeeds, 0, 2, &limiter)),
+            (expected * 1000.0) as u64
+        );
 
-    // Group by artist and title to remove duplicates
-    let mut seen_tracks = HashSet::new();
-    let mut tracks: Vec<Track> = Vec::new();
-
-    for rec in recordings {
-        let artist_name = rec
-            .artist_credit
-            .as_ref()
-            .and_then(|credits| credits.first())
-            .map(|credit| credit.artist.name.clone())
-            .unwrap_or_else(|| "Unknown Artist".to_string());
-
-        let track_key = format!(
-            "{} - {}",
-            artist_name.to_lowercase(),
-            rec.title.to_lowercase()
+        progress.seeds = Some(2);
+        progress.candidates = Some(10);
+        assert_eq!(
+            eta(progress.event(Stage::EnrichCandidates, 5, 10, &limiter)),
+            (5.0 * REQUESTS_PER_CANDIDATE * 1000.0) as u64
         );
+        assert_eq!(eta(progress.event(Stage::Rank, 1, 1, &limiter)), 0);
+    }
 
-        if !seen_tracks.contains(&track_key) && tracks.len() < 10 {
-            seen_tracks.insert(track_key);
+    #[test]
+    fn openapi_document_covers_routes_and_types() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in [
+            "/mb/search/{query}",
+            "/mb/suggest",
+            "/mb/recommend/stream",
+            "/mb/recommend/stream/{run_id}",
+            "/mb/recommend/session",
+            "/jobs/{job_id}",
+        ] {
+            assert!(doc["paths"][path].is_object(), "missing {}", path);
+        }
+        let schemas = &doc["components"]["schemas"];
+        for schema in [
+            "Track",
+            "RecommendRequest",
+            "RecommendationEvent",
+            "SessionMessage",
+        ] {
+            assert!(schemas[schema].is_object(), "missing {}", schema);
+        }
+        assert!(schemas["Pagination"]["properties"]["next_cursor"].is_object());
+    }
 
-            // Get real popularity or default to 0
-            let popularity = popularity_map.get(&rec.id).copied().unwrap_or(0);
+    #[tokio::test]
+    async fn unversioned_paths_point_to_their_successor() {
+        let mut app: Router = Router::new()
+            .route("/search/{query}", get(spotify_not_configured))
+            .layer(middleware::from_fn(deprecated_path));
+        let request = Request::builder()
+            .uri("/search/radiohead")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let response = tower::Service::call(&mut app, request).await.unwrap();
 
-            tracks.push(Track {
-                id: rec.id,
-                name: rec.title,
-                artist: artist_name,
-                features: HashMap::new(),
-                popularity,
-                album_art: None, // Could fetch art here if needed
-            });
-        }
+        assert_eq!(response.status(), StatusCode::NOT_IMPLEMENTED);
+        assert_eq!(response.headers()["deprecation"], "true");
+        assert_eq!(
+            response.headers()[LINK],
+            "</v1/search/radiohead>; rel=\"successor-version\""
+        );
     }
 
-    // Sort by popularity (descending)
-    tracks.sort_by(|a, b| b.popularity.cmp(&a.popularity));
+    #[tokio::test]
+    async fn batch_runs_keep_their_outcome_and_cancel_when_dropped() {
+        let registry = RunRegistry::new();
+        let work = Arc::new(WorkCache::default());
+        let done = registry.create_sharing(work.clone()).await;
+        let failed = registry.create_sharing(work).await;
+        assert!(done.outcome().is_err()); // Not finished yet
+
+        let tracks = vec![track("t", "A", serde_json::json!({}))];
+        done.emit(RecommendationEvent::Complete {
+            tracks,
+            total: 1,
+            next: None,
+            seed: 0,
+        })
+        .await;
+        failed
+            .emit(RecommendationEvent::Error {
+                message: "No valid input tracks found".to_string(),
+            })
+            .await;
+        assert_eq!(done.outcome().unwrap()[0].id, "t");
+        assert_eq!(
+            failed.outcome().err().as_deref(),
+            Some("No valid input tracks found")
+        );
 
-    // Add metadata about features availability
-    let response = serde_json::json!({
-        "tracks": tracks,
-        "metadata": {
-            "source": "MusicBrainz + ListenBrainz",
-            "audio_features_available": false,
-            "popularity_data": "Real listen counts from ListenBrainz",
-            "note": "Use track IDs with /mb/recommend for audio features via AcousticBrainz"
-        }
-    });
+        drop(CancelOnDrop(vec![done.clone(), failed.clone()]));
+        assert!(done.cancel.is_cancelled() && failed.cancel.is_cancelled());
+    }
 
-    Json(response).into_response()
-}
+    #[tokio::test]
+    async fn collector_keeps_only_the_outcome() {
+        let collector = CollectorSink::default();
+        EventSink::emit(
+            &collector,
+            RecommendationEvent::Status {
+                message: "Starting".to_string(),
+            },
+        )
+        .await;
+        assert!(collector.outcome.lock().unwrap().is_none());
+
+        let tracks = vec![track("t", "A", serde_json::json!({}))];
+        let complete = RecommendationEvent::Complete {
+            tracks,
+            total: 1,
+            next: None,
+            seed: 0,
+        };
+        EventSink::emit(&collector, complete).await;
+        let page = collector.outcome.into_inner().unwrap().unwrap().unwrap();
+        assert_eq!((page.tracks[0].id.as_str(), page.total), ("t", 1));
+    }
 
-// Legacy Spotify search handler
-async fn search_handler(
-    State(app_state): State<Arc<AppState>>,
-    Path(query): Path<String>,
-) -> impl IntoResponse {
-    let token_manager = app_state
-        .spotify_token_manager
-        .as_ref()
-        .expect("Spotify token manager not initialized");
-    let token = token_manager.get_token().await;
-    let client = reqwest::Client::new();
-    let url = format!(
-        "https://api.spotify.com/v1/search?q={}&type=track&limit=10",
-        urlencoding::encode(&query)
-    );
-    let res = client
-        .get(&url)
-        .header(AUTHORIZATION, format!("Bearer {}", token))
-        .send()
-        .await
-        .unwrap()
-        .json::<SpotifySearchResponse>()
-        .await
-        .unwrap();
-    (StatusCode::OK, Json(res)).into_response()
-}
+    #[test]
+    fn run_errors_blame_the_caller_only_for_bad_input() {
+        let bad = RunError::BadInput("No valid input tracks found".to_string());
+        assert_eq!(bad.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(bad.message(), "No valid input tracks found");
+        let upstream = RunError::Upstream("Last.fm failed".to_string());
+        assert_eq!(upstream.status(), StatusCode::BAD_GATEWAY);
+        let internal = RunError::Internal("LASTFM_API_KEY is not set".to_string());
+        assert_eq!(internal.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
-// Main
-#[tokio::main]
-async fn main() {
-    // Check for required environment variables
-    let use_spotify =
-        env::var("SPOTIFY_CLIENT_ID").is_ok() && env::var("SPOTIFY_CLIENT_SECRET").is_ok();
-    let _genius_key = env::var("GENIUS_API_KEY").ok();
-    let _lastfm_key =
-        env::var("LASTFM_API_KEY").expect("LASTFM_API_KEY required for recommendations");
+    #[test]
+    fn pages_continue_where_the_last_one_ended() {
+        let pool = RankedPool {
+            tracks: (0..5)
+                .map(|i| track(&i.to_string(), "A", serde_json::json!({})))
+                .collect(),
+            last_seed: None,
+            harmonic_sequence: false,
+            seed: 9,
+            created_at: Instant::now(),
+        };
+        let first = pool.page("p", 0, 2);
+        assert_eq!(first.total, 5);
+        assert_eq!(first.seed, 9);
+        let next = first.next.clone().unwrap();
+        assert_eq!(parse_continuation(&next), Some(("p", 2, 2)));
+
+        let response = page_response(first);
+        assert_eq!(response.headers()["x-total-count"], "5");
+        assert_eq!(response.headers()["x-next-token"], "p:2:2");
+        assert_eq!(response.headers()["x-recommendation-seed"], "9");
+
+        let last = pool.page("p", 4, 2);
+        assert_eq!(last.tracks.len(), 1);
+        assert_eq!(last.tracks[0].id, "4");
+        assert_eq!(last.next, None);
+        assert!(pool.page("p", 9, 2).tracks.is_empty());
+    }
 
-    println!("Starting NextTrack API...");
-    println!("MusicBrainz endpoints:");
-    println!("  - GET  /mb/search/:query");
-    println!("  - POST /mb/recommend");
-    println!("  - POST /mb/recommend/stream (Server-Sent Events)");
+    #[test]
+    fn parse_continuation_rejects_malformed_tokens() {
+        assert_eq!(parse_continuation("abc:20:10"), Some(("abc", 20, 10)));
+        assert_eq!(parse_continuation("abc:20"), None);
+        assert_eq!(parse_continuation("abc:x:10"), None);
+        assert_eq!(parse_continuation("abc:20:10:5"), None);
+        assert_eq!(parse_continuation(""), None);
+    }
 
-    // Create app state
-    let spotify_token_manager = if use_spotify {
-        let client_id = env::var("SPOTIFY_CLIENT_ID").unwrap();
-        let client_secret = env::var("SPOTIFY_CLIENT_SECRET").unwrap();
-        println!(
-            "Spotify credentials found - enabling legacy endpoints: /search/:query, /recommend"
-        );
-        Some(Arc::new(TokenManager::new(client_id, client_secret)))
-    } else {
-        println!("No Spotify credentials - only MusicBrainz endpoints available");
-        None
-    };
+    #[test]
+    fn tie_breaker_depends_on_seed_and_id_only() {
+        assert_eq!(tie_breaker(7, "a"), tie_breaker(7, "a"));
+        assert_ne!(tie_breaker(7, "a"), tie_breaker(7, "b"));
+        assert_ne!(tie_breaker(7, "a"), tie_breaker(8, "a"));
+    }
 
-    let app_state = Arc::new(AppState {
-        rate_limiter: Arc::new(RateLimiter::new()),
-        spotify_token_manager,
-    });
+    #[test]
+    fn ranking_is_deterministic_for_a_seed() {
+        let seeds = vec![track("s", "S", serde_json::json!({ "tempo": 0.5 }))];
+        // Equal scores throughout, so only the tie breaker orders them
+        let candidates: Vec<Track> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|id| track(id, "A", serde_json::json!({ "tempo": 0.5 })))
+            .collect();
+        let ranked = |order: &[usize], seed: u64| {
+            let mut steering = Steering::new(seeds.clone(), preferences(false), seed);
+            for &i in order {
+                steering.add(candidates[i].clone());
+            }
+            let ranking = steering.ranking();
+            select_top(ranking, 3, &steering.preferences)
+                .into_iter()
+                .map(|track| track.id)
+                .collect::<Vec<_>>()
+        };
+        let first = ranked(&[0, 1, 2, 3, 4], 42);
+        assert_eq!(first, ranked(&[4, 2, 0, 3, 1], 42));
+        let mut expected = ["a", "b", "c", "d", "e"];
+        expected.sort_by_key(|id| tie_breaker(42, id));
+        assert_eq!(first, expected[..3]);
+    }
 
-    // Configure CORS
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
-        .allow_headers(Any);
+    #[test]
+    fn upstream_failures_count_auth_errors_only_with_credentials() {
+        use reqwest::StatusCode as Http;
+        assert!(is_upstream_failure(Http::SERVICE_UNAVAILABLE, false));
+        assert!(is_upstream_failure(Http::TOO_MANY_REQUESTS, false));
+        assert!(!is_upstream_failure(Http::NOT_FOUND, true));
+        assert!(!is_upstream_failure(Http::UNAUTHORIZED, false));
+        assert!(is_upstream_failure(Http::FORBIDDEN, true));
+        assert!(!Upstream::MusicBrainz.authenticated());
+        assert!(!Upstream::CoverArtArchive.authenticated());
+        assert!(Upstream::LastFm.authenticated());
+    }
 
-    let app = Router::new()
-        // MusicBrainz routes (always available)
-        .route("/mb/search/{query}", get(search_musicbrainz_handler))
-        .route("/mb/recommend", post(recommend_musicbrainz_handler))
-        .route(
-            "/mb/recommend/stream",
-            post(recommend_musicbrainz_stream_handler),
-        )
-        // Legacy Spotify routes (if credentials available)
-        .route("/search/{query}", get(search_handler))
-        .route("/recommend", post(recommend_handler))
-        .layer(cors)
-        .with_state(app_state);
+    #[tokio::test(start_paused = true)]
+    async fn probes_only_take_a_free_rate_limiter_slot() {
+        let limiter = RateLimiter::new();
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire()); // Within the second
+        tokio::time::advance(Duration::from_secs(1)).await;
+        let _held = limiter.last_request.lock().await;
+        assert!(!limiter.try_acquire()); // Someone else is using it
+    }
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    println!("Server listening on http://0.0.0.0:3000");
-    axum::serve(listener, app).await.unwrap();
+    #[test]
+    fn operations_routes_are_documented_at_the_root() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        for path in ["/healthz", "/readyz", "/status"] {
+            assert_eq!(doc["paths"][path]["servers"][0]["url"], "/", "{}", path);
+        }
+        let schemas = &doc["components"]["schemas"];
+        for schema in ["ReadinessResponse", "StatusResponse", "UpstreamStatus"] {
+            assert!(schemas[schema].is_object(), "missing {}", schema);
+        }
+    }
 }
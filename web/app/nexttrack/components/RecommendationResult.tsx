@@ -1,6 +1,20 @@
 import { motion, AnimatePresence } from "framer-motion";
 import { useState } from "react";
-import type { ApiTrack } from "../types";
+import type { ApiTrack, ProgressEvent, ProgressStage } from "../types";
+
+const STAGE_LABELS: Record<ProgressStage, string> = {
+  resolve_seeds: "Finding your tracks",
+  enrich_seeds: "Analysing your tracks",
+  generate_candidates: "Finding similar tracks",
+  enrich_candidates: "Analysing candidates",
+  rank: "Ranking",
+};
+
+const formatEta = (ms: number) => {
+  const seconds = Math.round(ms / 1000);
+  if (seconds < 60) return `${seconds}s`;
+  return `${Math.floor(seconds / 60)}m ${seconds % 60}s`;
+};
 
 interface RecommendationResultProps {
   showRecommendation: boolean;
@@ -10,6 +24,7 @@ interface RecommendationResultProps {
   error: string | null;
   isStreaming: boolean;
   debugInfo: string[];
+  progress?: ProgressEvent | null;
   stats: {
     totalCandidatesFound: number;
     filteredByObscurity: number;
@@ -25,6 +40,7 @@ const RecommendationResult = ({
   error,
   isStreaming,
   debugInfo,
+  progress,
   stats,
 }: RecommendationResultProps) => {
   if (!showRecommendation) return null;
@@ -97,6 +113,15 @@ const RecommendationResult = ({
               />
               <div className="absolute inset-0 h-full bg-white/20 backdrop-blur-sm" />
             </div>
+            {progress && (
+              <div className="mt-2 flex items-center justify-between text-xs text-slate-500">
+                <span>
+                  {STAGE_LABELS[progress.stage]}
+                  {progress.total > 1 && ` (${progress.done}/${progress.total})`}
+                </span>
+                {progress.eta_ms > 0 && <span>About {formatEta(progress.eta_ms)} left</span>}
+              </div>
+            )}
           </div>
 
           {/* Real-time stats */}
@@ -1,12 +1,42 @@
-import type { Track } from "../types";
+import type { TrackEntry } from "../types";
+import { useTrackSuggestions } from "../hooks/useTrackSuggestions";
 
 interface TrackInputProps {
-  tracks: Track[];
+  tracks: TrackEntry[];
   onAddTrack: () => void;
   onRemoveTrack: (id: number) => void;
   onUpdateTrack: (id: number, name: string) => void;
 }
 
+const TrackField = ({
+  track,
+  onUpdateTrack,
+}: {
+  track: TrackEntry;
+  onUpdateTrack: (id: number, name: string) => void;
+}) => {
+  const suggestions = useTrackSuggestions(track.name);
+  const listId = `track-suggestions-${track.id}`;
+
+  return (
+    <>
+      <input
+        type="text"
+        placeholder="Track Name - Artist (e.g., Bohemian Rhapsody - Queen)"
+        className="flex-1 px-3 py-2 border border-slate-300 rounded-lg text-sm bg-white focus:ring-2 focus:ring-primary-500 focus:border-transparent"
+        value={track.name}
+        list={listId}
+        onChange={(e) => onUpdateTrack(track.id, e.target.value)}
+      />
+      <datalist id={listId}>
+        {suggestions.map((suggestion) => (
+          <option key={suggestion.id} value={suggestion.label} />
+        ))}
+      </datalist>
+    </>
+  );
+};
+
 const TrackInput = ({
   tracks,
   onAddTrack,
@@ -54,13 +84,7 @@ const TrackInput = ({
               <div className="w-8 h-8 bg-primary-100 text-primary-600 rounded-full flex items-center justify-center text-sm font-medium">
                 {index + 1}
               </div>
-              <input
-                type="text"
-                placeholder="Track Name - Artist (e.g., Bohemian Rhapsody - Queen)"
-                className="flex-1 px-3 py-2 border border-slate-300 rounded-lg text-sm bg-white focus:ring-2 focus:ring-primary-500 focus:border-transparent"
-                value={track.name}
-                onChange={(e) => onUpdateTrack(track.id, e.target.value)}
-              />
+              <TrackField track={track} onUpdateTrack={onUpdateTrack} />
               {index > 0 && (
                 <button
                   onClick={() => onRemoveTrack(track.id)}
@@ -1,5 +1,7 @@
-import { useState, useCallback } from 'react';
-import type { ApiTrack, StreamEvent, RecommendationRequest } from '../types';
+import { useState, useCallback, useRef, useEffect } from 'react';
+import type { ApiTrack, StreamEvent, RecommendationRequest, ProgressEvent } from '../types';
+
+const MAX_RECONNECTS = 5;
 
 interface StreamState {
   status: string;
@@ -8,6 +10,7 @@ interface StreamState {
   error: string | null;
   isStreaming: boolean;
   debugInfo: string[];
+  progress: ProgressEvent | null;
   stats: {
     totalCandidatesFound: number;
     filteredByObscurity: number;
@@ -23,6 +26,7 @@ export const useRecommendationStream = () => {
     error: null,
     isStreaming: false,
     debugInfo: [],
+    progress: null,
     stats: {
       totalCandidatesFound: 0,
       filteredByObscurity: 0,
@@ -30,7 +34,28 @@ export const useRecommendationStream = () => {
     },
   });
 
+  // The run in progress, so it can be cancelled on the server
+  const activeRun = useRef<{ runId: string | null; controller: AbortController } | null>(null);
+
+  const cancelRecommendations = useCallback(() => {
+    const run = activeRun.current;
+    if (!run) return;
+    activeRun.current = null;
+    run.controller.abort();
+    if (run.runId) {
+      fetch(`http://localhost:3000/v1/mb/recommend/${run.runId}`, { method: 'DELETE' }).catch(() => {});
+    }
+    setState(prev => (prev.isStreaming ? { ...prev, isStreaming: false, status: 'Cancelled' } : prev));
+  }, []);
+
+  // Closing the stream is enough on unmount: the server cancels unwatched runs
+  useEffect(() => () => activeRun.current?.controller.abort(), []);
+
   const streamRecommendations = useCallback(async (request: RecommendationRequest) => {
+    cancelRecommendations();
+    const run = { runId: null as string | null, controller: new AbortController() };
+    activeRun.current = run;
+
     setState({
       status: 'Connecting...',
       candidates: [],
@@ -38,6 +63,7 @@ export const useRecommendationStream = () => {
       error: null,
       isStreaming: true,
       debugInfo: [],
+      progress: null,
       stats: {
         totalCandidatesFound: 0,
         filteredByObscurity: 0,
@@ -45,110 +71,165 @@ export const useRecommendationStream = () => {
       },
     });
 
-    try {
-      const response = await fetch('http://localhost:3000/mb/recommend/stream', {
-        method: 'POST',
-        headers: {
-          'Content-Type': 'application/json',
-        },
-        body: JSON.stringify(request),
-      });
-
-      if (!response.ok) {
-        throw new Error(`HTTP error! status: ${response.status}`);
-      }
+    // Returns true once the run has ended (Complete or Error)
+    const handleEvent = (event: StreamEvent): boolean => {
+      switch (event.type) {
+        case 'Status':
+          setState(prev => ({
+            ...prev,
+            status: event.message,
+          }));
+          return false;
 
-      const reader = response.body?.getReader();
-      const decoder = new TextDecoder();
+        case 'Progress':
+          setState(prev => ({
+            ...prev,
+            progress: event,
+            stats:
+              event.stage === 'enrich_candidates'
+                ? { ...prev.stats, totalCandidatesFound: event.total }
+                : prev.stats,
+          }));
+          return false;
 
-      if (!reader) {
-        throw new Error('No response body');
+        case 'Candidate':
+          setState(prev => ({
+            ...prev,
+            candidates: [...prev.candidates, { track: event.track, score: event.score }]
+              .sort((a, b) => b.score - a.score)
+              .slice(0, 20), // Keep top 20 candidates
+          }));
+          return false;
+
+        case 'Complete':
+          setState(prev => ({
+            ...prev,
+            recommendations: event.tracks,
+            isStreaming: false,
+            status: 'Complete!',
+          }));
+          return true;
+
+        case 'Error':
+          setState(prev => ({
+            ...prev,
+            error: event.message,
+            isStreaming: false,
+          }));
+          return true;
+
+        case 'Rescored': {
+          const scores = new Map(event.candidates.map(c => [c.id, c.score]));
+          setState(prev => ({
+            ...prev,
+            candidates: prev.candidates
+              .filter(c => scores.has(c.track.id))
+              .map(c => ({ ...c, score: scores.get(c.track.id)! }))
+              .sort((a, b) => b.score - a.score),
+          }));
+          return false;
+        }
+
+        case 'Debug':
+          setState(prev => ({
+            ...prev,
+            debugInfo: [...prev.debugInfo, event.message],
+          }));
+          return false;
       }
+      return false;
+    };
+
+    // The server keeps the run going if the connection drops; reconnect with the
+    // last event id to replay what was missed
+    let runId: string | null = null;
+    let lastEventId: string | null = null;
+    let finished = false;
+    let reconnects = 0;
+
+    while (!finished) {
+      try {
+        const response = runId
+          ? await fetch(`http://localhost:3000/v1/mb/recommend/stream/${runId}`, {
+              headers: lastEventId ? { 'Last-Event-ID': lastEventId } : {},
+              signal: run.controller.signal,
+            })
+          : await fetch('http://localhost:3000/v1/mb/recommend/stream', {
+              method: 'POST',
+              headers: {
+                'Content-Type': 'application/json',
+              },
+              body: JSON.stringify(request),
+              signal: run.controller.signal,
+            });
 
-      let buffer = '';
-
-      while (true) {
-        const { done, value } = await reader.read();
-        
-        if (done) break;
-
-        buffer += decoder.decode(value, { stream: true });
-        
-        // Process complete SSE events
-        const lines = buffer.split('\n');
-        buffer = lines.pop() || '';
-
-        for (const line of lines) {
-          if (line.startsWith('data: ')) {
-            try {
-              const event = JSON.parse(line.slice(6)) as StreamEvent;
-              
-              switch (event.type) {
-                case 'Status':
-                  setState(prev => {
-                    // Parse status messages for stats
-                    const foundMatch = event.message.match(/Found (\d+) similar tracks to process/);
-                    if (foundMatch) {
-                      return {
-                        ...prev,
-                        status: event.message,
-                        stats: { ...prev.stats, totalCandidatesFound: parseInt(foundMatch[1]) },
-                      };
-                    }
-                    return {
-                      ...prev,
-                      status: event.message,
-                    };
-                  });
-                  break;
-                
-                case 'Candidate':
-                  setState(prev => ({
-                    ...prev,
-                    candidates: [...prev.candidates, { track: event.track, score: event.score }]
-                      .sort((a, b) => b.score - a.score)
-                      .slice(0, 20), // Keep top 20 candidates
-                  }));
-                  break;
-                
-                case 'Complete':
-                  setState(prev => ({
-                    ...prev,
-                    recommendations: event.tracks,
-                    isStreaming: false,
-                    status: 'Complete!',
-                  }));
-                  break;
-                
-                case 'Error':
-                  setState(prev => ({
-                    ...prev,
-                    error: event.message,
-                    isStreaming: false,
-                  }));
-                  break;
-                
-                case 'Debug':
-                  setState(prev => ({
-                    ...prev,
-                    debugInfo: [...prev.debugInfo, event.message],
-                  }));
-                  break;
+        if (!response.ok) {
+          throw new Error(`HTTP error! status: ${response.status}`);
+        }
+        runId = runId ?? response.headers.get('X-Run-Id');
+        run.runId = runId;
+
+        const reader = response.body?.getReader();
+        const decoder = new TextDecoder();
+
+        if (!reader) {
+          throw new Error('No response body');
+        }
+
+        let buffer = '';
+
+        while (!finished) {
+          const { done, value } = await reader.read();
+
+          if (done) break;
+
+          buffer += decoder.decode(value, { stream: true });
+
+          // Process complete SSE lines (ids, data; keep-alive comments are ignored)
+          const lines = buffer.split('\n');
+          buffer = lines.pop() || '';
+
+          for (const line of lines) {
+            if (line.startsWith('id: ')) {
+              lastEventId = line.slice(4);
+            } else if (line.startsWith('data: ')) {
+              try {
+                finished = handleEvent(JSON.parse(line.slice(6)) as StreamEvent) || finished;
+              } catch (e) {
+                console.error('Failed to parse SSE event:', e);
               }
-            } catch (e) {
-              console.error('Failed to parse SSE event:', e);
             }
           }
         }
+      } catch (error) {
+        if (run.controller.signal.aborted) return;
+        if (!runId || reconnects >= MAX_RECONNECTS) {
+          setState(prev => ({
+            ...prev,
+            error: error instanceof Error ? error.message : 'Unknown error',
+            isStreaming: false,
+          }));
+          return;
+        }
+      }
+
+      if (!finished) {
+        if (run.controller.signal.aborted) return;
+        if (!runId || reconnects >= MAX_RECONNECTS) {
+          setState(prev => ({
+            ...prev,
+            error: 'Connection lost',
+            isStreaming: false,
+          }));
+          return;
+        }
+        reconnects += 1;
+        setState(prev => ({ ...prev, status: `Reconnecting (attempt ${reconnects})...` }));
+        await new Promise(resolve => setTimeout(resolve, 1000 * reconnects));
       }
-    } catch (error) {
-      setState(prev => ({
-        ...prev,
-        error: error instanceof Error ? error.message : 'Unknown error',
-        isStreaming: false,
-      }));
     }
-  }, []);
+    if (activeRun.current === run) activeRun.current = null;
+  }, [cancelRecommendations]);
 
-  return { ...state, streamRecommendations };
+  return { ...state, streamRecommendations, cancelRecommendations };
 };
\ No newline at end of file
@@ -0,0 +1,40 @@
+import { useEffect, useState } from 'react';
+import type { Suggestion } from '../types';
+
+const SUGGEST_DEBOUNCE_MS = 250;
+
+export const useTrackSuggestions = (query: string) => {
+  const [suggestions, setSuggestions] = useState<Suggestion[]>([]);
+
+  useEffect(() => {
+    const trimmed = query.trim();
+    if (trimmed.length < 2) {
+      setSuggestions([]);
+      return;
+    }
+
+    const controller = new AbortController();
+    const timer = setTimeout(async () => {
+      try {
+        const response = await fetch(
+          `http://localhost:3000/v1/mb/suggest?q=${encodeURIComponent(trimmed)}`,
+          { signal: controller.signal }
+        );
+        if (!response.ok) return;
+        const data = (await response.json()) as { suggestions: Suggestion[] };
+        setSuggestions(data.suggestions);
+      } catch (e) {
+        if (!controller.signal.aborted) {
+          console.error('Failed to fetch suggestions:', e);
+        }
+      }
+    }, SUGGEST_DEBOUNCE_MS);
+
+    return () => {
+      clearTimeout(timer);
+      controller.abort();
+    };
+  }, [query]);
+
+  return suggestions;
+};
@@ -1,7 +1,7 @@
 "use client";
 
 import { useState, useRef } from "react";
-import type { Track, Preferences, RecommendationRequest } from "./types";
+import type { TrackEntry, Preferences, RecommendationRequest } from "./types";
 import { presets } from "./constants/presets";
 import { useRecommendationStream } from "./hooks/useRecommendationStream";
 import Header from "./components/Header";
@@ -13,7 +13,7 @@ import PresetButtons from "./components/PresetButtons";
 import RecommendationResult from "./components/RecommendationResult";
 
 const NextTrack = () => {
-  const [tracks, setTracks] = useState<Track[]>([{ id: 0, name: "" }]);
+  const [tracks, setTracks] = useState<TrackEntry[]>([{ id: 0, name: "" }]);
   const nextIdRef = useRef(1); // Keep track of the next available ID
   const [preferences, setPreferences] = useState<Preferences>({
     mood: 0.5,
@@ -33,6 +33,7 @@ const NextTrack = () => {
     error,
     isStreaming,
     debugInfo,
+    progress,
     stats,
     streamRecommendations
   } = useRecommendationStream();
@@ -91,6 +92,7 @@ const NextTrack = () => {
         energy: preferences.energy,
         obscurity: preferences.obscurity,
         mood: preferences.mood,
+        artist_diversity: preferences.artistDiversity,
       }
     };
 
@@ -137,6 +139,7 @@ const NextTrack = () => {
           error={error}
           isStreaming={isStreaming}
           debugInfo={debugInfo}
+          progress={progress}
           stats={stats}
         />
       </main>
@@ -1,4 +1,5 @@
-export interface Track {
+// A row of the track input form; `id` is a local row key, not an API id (see ApiTrack)
+export interface TrackEntry {
   id: number;
   name: string;
 }
@@ -20,23 +21,103 @@ export interface Preset {
   lyrical: number;
 }
 
-// API Types
+// API Types: these mirror the schemas in the API's OpenAPI document
+// (GET /openapi.json, browsable at /docs). `npm run api:types` generates
+// types/api.d.ts from a running API to check them against.
 export interface ApiTrack {
   id: string;
   name: string;
-  artist: string;
+  artist: string; // Display credit, e.g. "Artist A feat. Artist B"
+  artists?: ArtistCredit[];
   features: Record<string, number>;
-  popularity: number;
+  popularity: number; // Percentile of ListenBrainz listens (0-100)
+  listen_count?: number;
+  user_count?: number;
   album_art?: string;
+  flags?: RecordingFlags;
+  duration_ms?: number;
+  first_release_date?: string;
+  release?: ReleaseInfo;
+  release_group?: ReleaseGroupInfo;
+  isrcs?: string[];
+  tags?: TagCount[];
+  genres?: TagCount[];
+  tag_vector?: Record<string, number>; // Weighted genres/tags used for similarity
+  harmonic_key?: HarmonicKey;
+}
+
+export interface HarmonicKey {
+  tonic: string;
+  scale: "major" | "minor";
+  camelot: string; // e.g. "8A"
+}
+
+export interface ArtistCredit {
+  name: string;
+  mbid?: string;
+  joinphrase: string;
+}
+
+export interface ReleaseInfo {
+  id: string;
+  title: string;
+  date?: string;
+}
+
+export interface ReleaseGroupInfo {
+  id: string;
+  title: string;
+  primary_type?: string;
+  secondary_types: string[];
+}
+
+export interface TagCount {
+  name: string;
+  count: number;
+}
+
+export interface RecordingFlags {
+  cover: boolean;
+  karaoke: boolean;
+  tribute: boolean;
+  live: boolean;
+  compilation: boolean;
+}
+
+export interface Suggestion {
+  id: string;
+  name: string;
+  artist: string;
+  label: string;
 }
 
 export interface RecommendationRequest {
   tracks: string[];
+  artists?: string[]; // Artist MBIDs or names
+  albums?: string[]; // Release/release-group MBIDs or "Album by Artist"
   preferences: {
     energy: number;
     obscurity: number;
     mood: number;
+    artist_diversity?: boolean;
+    harmonic?: number; // 0-1 weight of key compatibility with the seeds
+    harmonic_sequence?: boolean; // Order results for smooth key transitions
   };
+  limit?: number; // Page size (default 20, at most 100)
+  offset?: number;
+  seed?: number; // Reproduces an earlier result (echoed in x-recommendation-seed)
+}
+
+// POST /mb/recommend and GET /mb/recommend/more?token=<next> return ApiTrack[];
+// the rest of a page comes in response headers:
+//   x-total-count          tracks in the whole ranking
+//   x-next-token           continuation token for the next page (absent on the last)
+//   x-recommendation-seed  send it back as `seed` to reproduce these results
+export interface RecommendPage {
+  tracks: ApiTrack[];
+  total: number;
+  next?: string | null;
+  seed: number;
 }
 
 // SSE Event Types
@@ -54,6 +135,9 @@ export interface CandidateEvent {
 export interface CompleteEvent {
   type: 'Complete';
   tracks: ApiTrack[];
+  total: number;
+  next?: string | null;
+  seed: number;
 }
 
 export interface ErrorEvent {
@@ -67,4 +151,62 @@ export interface DebugEvent {
   data?: any;
 }
 
-export type StreamEvent = StatusEvent | CandidateEvent | CompleteEvent | ErrorEvent | DebugEvent;
+// Sent when an interactive session steers the run: new scores for every
+// candidate still in the running (disliked ones are left out)
+export interface RescoredEvent {
+  type: 'Rescored';
+  candidates: Array<{ id: string; score: number }>;
+}
+
+export type ProgressStage =
+  | 'resolve_seeds'
+  | 'enrich_seeds'
+  | 'generate_candidates'
+  | 'enrich_candidates'
+  | 'rank';
+
+export interface ProgressEvent {
+  type: 'Progress';
+  stage: ProgressStage;
+  done: number;
+  total: number;
+  elapsed_ms: number;
+  eta_ms: number; // Estimated time left for the whole run
+}
+
+export type StreamEvent =
+  | StatusEvent
+  | CandidateEvent
+  | CompleteEvent
+  | ErrorEvent
+  | DebugEvent
+  | RescoredEvent
+  | ProgressEvent;
+
+// Messages a client sends on the /mb/recommend/session WebSocket
+export type SessionMessage =
+  | { type: 'Start'; request: RecommendationRequest }
+  | { type: 'Like'; track_id: string }
+  | { type: 'Dislike'; track_id: string }
+  | { type: 'Preferences'; preferences: RecommendationRequest['preferences'] }
+  | { type: 'AddSeed'; query: string }
+  | { type: 'RemoveSeed'; track_id: string }
+  | { type: 'Cancel' };
+
+// POST /mb/recommend/batch: named seed sets, each a full request
+export interface SeedSet extends RecommendationRequest {
+  name: string;
+}
+
+export interface SeedSetResult {
+  name: string;
+  run_id: string;
+  tracks: ApiTrack[];
+  error?: string | null;
+}
+
+// Data of each event on /mb/recommend/batch/stream
+export interface BatchStreamEvent {
+  set: string;
+  event: StreamEvent;
+}